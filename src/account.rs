@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::holdings::Order;
+use crate::portfolio::{net_positions, LotMethod};
+
+/// Cash and valuation summary for a single user.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Account {
+    pub user: String,
+    pub cash: f64,
+    pub market_value: f64,
+    pub equity: f64,
+}
+
+impl Account {
+    /// Value `orders` at `prices`, falling back to a position's average cost
+    /// for symbols without a quote.
+    pub fn new(user: &str, cash: f64, orders: &[Order], prices: &HashMap<String, f64>) -> Self {
+        // the lot method only affects realised gains, not quantities held
+        let market_value = net_positions(orders, LotMethod::default())
+            .iter()
+            .map(|(symbol, pos)| {
+                let price = prices.get(symbol).copied().unwrap_or(pos.average_cost);
+                price * pos.quantity as f64
+            })
+            .sum();
+        Self { user: user.to_string(), cash, market_value, equity: cash + market_value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holdings::Side;
+
+    #[test]
+    fn values_positions_at_market_prices() {
+        let orders = vec![
+            Order::new("alice", "AAPL", Side::Buy, 10, 10.0),
+            Order::new("alice", "MSFT", Side::Buy, 2, 50.0),
+        ];
+        let prices = HashMap::from([("AAPL".to_string(), 12.0)]);
+        let account = Account::new("alice", 800.0, &orders, &prices);
+        assert_eq!(account.market_value, 120.0 + 100.0);
+        assert_eq!(account.equity, 1020.0);
+    }
+}
@@ -1,8 +1,11 @@
-use std::collections::HashMap;
 use std::sync::Arc;
 
+use anyhow::Context;
+use arrow_array::Array;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::Mutex;
+
+use crate::storage::{ActivityStorage, MemoryStorage};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct GpsPoint {
@@ -19,34 +22,135 @@ pub struct Activity {
     pub gps: Vec<GpsPoint>,
 }
 
-#[derive(Clone, Default)]
+fn activity_schema() -> arrow_schema::Schema {
+    use arrow_schema::{DataType, Field, Schema};
+    let list = |name: &str, item: DataType| {
+        Field::new(name, DataType::List(Arc::new(Field::new("item", item, true))), false)
+    };
+    Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("metadata", DataType::Utf8, false),
+        list("heart_rate", DataType::UInt32),
+        list("power", DataType::UInt32),
+        list("gps_lat", DataType::Float64),
+        list("gps_lon", DataType::Float64),
+    ])
+}
+
+pub(crate) fn activities_to_record_batch(activities: &[Activity]) -> anyhow::Result<arrow_array::RecordBatch> {
+    use arrow_array::builder::{Float64Builder, ListBuilder, UInt32Builder};
+    use arrow_array::{ArrayRef, RecordBatch, StringArray};
+
+    let schema = Arc::new(activity_schema());
+    let u32_list = |values: fn(&Activity) -> &[u32]| -> ArrayRef {
+        let mut builder = ListBuilder::new(UInt32Builder::new());
+        for activity in activities {
+            builder.append_value(values(activity).iter().map(|v| Some(*v)));
+        }
+        Arc::new(builder.finish())
+    };
+    let f64_list = |value: fn(&GpsPoint) -> f64| -> ArrayRef {
+        let mut builder = ListBuilder::new(Float64Builder::new());
+        for activity in activities {
+            builder.append_value(activity.gps.iter().map(|p| Some(value(p))));
+        }
+        Arc::new(builder.finish())
+    };
+
+    Ok(RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(StringArray::from_iter_values(activities.iter().map(|a| a.id.as_str()))),
+            Arc::new(StringArray::from_iter_values(activities.iter().map(|a| a.metadata.as_str()))),
+            u32_list(|a| &a.heart_rate),
+            u32_list(|a| &a.power),
+            f64_list(|p| p.lat),
+            f64_list(|p| p.lon),
+        ],
+    )?)
+}
+
+pub(crate) fn batch_to_activities(batch: &arrow_array::RecordBatch) -> anyhow::Result<Vec<Activity>> {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Float64Type, UInt32Type};
+    use arrow_array::StringArray;
+
+    let column = |name: &str| batch.column_by_name(name).with_context(|| format!("missing {name} column"));
+    let strings = |name: &str| -> anyhow::Result<&StringArray> {
+        column(name)?.as_any().downcast_ref().with_context(|| format!("{name} column is not a string"))
+    };
+    let ids = strings("id")?;
+    let metadata = strings("metadata")?;
+    let heart_rate = column("heart_rate")?.as_list::<i32>();
+    let power = column("power")?.as_list::<i32>();
+    let gps_lat = column("gps_lat")?.as_list::<i32>();
+    let gps_lon = column("gps_lon")?.as_list::<i32>();
+
+    (0..batch.num_rows())
+        .map(|i| {
+            let u32s = |list: &arrow_array::ListArray| list.value(i).as_primitive::<UInt32Type>().values().to_vec();
+            let lat = gps_lat.value(i);
+            let lon = gps_lon.value(i);
+            let gps = lat
+                .as_primitive::<Float64Type>()
+                .values()
+                .iter()
+                .zip(lon.as_primitive::<Float64Type>().values().iter())
+                .map(|(lat, lon)| GpsPoint { lat: *lat, lon: *lon })
+                .collect();
+            Ok(Activity {
+                id: ids.value(i).to_string(),
+                metadata: metadata.value(i).to_string(),
+                heart_rate: u32s(heart_rate),
+                power: u32s(power),
+                gps,
+            })
+        })
+        .collect()
+}
+
+/// Recorded activities, kept in an [`ActivityStorage`].
+#[derive(Clone)]
 pub struct ActivityStore {
-    inner: Arc<RwLock<HashMap<String, Activity>>>,
+    storage: Arc<dyn ActivityStorage>,
+    /// Serialises writes so `add_if_missing` cannot race another add.
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl Default for ActivityStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ActivityStore {
+    /// Keep activities in memory only.
     pub fn new() -> Self {
-        Self { inner: Arc::new(RwLock::new(HashMap::new())) }
+        Self { storage: Arc::new(MemoryStorage::new()), write_lock: Arc::new(Mutex::new(())) }
+    }
+
+    /// Persist activities to `storage`.
+    pub fn with_storage(mut self, storage: Arc<dyn ActivityStorage>) -> Self {
+        self.storage = storage;
+        self
     }
 
-    pub async fn add(&self, activity: Activity) {
-        let mut guard = self.inner.write().await;
-        guard.insert(activity.id.clone(), activity);
+    pub async fn add(&self, activity: Activity) -> anyhow::Result<()> {
+        let _lock = self.write_lock.lock().await;
+        self.storage.save_activity(&activity).await.context("failed to persist activity")
     }
 
-    pub async fn add_if_missing(&self, activity: Activity) -> bool {
-        let mut guard = self.inner.write().await;
-        if guard.contains_key(&activity.id) {
-            false
-        } else {
-            guard.insert(activity.id.clone(), activity);
-            true
+    pub async fn add_if_missing(&self, activity: Activity) -> anyhow::Result<bool> {
+        let _lock = self.write_lock.lock().await;
+        if self.storage.activity(&activity.id).await?.is_some() {
+            return Ok(false);
         }
+        self.storage.save_activity(&activity).await.context("failed to persist activity")?;
+        Ok(true)
     }
 
-    pub async fn get(&self, id: &str) -> Option<Activity> {
-        let guard = self.inner.read().await;
-        guard.get(id).cloned()
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<Activity>> {
+        self.storage.activity(id).await
     }
 }
 
@@ -64,8 +168,8 @@ mod tests {
             power: vec![10, 20],
             gps: vec![GpsPoint { lat: 0.0, lon: 0.0 }],
         };
-        store.add(act.clone()).await;
-        assert_eq!(store.get("1").await, Some(act));
+        store.add(act.clone()).await.unwrap();
+        assert_eq!(store.get("1").await.unwrap(), Some(act));
     }
 
     #[tokio::test]
@@ -73,8 +177,8 @@ mod tests {
         let store = ActivityStore::new();
         let act1 = Activity { id: "1".into(), metadata: "a".into(), heart_rate: vec![1], power: vec![5], gps: vec![] };
         let act2 = Activity { id: "1".into(), metadata: "b".into(), heart_rate: vec![2], power: vec![6], gps: vec![] };
-        assert!(store.add_if_missing(act1.clone()).await);
-        assert!(!store.add_if_missing(act2.clone()).await);
-        assert_eq!(store.get("1").await, Some(act1));
+        assert!(store.add_if_missing(act1.clone()).await.unwrap());
+        assert!(!store.add_if_missing(act2.clone()).await.unwrap());
+        assert_eq!(store.get("1").await.unwrap(), Some(act1));
     }
 }
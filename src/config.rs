@@ -0,0 +1,521 @@
+//! Application settings.
+//!
+//! Settings start from built-in defaults, are overridden by a TOML file, then
+//! by environment variables and finally by command line flags. The result is
+//! validated once at startup so that mistakes are reported before the server
+//! starts.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::portfolio::LotMethod;
+use crate::provider::{FieldMapping, ProviderKind, SimulationParams};
+use crate::storage::StorageBackend;
+
+/// File read when neither `--config` nor `CONFIG_FILE` is given, if present.
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Upper bounds on market settings; larger values only stall the server.
+const MAX_QUOTE_AGE_HOURS: i64 = 24 * 365;
+const MAX_RETRY_ATTEMPTS: u32 = 10;
+const MAX_BACKFILL_YEARS: u32 = 50;
+/// Range of `market.rate_limit`, in requests per second.
+const MIN_RATE_LIMIT: f64 = 0.01;
+const MAX_RATE_LIMIT: f64 = 1000.0;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("failed to parse {}: {source}", path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("invalid {key}: {message}")]
+    Env { key: String, message: String },
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub data: DataConfig,
+    pub market: MarketConfig,
+    pub providers: ProvidersConfig,
+    pub trading: TradingConfig,
+    /// Token required in the `x-admin-token` header; admin endpoints are
+    /// disabled when unset.
+    pub admin_token: Option<String>,
+    /// Credentials of the Strava account activities are imported from.
+    pub strava: Option<StravaConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { bind: SocketAddr::from(([0, 0, 0, 0], 3000)) }
+    }
+}
+
+/// Where data is stored. Paths left unset live under `dir`.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    pub dir: PathBuf,
+    pub market_dir: Option<PathBuf>,
+    pub leagues_dir: Option<PathBuf>,
+    /// Seed for the instrument registry, loaded if the file exists.
+    pub instruments: Option<PathBuf>,
+    /// Where executed orders, prices and activities are kept. Pending
+    /// orders, holdings snapshots and leagues are Parquet files under `dir`
+    /// whichever backend is selected.
+    pub backend: StorageBackend,
+    /// Database file of the SQLite backend.
+    pub sqlite_path: Option<PathBuf>,
+    /// Orders a user's order log holds before it is compacted into Parquet.
+    pub compact_orders_after: usize,
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("data"),
+            market_dir: None,
+            leagues_dir: None,
+            instruments: None,
+            backend: StorageBackend::default(),
+            sqlite_path: None,
+            compact_orders_after: crate::storage::files::DEFAULT_COMPACT_AFTER,
+        }
+    }
+}
+
+impl DataConfig {
+    pub fn market_dir(&self) -> PathBuf {
+        self.market_dir.clone().unwrap_or_else(|| self.dir.join("market"))
+    }
+
+    pub fn leagues_dir(&self) -> PathBuf {
+        self.leagues_dir.clone().unwrap_or_else(|| self.dir.join("leagues"))
+    }
+
+    pub fn instruments(&self) -> PathBuf {
+        self.instruments.clone().unwrap_or_else(|| self.dir.join("instruments.json"))
+    }
+
+    pub fn sqlite_path(&self) -> PathBuf {
+        self.sqlite_path.clone().unwrap_or_else(|| self.dir.join("fantasy.db"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarketConfig {
+    pub update_interval_secs: u64,
+    pub max_quote_age_hours: i64,
+    pub retry_attempts: u32,
+    pub concurrency: usize,
+    pub fetch_timeout_secs: u64,
+    pub backfill_years: u32,
+    /// Seconds a symbol the provider does not know is rejected without
+    /// asking again; `0` asks every time.
+    pub unknown_symbol_ttl_secs: u64,
+    /// Requests per second sent to the quote providers; unlimited when unset.
+    pub rate_limit: Option<f64>,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        Self {
+            update_interval_secs: crate::market::DEFAULT_UPDATE_INTERVAL_SECS,
+            max_quote_age_hours: crate::market::DEFAULT_MAX_QUOTE_AGE_HOURS,
+            retry_attempts: crate::market::RetryPolicy::default().attempts,
+            concurrency: crate::market::DEFAULT_FETCH_CONCURRENCY,
+            fetch_timeout_secs: crate::market::DEFAULT_FETCH_TIMEOUT_SECS,
+            backfill_years: crate::market::DEFAULT_BACKFILL_YEARS,
+            unknown_symbol_ttl_secs: crate::market::DEFAULT_UNKNOWN_SYMBOL_TTL_SECS,
+            rate_limit: None,
+        }
+    }
+}
+
+/// Quote providers, tried in the order of `chain`.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvidersConfig {
+    pub chain: Vec<ProviderKind>,
+    pub file: Option<FileProviderConfig>,
+    pub http: Option<HttpProviderConfig>,
+    pub simulated: SimulationParams,
+}
+
+impl Default for ProvidersConfig {
+    fn default() -> Self {
+        Self { chain: vec![ProviderKind::Yahoo], file: None, http: None, simulated: SimulationParams::default() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileProviderConfig {
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HttpProviderConfig {
+    pub url: String,
+    pub history_url: Option<String>,
+    #[serde(default)]
+    pub fields: FieldMapping,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TradingConfig {
+    pub starting_cash: f64,
+    pub lot_method: LotMethod,
+}
+
+impl Default for TradingConfig {
+    fn default() -> Self {
+        Self { starting_cash: crate::holdings::DEFAULT_STARTING_CASH, lot_method: LotMethod::default() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StravaConfig {
+    pub access_token: String,
+}
+
+/// Command line flags. Each one overrides the file and the environment.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "Fantasy stock trading server")]
+pub struct Cli {
+    /// TOML configuration file [env: CONFIG_FILE] [default: config.toml if present]
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [env: BIND_ADDR]
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Directory holding orders, market data and leagues [env: DATA_DIR]
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// Seconds between market data refreshes [env: UPDATE_INTERVAL_SECS]
+    #[arg(long)]
+    pub update_interval_secs: Option<u64>,
+    /// Comma separated quote providers, tried in order [env: QUOTE_PROVIDERS]
+    #[arg(long, value_delimiter = ',')]
+    pub providers: Option<Vec<ProviderKind>>,
+}
+
+impl Config {
+    /// Load the configuration for this process from its arguments and
+    /// environment.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(Cli::parse(), |key| std::env::var(key).ok())
+    }
+
+    /// Layer the file named by `cli` or the environment, the environment
+    /// read through `env`, and the flags in `cli` over the defaults.
+    pub fn load_from(cli: Cli, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let path = cli.config.clone().or_else(|| env("CONFIG_FILE").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_env(env)?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let env = &env;
+        set(env, "BIND_ADDR", &mut self.server.bind)?;
+        set(env, "DATA_DIR", &mut self.data.dir)?;
+        set(env, "STORAGE_BACKEND", &mut self.data.backend)?;
+        set_opt(env, "SQLITE_PATH", &mut self.data.sqlite_path)?;
+        set(env, "ORDER_LOG_COMPACT_AFTER", &mut self.data.compact_orders_after)?;
+
+        let market = &mut self.market;
+        set(env, "UPDATE_INTERVAL_SECS", &mut market.update_interval_secs)?;
+        set(env, "MAX_QUOTE_AGE_HOURS", &mut market.max_quote_age_hours)?;
+        set(env, "QUOTE_RETRY_ATTEMPTS", &mut market.retry_attempts)?;
+        set(env, "QUOTE_CONCURRENCY", &mut market.concurrency)?;
+        set(env, "QUOTE_TIMEOUT_SECS", &mut market.fetch_timeout_secs)?;
+        set(env, "BACKFILL_YEARS", &mut market.backfill_years)?;
+        set(env, "UNKNOWN_SYMBOL_TTL_SECS", &mut market.unknown_symbol_ttl_secs)?;
+        set_opt(env, "QUOTE_RATE_LIMIT", &mut market.rate_limit)?;
+
+        let providers = &mut self.providers;
+        if let Some(list) = env("QUOTE_PROVIDERS") {
+            providers.chain = list
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|message| ConfigError::Env { key: "QUOTE_PROVIDERS".into(), message })?;
+        }
+        if let Some(dir) = env("QUOTE_FILE_DIR") {
+            providers.file = Some(FileProviderConfig { dir: dir.into() });
+        }
+        if let Some(url) = env("QUOTE_HTTP_URL") {
+            let http = providers.http.get_or_insert_with(|| HttpProviderConfig {
+                url: String::new(),
+                history_url: None,
+                fields: FieldMapping::default(),
+            });
+            http.url = url;
+        }
+        if let Some(http) = providers.http.as_mut() {
+            set_opt(env, "QUOTE_HTTP_HISTORY_URL", &mut http.history_url)?;
+            if let Some(json) = env("QUOTE_HTTP_FIELDS") {
+                http.fields = serde_json::from_str(&json)
+                    .map_err(|e| ConfigError::Env { key: "QUOTE_HTTP_FIELDS".into(), message: e.to_string() })?;
+            }
+        }
+        let sim = &mut providers.simulated;
+        set(env, "SIM_SEED", &mut sim.seed)?;
+        set(env, "SIM_DRIFT", &mut sim.drift)?;
+        set(env, "SIM_VOLATILITY", &mut sim.volatility)?;
+        set(env, "SIM_START_PRICE", &mut sim.start_price)?;
+
+        set(env, "STARTING_CASH", &mut self.trading.starting_cash)?;
+        set(env, "LOT_METHOD", &mut self.trading.lot_method)?;
+        set_opt(env, "ADMIN_TOKEN", &mut self.admin_token)?;
+        if let Some(access_token) = env("STRAVA_ACCESS_TOKEN") {
+            self.strava = Some(StravaConfig { access_token });
+        }
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: Cli) {
+        if let Some(bind) = cli.bind {
+            self.server.bind = bind;
+        }
+        if let Some(dir) = cli.data_dir {
+            self.data.dir = dir;
+        }
+        if let Some(secs) = cli.update_interval_secs {
+            self.market.update_interval_secs = secs;
+        }
+        if let Some(chain) = cli.providers {
+            self.providers.chain = chain;
+        }
+    }
+
+    /// Reject settings the server cannot run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+        if self.data.compact_orders_after == 0 {
+            return invalid("data.compact_orders_after must be at least 1");
+        }
+        let market = &self.market;
+        if market.update_interval_secs == 0 {
+            return invalid("market.update_interval_secs must be positive");
+        }
+        if !(1..=MAX_QUOTE_AGE_HOURS).contains(&market.max_quote_age_hours) {
+            return invalid(&format!("market.max_quote_age_hours must be between 1 and {MAX_QUOTE_AGE_HOURS}"));
+        }
+        if !(1..=MAX_RETRY_ATTEMPTS).contains(&market.retry_attempts) {
+            return invalid(&format!("market.retry_attempts must be between 1 and {MAX_RETRY_ATTEMPTS}"));
+        }
+        if market.concurrency == 0 {
+            return invalid("market.concurrency must be at least 1");
+        }
+        if market.fetch_timeout_secs == 0 {
+            return invalid("market.fetch_timeout_secs must be positive");
+        }
+        if market.backfill_years > MAX_BACKFILL_YEARS {
+            return invalid(&format!("market.backfill_years must be at most {MAX_BACKFILL_YEARS}"));
+        }
+        if market.rate_limit.is_some_and(|r| !(MIN_RATE_LIMIT..=MAX_RATE_LIMIT).contains(&r)) {
+            return invalid(&format!(
+                "market.rate_limit must be between {MIN_RATE_LIMIT} and {MAX_RATE_LIMIT} requests per second"
+            ));
+        }
+
+        let providers = &self.providers;
+        if providers.chain.is_empty() {
+            return invalid("providers.chain must name at least one provider");
+        }
+        for kind in &providers.chain {
+            match kind {
+                ProviderKind::File if providers.file.is_none() => {
+                    return invalid("the file provider needs providers.file.dir (QUOTE_FILE_DIR)");
+                }
+                ProviderKind::Http if providers.http.as_ref().is_none_or(|h| h.url.is_empty()) => {
+                    return invalid("the http provider needs providers.http.url (QUOTE_HTTP_URL)");
+                }
+                _ => {}
+            }
+        }
+        let sim = &providers.simulated;
+        let valid_sim = sim.start_price > 0.0 && sim.start_price.is_finite() && sim.volatility >= 0.0 && sim.volatility.is_finite() && sim.drift.is_finite();
+        if !valid_sim {
+            return invalid("providers.simulated needs a positive start_price, a non-negative volatility and a finite drift");
+        }
+
+        let cash = self.trading.starting_cash;
+        if cash < 0.0 || !cash.is_finite() {
+            return invalid("trading.starting_cash must be a non-negative amount");
+        }
+        if self.admin_token.as_deref() == Some("") {
+            return invalid("admin_token must not be empty");
+        }
+        if self.strava.as_ref().is_some_and(|s| s.access_token.is_empty()) {
+            return invalid("strava.access_token must not be empty");
+        }
+        Ok(())
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Env { key: key.into(), message: e.to_string() })
+}
+
+/// Overwrite `target` with the parsed value of environment variable `key`.
+fn set<T>(env: &impl Fn(&str) -> Option<String>, key: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(key) {
+        *target = parse(key, &value)?;
+    }
+    Ok(())
+}
+
+fn set_opt<T>(env: &impl Fn(&str) -> Option<String>, key: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(key) {
+        *target = Some(parse(key, &value)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    fn write_config(text: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, text).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn file_env_and_cli_are_layered() {
+        let (_dir, path) = write_config(
+            r#"
+            admin_token = "from-file"
+
+            [server]
+            bind = "127.0.0.1:8080"
+
+            [data]
+            dir = "/srv/fantasy"
+
+            [market]
+            update_interval_secs = 30
+            concurrency = 2
+
+            [providers]
+            chain = ["http", "yahoo"]
+
+            [providers.http]
+            url = "https://quotes.example/{symbol}"
+            fields = { close = "/c" }
+
+            [trading]
+            lot_method = "fifo"
+            "#,
+        );
+        let cli = Cli::try_parse_from(["app", "--config", path.to_str().unwrap(), "--update-interval-secs", "10"]).unwrap();
+        let config = Config::load_from(cli, env(&[("QUOTE_CONCURRENCY", "4"), ("ADMIN_TOKEN", "from-env")])).unwrap();
+
+        assert_eq!(config.server.bind, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.data.market_dir(), PathBuf::from("/srv/fantasy/market"));
+        assert_eq!(config.market.update_interval_secs, 10);
+        assert_eq!(config.market.concurrency, 4);
+        assert_eq!(config.market.fetch_timeout_secs, crate::market::DEFAULT_FETCH_TIMEOUT_SECS);
+        assert_eq!(config.providers.chain, vec![ProviderKind::Http, ProviderKind::Yahoo]);
+        let http = config.providers.http.unwrap();
+        assert_eq!(http.fields.close, "/c");
+        assert_eq!(http.fields.open, "/open");
+        assert_eq!(config.trading.lot_method, LotMethod::Fifo);
+        assert_eq!(config.admin_token.as_deref(), Some("from-env"));
+    }
+
+    #[test]
+    fn defaults_without_file() {
+        let cli = Cli::try_parse_from(["app", "--providers", "simulated,yahoo"]).unwrap();
+        let config = Config::load_from(cli, env(&[("SIM_SEED", "9"), ("STRAVA_ACCESS_TOKEN", "abc")])).unwrap();
+        assert_eq!(config.server, ServerConfig::default());
+        assert_eq!(config.data.leagues_dir(), PathBuf::from("data/leagues"));
+        assert_eq!(config.providers.chain, vec![ProviderKind::Simulated, ProviderKind::Yahoo]);
+        assert_eq!(config.providers.simulated.seed, 9);
+        assert_eq!(config.strava.unwrap().access_token, "abc");
+        assert_eq!(config.admin_token, None);
+    }
+
+    #[test]
+    fn reports_invalid_settings() {
+        let load = |vars: &[(&str, &str)]| Config::load_from(Cli::default(), env(vars)).unwrap_err().to_string();
+
+        assert_eq!(load(&[("QUOTE_CONCURRENCY", "many")]), "invalid QUOTE_CONCURRENCY: invalid digit found in string");
+        assert_eq!(load(&[("QUOTE_PROVIDERS", "yahoo,bloomberg")]), r#"invalid QUOTE_PROVIDERS: unknown quote provider "bloomberg""#);
+        assert_eq!(load(&[("QUOTE_CONCURRENCY", "0")]), "invalid configuration: market.concurrency must be at least 1");
+        assert!(load(&[("QUOTE_PROVIDERS", "file")]).contains("QUOTE_FILE_DIR"));
+        assert!(load(&[("QUOTE_RATE_LIMIT", "-1")]).contains("market.rate_limit"));
+        for rate in ["0.001", "1e9", "NaN", "inf"] {
+            assert!(load(&[("QUOTE_RATE_LIMIT", rate)]).contains("market.rate_limit"), "{rate}");
+        }
+        assert!(load(&[("QUOTE_RETRY_ATTEMPTS", "4000000000")]).contains("market.retry_attempts"));
+        assert!(load(&[("BACKFILL_YEARS", "1000")]).contains("market.backfill_years"));
+        assert!(load(&[("MAX_QUOTE_AGE_HOURS", "9223372036854775807")]).contains("market.max_quote_age_hours"));
+        for rate in ["0.01", "1000"] {
+            let limits = [("QUOTE_RATE_LIMIT", rate), ("QUOTE_RETRY_ATTEMPTS", "10"), ("BACKFILL_YEARS", "50"), ("MAX_QUOTE_AGE_HOURS", "8760")];
+            Config::load_from(Cli::default(), env(&limits)).unwrap();
+        }
+
+        let (_dir, path) = write_config("[market]\nrefresh = 5\n");
+        let err = Config::load_from(Cli { config: Some(path), ..Cli::default() }, env(&[])).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }));
+        assert!(err.to_string().contains("unknown field `refresh`"), "{err}");
+
+        let missing = Cli { config: Some(PathBuf::from("/nonexistent/config.toml")), ..Cli::default() };
+        assert!(matches!(Config::load_from(missing, env(&[])), Err(ConfigError::Read { .. })));
+    }
+}
@@ -23,9 +23,17 @@ impl AppError {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
     }
 
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
     pub fn not_found(message: impl Into<String>) -> Self {
         Self::new(StatusCode::NOT_FOUND, message)
     }
+
+    pub fn unprocessable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, message)
+    }
 }
 
 impl IntoResponse for AppError {
@@ -41,7 +49,62 @@ impl From<crate::holdings::StoreError> for AppError {
             crate::holdings::StoreError::NoOrders(user) => {
                 AppError::not_found(format!("no orders for user {user}"))
             }
+            e @ (crate::holdings::StoreError::InvalidOrder(_)
+            | crate::holdings::StoreError::InsufficientPosition { .. }
+            | crate::holdings::StoreError::InsufficientFunds { .. }) => {
+                AppError::unprocessable(e.to_string())
+            }
+            e @ crate::holdings::StoreError::InvalidId(_) => AppError::bad_request(e.to_string()),
             crate::holdings::StoreError::Other(e) => AppError::internal(e.to_string()),
         }
     }
 }
+
+impl From<crate::ident::IdError> for AppError {
+    fn from(err: crate::ident::IdError) -> Self {
+        AppError::bad_request(err.to_string())
+    }
+}
+
+impl From<crate::league::LeagueError> for AppError {
+    fn from(err: crate::league::LeagueError) -> Self {
+        use crate::league::LeagueError;
+        match err {
+            LeagueError::NotFound(_) => AppError::not_found(err.to_string()),
+            LeagueError::Invalid(_) => AppError::bad_request(err.to_string()),
+            LeagueError::RuleViolation { .. } => AppError::unprocessable(err.to_string()),
+            LeagueError::Other(e) => AppError::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<crate::market::QuoteError> for AppError {
+    fn from(err: crate::market::QuoteError) -> Self {
+        use crate::market::QuoteError;
+        match err {
+            QuoteError::Missing(_) | QuoteError::Stale { .. } => AppError::unprocessable(err.to_string()),
+            QuoteError::Fetch(e) => AppError::new(StatusCode::BAD_GATEWAY, format!("failed to fetch quote: {e}")),
+        }
+    }
+}
+
+impl From<crate::market::BackfillError> for AppError {
+    fn from(err: crate::market::BackfillError) -> Self {
+        use crate::market::BackfillError;
+        match err {
+            BackfillError::Fetch(e) => AppError::new(StatusCode::BAD_GATEWAY, format!("failed to fetch history: {e}")),
+            BackfillError::Storage(e) => AppError::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<crate::replay::ReplayError> for AppError {
+    fn from(err: crate::replay::ReplayError) -> Self {
+        use crate::replay::ReplayError;
+        match err {
+            ReplayError::NotActive | ReplayError::Trading => AppError::new(StatusCode::CONFLICT, err.to_string()),
+            ReplayError::Invalid(_) => AppError::bad_request(err.to_string()),
+            ReplayError::Other(e) => AppError::internal(e.to_string()),
+        }
+    }
+}
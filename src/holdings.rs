@@ -1,39 +1,171 @@
 use std::collections::HashMap;
+use std::future::Future;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::RwLock;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
 use anyhow::Context;
+use arrow_array::Array;
+use chrono::{DateTime, SubsecRound, Utc};
+use uuid::Uuid;
+
+use crate::league::{LeagueError, LeagueStore};
+use crate::orderbook::{OrderBook, OrderType, TimeInForce};
+use crate::storage::files::KeyedLocks;
+use crate::storage::{OrderStorage, ParquetOrderStorage};
 
 #[derive(Debug, Error)]
 pub enum StoreError {
     #[error("no orders for user {0}")]
     NoOrders(String),
+    #[error("invalid order: {0}")]
+    InvalidOrder(String),
+    #[error(transparent)]
+    InvalidId(#[from] crate::ident::IdError),
+    #[error("cannot sell {requested} {symbol}: only {held} held")]
+    InsufficientPosition { symbol: String, held: i64, requested: i64 },
+    #[error("insufficient buying power: order costs {required:.2} but only {available:.2} cash available")]
+    InsufficientFunds { required: f64, available: f64 },
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+/// Why a pending order could not be filled.
+enum FillError {
+    /// The order breaks a rule and is dropped.
+    Rejected(String),
+    /// The order could not be stored and stays open.
+    Failed(anyhow::Error),
+}
+
+impl From<StoreError> for FillError {
+    fn from(err: StoreError) -> Self {
+        match err {
+            StoreError::Other(e) => FillError::Failed(e),
+            e => FillError::Rejected(e.to_string()),
+        }
+    }
+}
+
+impl From<LeagueError> for FillError {
+    fn from(err: LeagueError) -> Self {
+        match err {
+            LeagueError::Other(e) => FillError::Failed(e),
+            e => FillError::Rejected(e.to_string()),
+        }
+    }
+}
+
+/// Direction of an order.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    #[default]
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+impl std::str::FromStr for Side {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            other => Err(anyhow::anyhow!("unknown order side {other}")),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Order {
+    pub id: String,
     pub user: String,
     pub symbol: String,
+    pub side: Side,
     pub amount: i64,
     pub price: f64,
+    pub executed_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl Order {
+    /// Create an order with a fresh id executed at the current time.
+    ///
+    /// The timestamp is truncated to the microsecond precision it is stored with.
+    pub fn new(user: impl Into<String>, symbol: impl Into<String>, side: Side, amount: i64, price: f64) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            user: user.into(),
+            symbol: symbol.into(),
+            side,
+            amount,
+            price,
+            executed_at: Utc::now().trunc_subsecs(6),
+            note: None,
+        }
+    }
+}
+
+fn validate_order(order: &Order) -> Result<(), StoreError> {
+    crate::ident::validate_user(&order.user)?;
+    crate::ident::validate_symbol(&order.symbol)?;
+    if order.amount <= 0 {
+        return Err(StoreError::InvalidOrder(format!("amount must be positive, got {}", order.amount)));
+    }
+    if !order.price.is_finite() || order.price <= 0.0 {
+        return Err(StoreError::InvalidOrder(format!("price must be positive, got {}", order.price)));
+    }
+    Ok(())
+}
+
+/// Quantity of `symbol` held after applying `orders`.
+fn net_quantity(orders: &[Order], symbol: &str) -> i64 {
+    orders
+        .iter()
+        .filter(|o| o.symbol == symbol)
+        .map(|o| match o.side {
+            Side::Buy => o.amount,
+            Side::Sell => -o.amount,
+        })
+        .sum()
+}
+
+/// Cash left after applying `orders` to a balance of `starting_cash`.
+fn cash_balance(orders: &[Order], starting_cash: f64) -> f64 {
+    orders.iter().fold(starting_cash, |cash, o| match o.side {
+        Side::Buy => cash - o.price * o.amount as f64,
+        Side::Sell => cash + o.price * o.amount as f64,
+    })
 }
 
 fn order_schema() -> arrow_schema::Schema {
-    use arrow_schema::{DataType, Field, Schema};
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
     Schema::new(vec![
         Field::new("user", DataType::Utf8, false),
         Field::new("symbol", DataType::Utf8, false),
         Field::new("amount", DataType::Int64, false),
         Field::new("price", DataType::Float64, false),
+        Field::new("id", DataType::Utf8, false),
+        Field::new("side", DataType::Utf8, false),
+        Field::new("executed_at", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("note", DataType::Utf8, true),
     ])
 }
 
-fn orders_to_record_batch(orders: &[Order]) -> anyhow::Result<arrow_array::RecordBatch> {
-    use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+pub(crate) fn orders_to_record_batch(orders: &[Order]) -> anyhow::Result<arrow_array::RecordBatch> {
+    use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
     use std::sync::Arc as SyncArc;
 
     let schema = SyncArc::new(order_schema());
@@ -41,6 +173,12 @@ fn orders_to_record_batch(orders: &[Order]) -> anyhow::Result<arrow_array::Recor
     let symbol_array = StringArray::from_iter_values(orders.iter().map(|o| o.symbol.as_str()));
     let amount_array = Int64Array::from_iter_values(orders.iter().map(|o| o.amount));
     let price_array = Float64Array::from_iter_values(orders.iter().map(|o| o.price));
+    let id_array = StringArray::from_iter_values(orders.iter().map(|o| o.id.as_str()));
+    let side_array = StringArray::from_iter_values(orders.iter().map(|o| o.side.as_str()));
+    let executed_array =
+        TimestampMicrosecondArray::from_iter_values(orders.iter().map(|o| o.executed_at.timestamp_micros()))
+            .with_timezone("UTC");
+    let note_array: StringArray = orders.iter().map(|o| o.note.as_deref()).collect();
 
     Ok(RecordBatch::try_new(
         schema,
@@ -49,52 +187,190 @@ fn orders_to_record_batch(orders: &[Order]) -> anyhow::Result<arrow_array::Recor
             SyncArc::new(symbol_array),
             SyncArc::new(amount_array),
             SyncArc::new(price_array),
+            SyncArc::new(id_array),
+            SyncArc::new(side_array),
+            SyncArc::new(executed_array),
+            SyncArc::new(note_array),
         ],
     )?)
 }
 
-fn batch_to_orders(batch: &arrow_array::RecordBatch) -> Vec<Order> {
-    use arrow_array::{Float64Array, Int64Array, StringArray};
+/// Decode a batch of orders.
+///
+/// Files written before orders carried an id, side and timestamp only have the
+/// `user`, `symbol`, `amount` and `price` columns. Rows from those files get a
+/// deterministic id derived from their position, a side taken from the sign of
+/// `amount` and the Unix epoch as their (unknown) execution time.
+pub(crate) fn batch_to_orders(batch: &arrow_array::RecordBatch, offset: usize) -> anyhow::Result<Vec<Order>> {
+    use arrow_array::{Float64Array, Int64Array, StringArray, TimestampMicrosecondArray};
 
     let user_array = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
     let symbol_array = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
     let amount_array = batch.column(2).as_any().downcast_ref::<Int64Array>().unwrap();
     let price_array = batch.column(3).as_any().downcast_ref::<Float64Array>().unwrap();
 
+    let string_column = |name: &str| {
+        batch
+            .column_by_name(name)
+            .and_then(|c| c.as_any().downcast_ref::<StringArray>())
+    };
+    let id_array = string_column("id");
+    let side_array = string_column("side");
+    let note_array = string_column("note");
+    let executed_array = batch
+        .column_by_name("executed_at")
+        .and_then(|c| c.as_any().downcast_ref::<TimestampMicrosecondArray>());
+
     (0..batch.num_rows())
-        .map(|i| Order {
-            user: user_array.value(i).to_string(),
-            symbol: symbol_array.value(i).to_string(),
-            amount: amount_array.value(i),
-            price: price_array.value(i),
+        .map(|i| {
+            let user = user_array.value(i).to_string();
+            let raw_amount = amount_array.value(i);
+            let side = match side_array {
+                Some(a) => a.value(i).parse()?,
+                None if raw_amount < 0 => Side::Sell,
+                None => Side::Buy,
+            };
+            let id = match id_array {
+                Some(a) => a.value(i).to_string(),
+                None => format!("legacy-{user}-{}", offset + i),
+            };
+            let executed_at = match executed_array {
+                Some(a) => DateTime::<Utc>::from_timestamp_micros(a.value(i))
+                    .context("invalid order timestamp")?,
+                None => DateTime::<Utc>::UNIX_EPOCH,
+            };
+            let note = note_array.filter(|a| a.is_valid(i)).map(|a| a.value(i).to_string());
+            Ok(Order {
+                id,
+                user,
+                symbol: symbol_array.value(i).to_string(),
+                side,
+                amount: raw_amount.abs(),
+                price: price_array.value(i),
+                executed_at,
+                note,
+            })
         })
         .collect()
 }
 
+/// Virtual cash every user starts the game with.
+pub const DEFAULT_STARTING_CASH: f64 = 100_000.0;
+
+/// Executed orders per user, cached in memory in front of an [`OrderStorage`].
 #[derive(Clone)]
 pub struct HoldingStore {
-    data_dir: PathBuf,
+    storage: Arc<dyn OrderStorage>,
     inner: Arc<RwLock<HashMap<String, Vec<Order>>>>,
-    fs_lock: Arc<Mutex<()>>,
+    /// Held while a user's order is checked and persisted.
+    user_locks: Arc<KeyedLocks>,
+    starting_cash: f64,
+    book: OrderBook,
+    /// Leagues whose rules pending orders are checked against when filled.
+    leagues: Option<LeagueStore>,
 }
 
 impl HoldingStore {
+    /// Store orders as Parquet files under `data_dir`.
     pub fn new(data_dir: PathBuf) -> Self {
         Self {
-            data_dir,
+            book: OrderBook::new(data_dir.clone()),
+            storage: Arc::new(ParquetOrderStorage::new(data_dir)),
             inner: Arc::new(RwLock::new(HashMap::new())),
-            fs_lock: Arc::new(Mutex::new(())),
+            user_locks: Arc::new(KeyedLocks::new()),
+            starting_cash: DEFAULT_STARTING_CASH,
+            leagues: None,
         }
     }
 
+    /// Keep executed orders in `storage` instead of the default Parquet files.
+    pub fn with_storage(mut self, storage: Arc<dyn OrderStorage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Give every user `cash` to trade with instead of [`DEFAULT_STARTING_CASH`].
+    pub fn with_starting_cash(mut self, cash: f64) -> Self {
+        self.starting_cash = cash;
+        self
+    }
+
+    /// Check fills of pending orders against the rules of `leagues`.
+    pub fn with_leagues(mut self, leagues: LeagueStore) -> Self {
+        self.leagues = Some(leagues);
+        self
+    }
+
+    /// Read the orders and pending orders of every stored user, returning
+    /// the number of users with orders.
+    ///
+    /// Users are otherwise loaded lazily on first access, which leaves
+    /// [`HoldingStore::all_orders`] empty after a restart.
+    pub async fn load(&self) -> anyhow::Result<usize> {
+        let users = self.storage.users().await.context("failed to list users with orders")?;
+        let mut loaded = 0;
+        for user in users {
+            if !self.load_user(&user).await?.is_empty() {
+                loaded += 1;
+            }
+        }
+        self.book.load().await?;
+        Ok(loaded)
+    }
+
+    /// Validate and append `order`, persisting it to storage.
+    ///
+    /// Sells are rejected if they exceed the user's current net position in
+    /// the symbol and buys if they cost more than the user's cash balance.
+    /// The order only counts towards the user's position once it is stored.
     pub async fn add_order(&self, order: Order) -> Result<(), StoreError> {
-        {
-            let mut map = self.inner.write().await;
-            map.entry(order.user.clone()).or_default().push(order.clone());
+        validate_order(&order)?;
+        let _lock = self.user_locks.lock(&order.user).await;
+        let orders = self.load_user(&order.user).await?;
+        self.append_locked(order, &orders).await
+    }
+
+    /// Like [`HoldingStore::add_order`], but first run `check` on the order
+    /// and the user's existing orders. `check` runs under the same per-user
+    /// lock as the append, so concurrent orders of one user cannot all pass a
+    /// rule that only one of them should.
+    pub async fn add_order_checked<F, Fut, E>(&self, order: Order, check: F) -> Result<(), E>
+    where
+        F: FnOnce(Order, Vec<Order>) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: From<StoreError>,
+    {
+        validate_order(&order)?;
+        let _lock = self.user_locks.lock(&order.user).await;
+        let orders = self.load_user(&order.user).await.map_err(StoreError::from)?;
+        check(order.clone(), orders.clone()).await?;
+        Ok(self.append_locked(order, &orders).await?)
+    }
+
+    /// Check `order` against the user's `orders` and persist it. The caller
+    /// holds the user's lock.
+    async fn append_locked(&self, order: Order, orders: &[Order]) -> Result<(), StoreError> {
+        match order.side {
+            Side::Sell => {
+                let held = net_quantity(orders, &order.symbol);
+                if order.amount > held {
+                    return Err(StoreError::InsufficientPosition {
+                        symbol: order.symbol.clone(),
+                        held,
+                        requested: order.amount,
+                    });
+                }
+            }
+            Side::Buy => {
+                let required = order.price * order.amount as f64;
+                let available = cash_balance(orders, self.starting_cash);
+                if required > available {
+                    return Err(StoreError::InsufficientFunds { required, available });
+                }
+            }
         }
-        self.write_user_file(&order.user)
-            .await
-            .context("failed to persist order")?;
+        self.storage.append_order(&order).await.context("failed to persist order")?;
+        self.inner.write().await.entry(order.user.clone()).or_default().push(order);
         Ok(())
     }
 
@@ -107,6 +383,76 @@ impl HoldingStore {
         &self,
         user: &str,
     ) -> Result<Vec<Order>, StoreError> {
+        let orders = self.load_user(user).await?;
+        if orders.is_empty() {
+            return Err(StoreError::NoOrders(user.to_string()));
+        }
+        Ok(orders)
+    }
+
+    /// Open limit and stop orders waiting to be filled.
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    /// Fill every pending order whose conditions are met at `prices`.
+    ///
+    /// Fills that fail validation, e.g. because the user no longer has the
+    /// cash or shares or a league rule forbids them, are dropped from the
+    /// book and logged. Fills that cannot be persisted go back in the book
+    /// to be tried again.
+    pub async fn match_pending(
+        &self,
+        prices: &HashMap<String, f64>,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Order>> {
+        let evaluation = self.book.evaluate(prices, now).await?;
+        for expired in &evaluation.expired {
+            tracing::info!("pending order {} for {} expired", expired.id, expired.user);
+        }
+        let mut filled = Vec::new();
+        for (pending, price) in evaluation.fills {
+            let order = Order {
+                id: pending.id.clone(),
+                user: pending.user.clone(),
+                symbol: pending.symbol.clone(),
+                side: pending.side,
+                amount: pending.amount,
+                price,
+                executed_at: now.trunc_subsecs(6),
+                note: pending.note.clone(),
+            };
+            let leagues = self.leagues.clone();
+            let result = self
+                .add_order_checked(order.clone(), |order, existing| async move {
+                    match leagues {
+                        Some(leagues) => leagues.check_order(&order, &existing).await.map_err(FillError::from),
+                        None => Ok(()),
+                    }
+                })
+                .await;
+            match result {
+                Ok(()) => filled.push(order),
+                Err(FillError::Rejected(reason)) => tracing::warn!("rejected fill of pending order {}: {reason}", order.id),
+                Err(FillError::Failed(e)) => {
+                    tracing::error!("failed to fill pending order {}, keeping it open: {e:#}", order.id);
+                    if let Err(e) = self.book.place(pending).await {
+                        tracing::error!("lost pending order {}: {e:#}", order.id);
+                    }
+                }
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Cash `user` has left to trade with.
+    pub async fn cash_for_user(&self, user: &str) -> Result<f64, StoreError> {
+        let orders = self.load_user(user).await?;
+        Ok(cash_balance(&orders, self.starting_cash))
+    }
+
+    /// Orders for `user`, reading them from storage on first access.
+    async fn load_user(&self, user: &str) -> anyhow::Result<Vec<Order>> {
         {
             let map = self.inner.read().await;
             if let Some(orders) = map.get(user) {
@@ -114,73 +460,408 @@ impl HoldingStore {
             }
         }
 
-        let loaded = self.read_user_file(user)
+        let loaded = self.storage.orders(user)
             .await
             .with_context(|| format!("failed to load orders for {user}"))?;
         if loaded.is_empty() {
-            return Err(StoreError::NoOrders(user.to_string()));
+            return Ok(loaded);
         }
 
         let mut map = self.inner.write().await;
-        map.insert(user.to_string(), loaded.clone());
-        Ok(loaded)
+        Ok(map.entry(user.to_string()).or_insert(loaded).clone())
     }
+}
 
-    async fn write_user_file(&self, user: &str) -> anyhow::Result<()> {
-        use parquet::arrow::ArrowWriter;
-        use std::fs::{create_dir_all, File};
-
-        let _lock = self.fs_lock.lock().await;
-
-        let user_dir = self.data_dir.join(user);
-        create_dir_all(&user_dir)?;
-        let file_path = user_dir.join("orders.parquet");
-
-        let map = self.inner.read().await;
-        let orders = map.get(user).cloned().unwrap_or_default();
-        drop(map);
-
-        let batch = orders_to_record_batch(&orders)?;
+/// Order placed by a player. Market orders are filled at the latest market
+/// price; other order types are queued in the [`OrderBook`].
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct OrderRequest {
+    pub user: String,
+    pub symbol: String,
+    #[serde(default)]
+    pub side: Side,
+    pub amount: i64,
+    #[serde(default)]
+    pub order_type: OrderType,
+    #[serde(default)]
+    pub limit_price: Option<f64>,
+    #[serde(default)]
+    pub stop_price: Option<f64>,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    #[serde(default)]
+    pub note: Option<String>,
+}
 
-        let file = File::create(file_path)?;
-        let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
-        writer.write(&batch)?;
-        writer.close()?;
-        Ok(())
+impl OrderRequest {
+    /// Turn a limit or stop request into an order for the book.
+    pub fn into_pending(self, now: DateTime<Utc>) -> Result<crate::orderbook::PendingOrder, StoreError> {
+        let note = self.note;
+        crate::orderbook::PendingOrder::new(
+            self.user,
+            self.symbol,
+            self.side,
+            self.amount,
+            self.order_type,
+            self.limit_price,
+            self.stop_price,
+            self.time_in_force,
+            now,
+        )
+        .map(|pending| crate::orderbook::PendingOrder { note, ..pending })
+        .map_err(StoreError::InvalidOrder)
     }
 
-    async fn read_user_file(&self, user: &str) -> anyhow::Result<Vec<Order>> {
-        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
-        use std::fs::File;
-
-        let file_path = self.data_dir.join(user).join("orders.parquet");
-        if !file_path.exists() {
-            return Ok(Vec::new());
+    /// Turn the request into an order filled at `price` and `now`.
+    pub fn fill(self, price: f64, now: DateTime<Utc>) -> Order {
+        Order {
+            executed_at: now.trunc_subsecs(6),
+            note: self.note,
+            ..Order::new(self.user, self.symbol, self.side, self.amount, price)
         }
-
-        let _lock = self.fs_lock.lock().await;
-        let file = File::open(file_path)?;
-        let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
-        let mut reader = builder.build()?;
-        let mut orders = Vec::new();
-        while let Some(batch) = reader.next() {
-            let batch = batch?;
-            orders.extend(batch_to_orders(&batch));
-        }
-        Ok(orders)
     }
 }
 
+/// Historical trade recorded by an administrator with an explicit price.
 #[derive(Debug, Deserialize, Serialize)]
-pub struct OrderRequest {
+pub struct BackfillOrderRequest {
     pub user: String,
     pub symbol: String,
+    #[serde(default)]
+    pub side: Side,
     pub amount: i64,
     pub price: f64,
+    #[serde(default)]
+    pub executed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+impl From<BackfillOrderRequest> for Order {
+    fn from(req: BackfillOrderRequest) -> Self {
+        let order = Order::new(req.user, req.symbol, req.side, req.amount, req.price);
+        Order {
+            executed_at: req.executed_at.map(|t| t.trunc_subsecs(6)).unwrap_or(order.executed_at),
+            note: req.note,
+            ..order
+        }
+    }
 }
 
-impl From<OrderRequest> for Order {
-    fn from(req: OrderRequest) -> Self {
-        Order { user: req.user, symbol: req.symbol, amount: req.amount, price: req.price }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Order storage whose appends always fail.
+    struct FailingStorage;
+
+    #[axum::async_trait]
+    impl OrderStorage for FailingStorage {
+        async fn users(&self) -> anyhow::Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+        async fn orders(&self, _user: &str) -> anyhow::Result<Vec<Order>> {
+            Ok(Vec::new())
+        }
+        async fn append_order(&self, _order: &Order) -> anyhow::Result<()> {
+            anyhow::bail!("disk full")
+        }
+    }
+
+    #[tokio::test]
+    async fn orders_round_trip_with_new_columns() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let mut order = Order::new("alice", "AAPL", Side::Buy, 3, 12.5);
+        order.note = Some("take profit".into());
+        store.add_order(order.clone()).await.unwrap();
+
+        let reloaded = HoldingStore::new(dir.path().to_path_buf());
+        let orders = reloaded.orders_for_user("alice").await.unwrap();
+        assert_eq!(orders, vec![order]);
+    }
+
+    #[tokio::test]
+    async fn load_hydrates_every_user() {
+        use crate::orderbook::PendingOrder;
+
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 3, 12.5)).await.unwrap();
+        store.add_order(Order::new("bob", "MSFT", Side::Buy, 1, 20.0)).await.unwrap();
+        let pending = PendingOrder::new("carol".into(), "TSLA".into(), Side::Buy, 1, OrderType::Limit, Some(5.0), None, TimeInForce::Gtc, Utc::now()).unwrap();
+        store.book().place(pending.clone()).await.unwrap();
+        // directories without orders, like the market data, are skipped
+        std::fs::create_dir_all(dir.path().join("market/AAPL")).unwrap();
+
+        let restarted = HoldingStore::new(dir.path().to_path_buf());
+        assert!(restarted.all_orders().await.is_empty());
+        assert_eq!(restarted.load().await.unwrap(), 2);
+        let mut symbols: Vec<_> = restarted.all_orders().await.into_iter().map(|o| o.symbol).collect();
+        symbols.sort();
+        assert_eq!(symbols, vec!["AAPL", "MSFT"]);
+        assert!(restarted.book().symbols().await.contains("TSLA"));
+        assert_eq!(restarted.book().cancel(&pending.id).await.unwrap(), Some(pending));
+    }
+
+    /// Many users trading at once: every order is persisted, and the runtime
+    /// thread keeps running other tasks promptly while the files are written
+    /// on the blocking pool.
+    #[tokio::test(flavor = "current_thread")]
+    async fn users_trade_in_parallel_without_blocking_the_runtime() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+
+        const USERS: usize = 32;
+        const ORDERS_PER_USER: usize = 20;
+
+        let dir = tempdir().unwrap();
+        let storage = ParquetOrderStorage::new(dir.path().to_path_buf()).with_compact_after(5);
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_storage(Arc::new(storage));
+
+        // a single runtime thread runs both the traders and this task, so it
+        // is late whenever a trader does file I/O on that thread
+        let done = Arc::new(AtomicBool::new(false));
+        let heartbeat = tokio::spawn({
+            let done = done.clone();
+            async move {
+                let mut worst = Duration::ZERO;
+                while !done.load(Ordering::SeqCst) {
+                    let started = Instant::now();
+                    tokio::task::yield_now().await;
+                    worst = worst.max(started.elapsed());
+                }
+                worst
+            }
+        });
+
+        let traders: Vec<_> = (0..USERS)
+            .map(|u| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for i in 0..ORDERS_PER_USER {
+                        let order = Order::new(format!("user{u}"), "AAPL", Side::Buy, 1, 1.0 + i as f64);
+                        store.add_order(order).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for trader in traders {
+            trader.await.unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        let worst = heartbeat.await.unwrap();
+        assert!(worst < Duration::from_millis(200), "the runtime thread was blocked for {worst:?}");
+
+        let restarted = HoldingStore::new(dir.path().to_path_buf());
+        assert_eq!(restarted.load().await.unwrap(), USERS);
+        assert_eq!(restarted.all_orders().await.len(), USERS * ORDERS_PER_USER);
+    }
+
+    #[tokio::test]
+    async fn failed_appends_leave_no_trace() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_storage(Arc::new(FailingStorage));
+        let err = store.add_order(Order::new("alice", "AAPL", Side::Buy, 10, 100.0)).await.unwrap_err();
+        assert!(matches!(err, StoreError::Other(_)), "{err}");
+        assert!(store.all_orders().await.is_empty());
+        assert_eq!(store.cash_for_user("alice").await.unwrap(), DEFAULT_STARTING_CASH);
+    }
+
+    #[tokio::test]
+    async fn checks_run_one_at_a_time_per_user() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        // a rule allowing a single order, slow enough for the orders to overlap
+        let first_order_only = |_order: Order, existing: Vec<Order>| async move {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            if existing.is_empty() { Ok(()) } else { Err(StoreError::InvalidOrder("one order only".into())) }
+        };
+        let (a, b) = tokio::join!(
+            store.add_order_checked(Order::new("alice", "AAPL", Side::Buy, 1, 10.0), first_order_only),
+            store.add_order_checked(Order::new("alice", "AAPL", Side::Buy, 1, 10.0), first_order_only),
+        );
+        assert_eq!([a.is_ok(), b.is_ok()].iter().filter(|ok| **ok).count(), 1);
+        assert_eq!(store.all_orders().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_sells_beyond_position() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 5, 10.0)).await.unwrap();
+        store.add_order(Order::new("alice", "AAPL", Side::Sell, 3, 11.0)).await.unwrap();
+
+        let err = store
+            .add_order(Order::new("alice", "AAPL", Side::Sell, 3, 11.0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StoreError::InsufficientPosition { held: 2, requested: 3, .. }));
+        let err = store
+            .add_order(Order::new("alice", "MSFT", Side::Sell, 1, 11.0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StoreError::InsufficientPosition { held: 0, .. }));
+        assert_eq!(store.orders_for_user("alice").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn buys_debit_and_sells_credit_cash() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_starting_cash(100.0);
+        assert_eq!(store.cash_for_user("alice").await.unwrap(), 100.0);
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 8, 10.0)).await.unwrap();
+        assert_eq!(store.cash_for_user("alice").await.unwrap(), 20.0);
+
+        let err = store
+            .add_order(Order::new("alice", "MSFT", Side::Buy, 3, 10.0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StoreError::InsufficientFunds { .. }));
+
+        store.add_order(Order::new("alice", "AAPL", Side::Sell, 2, 15.0)).await.unwrap();
+        assert_eq!(store.cash_for_user("alice").await.unwrap(), 50.0);
+        store.add_order(Order::new("alice", "MSFT", Side::Buy, 5, 10.0)).await.unwrap();
+        assert_eq!(store.cash_for_user("alice").await.unwrap(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn rejects_non_positive_amounts() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let err = store
+            .add_order(Order::new("alice", "AAPL", Side::Buy, -5, 10.0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StoreError::InvalidOrder(_)));
+        assert!(matches!(store.orders_for_user("alice").await, Err(StoreError::NoOrders(_))));
+    }
+
+    #[tokio::test]
+    async fn validates_sells_against_orders_on_disk() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 5, 10.0)).await.unwrap();
+
+        let restarted = HoldingStore::new(dir.path().to_path_buf());
+        restarted.add_order(Order::new("alice", "AAPL", Side::Sell, 5, 12.0)).await.unwrap();
+        assert_eq!(restarted.orders_for_user("alice").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn match_pending_fills_orders_that_pass_validation() {
+        use crate::orderbook::PendingOrder;
+
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_starting_cash(100.0);
+        let now = Utc::now();
+        let buy = PendingOrder::new("alice".into(), "AAPL".into(), Side::Buy, 5, OrderType::Limit, Some(10.0), None, TimeInForce::Gtc, now).unwrap();
+        let sell = PendingOrder::new("bob".into(), "AAPL".into(), Side::Sell, 5, OrderType::Stop, None, Some(10.0), TimeInForce::Gtc, now).unwrap();
+        store.book().place(buy.clone()).await.unwrap();
+        store.book().place(sell).await.unwrap();
+
+        let prices = HashMap::from([("AAPL".to_string(), 9.0)]);
+        let filled = store.match_pending(&prices, now).await.unwrap();
+        // bob holds no shares, so his stop order is rejected at fill time
+        assert_eq!(filled.len(), 1);
+        assert_eq!(filled[0].id, buy.id);
+        assert_eq!(filled[0].price, 9.0);
+        assert_eq!(store.cash_for_user("alice").await.unwrap(), 55.0);
+        assert!(store.book().symbols().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn match_pending_applies_league_rules() {
+        use crate::league::{CreateLeagueRequest, TradingRules};
+        use crate::orderbook::PendingOrder;
+
+        let dir = tempdir().unwrap();
+        let now = Utc::now();
+        let leagues = LeagueStore::new(dir.path().join("leagues"));
+        let league = leagues
+            .create(CreateLeagueRequest {
+                name: "office".into(),
+                starting_bankroll: 1_000.0,
+                symbols: vec!["AAPL".into()],
+                start: now.date_naive(),
+                end: now.date_naive(),
+                rules: TradingRules { max_order_value: Some(20.0), max_trades_per_day: None },
+            })
+            .await
+            .unwrap();
+        leagues.join(&league.id, "alice", now.date_naive()).await.unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_leagues(leagues);
+        let pending = PendingOrder::new("alice".into(), "AAPL".into(), Side::Buy, 5, OrderType::Limit, Some(10.0), None, TimeInForce::Gtc, now).unwrap();
+        store.book().place(pending).await.unwrap();
+
+        let prices = HashMap::from([("AAPL".to_string(), 9.0)]);
+        // 5 shares at 9.0 exceed the league's order value limit
+        assert!(store.match_pending(&prices, now).await.unwrap().is_empty());
+        assert!(store.all_orders().await.is_empty());
+        assert!(store.book().for_user("alice").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn match_pending_keeps_orders_that_fail_to_store() {
+        use crate::orderbook::PendingOrder;
+
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_storage(Arc::new(FailingStorage));
+        let now = Utc::now();
+        let pending = PendingOrder::new("alice".into(), "AAPL".into(), Side::Buy, 5, OrderType::Limit, Some(10.0), None, TimeInForce::Gtc, now).unwrap();
+        store.book().place(pending.clone()).await.unwrap();
+
+        let prices = HashMap::from([("AAPL".to_string(), 9.0)]);
+        assert!(store.match_pending(&prices, now).await.unwrap().is_empty());
+        assert_eq!(store.book().for_user("alice").await.unwrap(), vec![pending]);
+    }
+
+    #[tokio::test]
+    async fn reads_legacy_four_column_files() {
+        use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray};
+        use arrow_schema::{DataType, Field, Schema};
+        use parquet::arrow::ArrowWriter;
+
+        let dir = tempdir().unwrap();
+        let user_dir = dir.path().join("alice");
+        std::fs::create_dir_all(&user_dir).unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("user", DataType::Utf8, false),
+            Field::new("symbol", DataType::Utf8, false),
+            Field::new("amount", DataType::Int64, false),
+            Field::new("price", DataType::Float64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["alice", "alice"])),
+                Arc::new(StringArray::from(vec!["AAPL", "AAPL"])),
+                Arc::new(Int64Array::from(vec![5, -2])),
+                Arc::new(Float64Array::from(vec![10.0, 11.0])),
+            ],
+        )
+        .unwrap();
+        let file = std::fs::File::create(user_dir.join("orders.parquet")).unwrap();
+        let mut writer = ArrowWriter::try_new(file, schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let orders = store.orders_for_user("alice").await.unwrap();
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[0].id, "legacy-alice-0");
+        assert_eq!(orders[0].side, Side::Buy);
+        assert_eq!(orders[1].side, Side::Sell);
+        assert_eq!(orders[1].amount, 2);
+        assert_eq!(orders[1].executed_at, DateTime::<Utc>::UNIX_EPOCH);
+        assert_eq!(orders[1].note, None);
+
+        // appending rewrites the file with the new schema
+        store.add_order(Order::new("alice", "MSFT", Side::Buy, 1, 1.0)).await.unwrap();
+        let reloaded = HoldingStore::new(dir.path().to_path_buf());
+        let orders = reloaded.orders_for_user("alice").await.unwrap();
+        assert_eq!(orders.len(), 3);
+        assert_eq!(orders[1].id, "legacy-alice-1");
     }
 }
@@ -0,0 +1,131 @@
+//! User and symbol identifiers, and their names on disk.
+//!
+//! Both end up as directory names under the data directory, so they are
+//! checked against a strict character set before use and encoded with
+//! [`encode_segment`] whenever they are joined onto a path.
+
+use thiserror::Error;
+
+const MAX_USER_LEN: usize = 64;
+const MAX_SYMBOL_LEN: usize = 20;
+
+/// Names of the directories the default layout keeps next to user directories.
+const RESERVED_USERS: &[&str] = &["market", "leagues"];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum IdError {
+    #[error("invalid user {0:?}: use 1 to 64 letters, digits, '_', '-' or '.', not starting with '.'")]
+    User(String),
+    #[error("user name {0:?} is reserved")]
+    ReservedUser(String),
+    #[error("invalid symbol {0:?}: use 1 to 20 letters, digits, '^', '.', '-', '=' or '_'")]
+    Symbol(String),
+}
+
+/// Accept user names like `alice`, `bob_2` or `j.doe`.
+pub fn validate_user(user: &str) -> Result<(), IdError> {
+    let valid = !user.is_empty()
+        && user.len() <= MAX_USER_LEN
+        && !user.starts_with('.')
+        && user.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(IdError::User(user.to_string()));
+    }
+    if RESERVED_USERS.contains(&user) {
+        return Err(IdError::ReservedUser(user.to_string()));
+    }
+    Ok(())
+}
+
+/// Accept ticker symbols like `AAPL`, `BRK.B`, `^GSPC`, `BTC-USD` or `EURUSD=X`.
+pub fn validate_symbol(symbol: &str) -> Result<(), IdError> {
+    let valid = !symbol.is_empty()
+        && symbol.len() <= MAX_SYMBOL_LEN
+        && symbol.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '^' | '.' | '-' | '=' | '_'));
+    if valid { Ok(()) } else { Err(IdError::Symbol(symbol.to_string())) }
+}
+
+/// Name of the directory holding `id`'s files.
+///
+/// Letters, digits, `_` and `-` are kept and every other byte becomes `%XX`,
+/// so `BRK.B` is stored as `BRK%2EB` and `^GSPC` as `%5EGSPC`. The result is
+/// always a single path component that is neither `.` nor `..`.
+pub fn encode_segment(id: &str) -> String {
+    let mut encoded = String::with_capacity(id.len());
+    for byte in id.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    if encoded.is_empty() {
+        // keep the empty id from resolving to the parent directory
+        encoded.push('%');
+    }
+    encoded
+}
+
+/// The id encoded as `name` by [`encode_segment`], if `name` is one.
+pub fn decode_segment(name: &str) -> Option<String> {
+    if name == "%" {
+        return Some(String::new());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else if byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-') {
+            bytes.push(byte);
+            rest = tail;
+        } else {
+            return None;
+        }
+    }
+    let id = String::from_utf8(bytes).ok()?;
+    (encode_segment(&id) == name).then_some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_users() {
+        for user in ["alice", "Bob_2", "j.doe", "a-b", &"x".repeat(64)] {
+            assert_eq!(validate_user(user), Ok(()), "{user}");
+        }
+        for user in ["", "../market/AAPL", "a/b", ".hidden", "..", "al ice", "é", "a\\b", &"x".repeat(65)] {
+            assert_eq!(validate_user(user), Err(IdError::User(user.to_string())), "{user}");
+        }
+        assert_eq!(validate_user("market"), Err(IdError::ReservedUser("market".into())));
+    }
+
+    #[test]
+    fn validates_symbols() {
+        for symbol in ["AAPL", "BRK.B", "^GSPC", "BTC-USD", "EURUSD=X", "0700.HK"] {
+            assert_eq!(validate_symbol(symbol), Ok(()), "{symbol}");
+        }
+        for symbol in ["", "../AAPL", "A/B", "AA PL", "AAPL%", &"A".repeat(21)] {
+            assert!(validate_symbol(symbol).is_err(), "{symbol}");
+        }
+    }
+
+    #[test]
+    fn encodes_unsafe_characters() {
+        assert_eq!(encode_segment("AAPL"), "AAPL");
+        assert_eq!(encode_segment("BRK.B"), "BRK%2EB");
+        assert_eq!(encode_segment("^GSPC"), "%5EGSPC");
+        assert_eq!(encode_segment("../market/AAPL"), "%2E%2E%2Fmarket%2FAAPL");
+        assert_eq!(encode_segment("é"), "%C3%A9");
+        for id in ["AAPL", "BRK.B", "^GSPC", "EURUSD=X", "j.doe", "../x", "é", "", "%"] {
+            assert_eq!(decode_segment(&encode_segment(id)).as_deref(), Some(id), "{id}");
+        }
+        for name in ["BRK.B", "%2", "%zz", "%2e", "a%41", "%FF"] {
+            assert_eq!(decode_segment(name), None, "{name}");
+        }
+    }
+}
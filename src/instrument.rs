@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::storage::files::blocking;
+
+/// Static description of a tradable symbol.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Instrument {
+    pub symbol: String,
+    pub name: String,
+    #[serde(default)]
+    pub exchange: Option<String>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(default)]
+    pub asset_type: Option<String>,
+}
+
+impl Instrument {
+    /// Instrument known only by its symbol.
+    pub fn bare(symbol: &str) -> Self {
+        Self { symbol: symbol.to_string(), name: symbol.to_string(), exchange: None, currency: None, asset_type: None }
+    }
+}
+
+/// Known instruments keyed by upper-case symbol.
+#[derive(Clone, Default)]
+pub struct InstrumentRegistry {
+    inner: Arc<RwLock<HashMap<String, Instrument>>>,
+}
+
+impl InstrumentRegistry {
+    pub fn new() -> Self {
+        Self { inner: Arc::new(RwLock::new(HashMap::new())) }
+    }
+
+    /// Add the instruments listed in a JSON seed file.
+    pub async fn load_seed(&self, path: &Path) -> anyhow::Result<usize> {
+        let file = path.to_path_buf();
+        let data = blocking(move || Ok(std::fs::read(file)?))
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let instruments: Vec<Instrument> =
+            serde_json::from_slice(&data).with_context(|| format!("invalid instrument seed {}", path.display()))?;
+        let count = instruments.len();
+        for instrument in instruments {
+            self.insert(instrument).await;
+        }
+        Ok(count)
+    }
+
+    pub async fn insert(&self, instrument: Instrument) {
+        let mut map = self.inner.write().await;
+        map.insert(instrument.symbol.to_uppercase(), instrument);
+    }
+
+    pub async fn get(&self, symbol: &str) -> Option<Instrument> {
+        let map = self.inner.read().await;
+        map.get(&symbol.to_uppercase()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn loads_seed_file_and_looks_up_case_insensitively() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("instruments.json");
+        std::fs::write(
+            &path,
+            r#"[{"symbol":"AAPL","name":"Apple Inc.","exchange":"NMS","currency":"USD","asset_type":"EQUITY"}]"#,
+        )
+        .unwrap();
+        let registry = InstrumentRegistry::new();
+        assert_eq!(registry.load_seed(&path).await.unwrap(), 1);
+        let apple = registry.get("aapl").await.unwrap();
+        assert_eq!(apple.name, "Apple Inc.");
+        assert_eq!(apple.currency.as_deref(), Some("USD"));
+        assert!(registry.get("APPL").await.is_none());
+    }
+}
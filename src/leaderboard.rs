@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::holdings::{HoldingStore, Order, Side, StoreError};
+use crate::league::League;
+use crate::market::MarketData;
+
+/// Metric used to order a league's leaderboard.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RankBy {
+    AbsoluteGain,
+    #[default]
+    PercentReturn,
+    /// Mean daily return divided by the standard deviation of daily returns.
+    RiskAdjusted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Standing {
+    pub rank: usize,
+    pub user: String,
+    pub equity: f64,
+    pub absolute_gain: f64,
+    pub percent_return: f64,
+    pub risk_adjusted_return: f64,
+}
+
+impl Standing {
+    fn metric(&self, rank_by: RankBy) -> f64 {
+        match rank_by {
+            RankBy::AbsoluteGain => self.absolute_gain,
+            RankBy::PercentReturn => self.percent_return,
+            RankBy::RiskAdjusted => self.risk_adjusted_return,
+        }
+    }
+}
+
+/// Close on or before `date`, if any.
+fn close_on(closes: &[(NaiveDate, f64)], date: NaiveDate) -> Option<f64> {
+    let idx = closes.partition_point(|(d, _)| *d <= date);
+    idx.checked_sub(1).map(|i| closes[i].1)
+}
+
+/// End-of-day equity of a member for every day of the season up to `until`.
+///
+/// Starts from the league bankroll and applies only orders executed during the
+/// season. Positions are valued at the latest stored close, or at the last
+/// traded price for days before the symbol has a close.
+pub fn equity_curve(
+    league: &League,
+    orders: &[Order],
+    closes: &HashMap<String, Vec<(NaiveDate, f64)>>,
+    until: NaiveDate,
+) -> Vec<f64> {
+    let mut season = league.season_orders(orders);
+    season.sort_by_key(|o| o.executed_at);
+    let last_day = until.min(league.end);
+
+    let mut cash = league.starting_bankroll;
+    let mut quantities: BTreeMap<&str, i64> = BTreeMap::new();
+    let mut last_trade: HashMap<&str, f64> = HashMap::new();
+    let mut pending = season.into_iter().peekable();
+    let mut curve = Vec::new();
+    let mut day = league.start;
+    while day <= last_day {
+        while let Some(order) = pending.next_if(|o| o.executed_at.date_naive() <= day) {
+            let value = order.price * order.amount as f64;
+            let qty = quantities.entry(order.symbol.as_str()).or_default();
+            match order.side {
+                Side::Buy => {
+                    cash -= value;
+                    *qty += order.amount;
+                }
+                Side::Sell => {
+                    cash += value;
+                    *qty -= order.amount;
+                }
+            }
+            last_trade.insert(order.symbol.as_str(), order.price);
+        }
+        let market_value: f64 = quantities
+            .iter()
+            .map(|(symbol, qty)| {
+                let price = closes
+                    .get(*symbol)
+                    .and_then(|c| close_on(c, day))
+                    .or_else(|| last_trade.get(symbol).copied())
+                    .unwrap_or(0.0);
+                price * *qty as f64
+            })
+            .sum();
+        curve.push(cash + market_value);
+        day = day.succ_opt().expect("date out of range");
+    }
+    if curve.is_empty() {
+        curve.push(league.starting_bankroll);
+    }
+    curve
+}
+
+fn risk_adjusted(curve: &[f64]) -> f64 {
+    let returns: Vec<f64> = curve
+        .windows(2)
+        .filter(|w| w[0] > 0.0)
+        .map(|w| w[1] / w[0] - 1.0)
+        .collect();
+    if returns.len() < 2 {
+        return 0.0;
+    }
+    let n = returns.len() as f64;
+    let mean = returns.iter().sum::<f64>() / n;
+    let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    let std_dev = variance.sqrt();
+    if std_dev == 0.0 { 0.0 } else { mean / std_dev }
+}
+
+/// Rank every member of `league`, breaking ties by user name.
+pub fn standings(
+    league: &League,
+    orders: &HashMap<String, Vec<Order>>,
+    closes: &HashMap<String, Vec<(NaiveDate, f64)>>,
+    today: NaiveDate,
+    rank_by: RankBy,
+) -> Vec<Standing> {
+    let mut standings: Vec<Standing> = league
+        .members
+        .iter()
+        .map(|user| {
+            let member_orders = orders.get(user).map(Vec::as_slice).unwrap_or_default();
+            let curve = equity_curve(league, member_orders, closes, today);
+            let equity = *curve.last().unwrap();
+            let absolute_gain = equity - league.starting_bankroll;
+            Standing {
+                rank: 0,
+                user: user.clone(),
+                equity,
+                absolute_gain,
+                percent_return: absolute_gain / league.starting_bankroll * 100.0,
+                risk_adjusted_return: risk_adjusted(&curve),
+            }
+        })
+        .collect();
+    standings.sort_by(|a, b| {
+        b.metric(rank_by)
+            .total_cmp(&a.metric(rank_by))
+            .then_with(|| a.user.cmp(&b.user))
+    });
+    for (i, standing) in standings.iter_mut().enumerate() {
+        standing.rank = i + 1;
+    }
+    standings
+}
+
+/// Load members' orders and stored closes and rank the league as of `today`.
+pub async fn leaderboard(
+    league: &League,
+    store: &HoldingStore,
+    market: &MarketData,
+    today: NaiveDate,
+    rank_by: RankBy,
+) -> anyhow::Result<Vec<Standing>> {
+    let mut orders = HashMap::new();
+    for user in &league.members {
+        match store.orders_for_user(user).await {
+            Ok(o) => {
+                orders.insert(user.clone(), o);
+            }
+            Err(StoreError::NoOrders(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let mut closes = HashMap::new();
+    for symbol in orders.values().flatten().map(|o| o.symbol.clone()) {
+        if closes.contains_key(&symbol) {
+            continue;
+        }
+        let mut series: Vec<(NaiveDate, f64)> =
+            market.bars(&symbol).await?.iter().map(|b| (b.date, b.close)).collect();
+        series.sort_by_key(|(d, _)| *d);
+        closes.insert(symbol, series);
+    }
+
+    Ok(standings(league, &orders, &closes, today, rank_by))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::league::TradingRules;
+    use chrono::{TimeZone, Utc};
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn league(members: &[&str]) -> League {
+        League {
+            id: "l".into(),
+            name: "test".into(),
+            starting_bankroll: 1_000.0,
+            symbols: Vec::new(),
+            start: date(1),
+            end: date(10),
+            rules: TradingRules::default(),
+            members: members.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    fn order(user: &str, side: Side, amount: i64, price: f64, day: u32) -> Order {
+        let mut o = Order::new(user, "AAPL", side, amount, price);
+        o.executed_at = Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap();
+        o
+    }
+
+    fn closes() -> HashMap<String, Vec<(NaiveDate, f64)>> {
+        HashMap::from([(
+            "AAPL".to_string(),
+            vec![(date(1), 10.0), (date(2), 12.0), (date(3), 11.0), (date(4), 15.0)],
+        )])
+    }
+
+    #[test]
+    fn equity_curve_values_positions_at_closes() {
+        let orders = vec![order("alice", Side::Buy, 10, 10.0, 1), order("alice", Side::Sell, 5, 11.0, 3)];
+        let curve = equity_curve(&league(&["alice"]), &orders, &closes(), date(5));
+        assert_eq!(curve, vec![1_000.0, 1_020.0, 1_010.0, 1_030.0, 1_030.0]);
+    }
+
+    #[test]
+    fn ignores_orders_outside_the_season() {
+        let mut early = order("alice", Side::Buy, 10, 10.0, 1);
+        early.executed_at = Utc.with_ymd_and_hms(2023, 12, 31, 12, 0, 0).unwrap();
+        let curve = equity_curve(&league(&["alice"]), &[early], &closes(), date(4));
+        assert!(curve.iter().all(|e| *e == 1_000.0));
+    }
+
+    #[test]
+    fn ranks_by_metric_and_breaks_ties_by_name() {
+        let league = league(&["carol", "bob", "alice", "dave"]);
+        let orders = HashMap::from([
+            ("alice".to_string(), vec![order("alice", Side::Buy, 10, 10.0, 1)]),
+            ("bob".to_string(), vec![order("bob", Side::Buy, 50, 10.0, 1)]),
+            ("carol".to_string(), vec![order("carol", Side::Buy, 10, 10.0, 1)]),
+        ]);
+        let ranked = standings(&league, &orders, &closes(), date(4), RankBy::AbsoluteGain);
+        let users: Vec<_> = ranked.iter().map(|s| s.user.as_str()).collect();
+        assert_eq!(users, vec!["bob", "alice", "carol", "dave"]);
+        assert_eq!(ranked[0].absolute_gain, 250.0);
+        assert_eq!(ranked[0].percent_return, 25.0);
+        assert_eq!(ranked[1].rank, 2);
+        assert_eq!(ranked[3].equity, 1_000.0);
+
+        let ranked = standings(&league, &orders, &closes(), date(4), RankBy::RiskAdjusted);
+        assert_eq!(ranked[0].user, "bob");
+        assert_eq!(ranked[3].risk_adjusted_return, 0.0);
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use arrow_array::Array;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::holdings::{Order, Side};
+use crate::storage::files::{blocking, write_atomically};
+use crate::util::{date_to_days, days_to_date};
+
+/// Longest season a league may run, in days. Leaderboards walk every day of
+/// the season, so this also bounds the work of each leaderboard request.
+pub const MAX_SEASON_DAYS: i64 = 731;
+
+#[derive(Debug, Error)]
+pub enum LeagueError {
+    #[error("no league with id {0}")]
+    NotFound(String),
+    #[error("invalid league: {0}")]
+    Invalid(String),
+    #[error("league {league} does not allow this order: {reason}")]
+    RuleViolation { league: String, reason: String },
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Limits applied to orders placed by league members during the season.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TradingRules {
+    #[serde(default)]
+    pub max_order_value: Option<f64>,
+    #[serde(default)]
+    pub max_trades_per_day: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct League {
+    pub id: String,
+    pub name: String,
+    pub starting_bankroll: f64,
+    /// Symbols members may trade; empty means any symbol.
+    pub symbols: Vec<String>,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub rules: TradingRules,
+    pub members: Vec<String>,
+}
+
+impl League {
+    pub fn is_active(&self, date: NaiveDate) -> bool {
+        self.start <= date && date <= self.end
+    }
+
+    /// Orders from `orders` that were executed during the season.
+    pub fn season_orders<'a>(&self, orders: &'a [Order]) -> Vec<&'a Order> {
+        orders
+            .iter()
+            .filter(|o| self.is_active(o.executed_at.date_naive()))
+            .collect()
+    }
+
+    /// Check `order` against the league's rules given the member's existing `orders`.
+    fn check_order(&self, order: &Order, orders: &[Order]) -> Result<(), String> {
+        if !self.symbols.is_empty() && !self.symbols.contains(&order.symbol) {
+            return Err(format!("{} is not in the league's symbol universe", order.symbol));
+        }
+        let value = order.price * order.amount as f64;
+        if let Some(max) = self.rules.max_order_value
+            && value > max
+        {
+            return Err(format!("order value {value:.2} exceeds the limit of {max:.2}"));
+        }
+        if let Some(max) = self.rules.max_trades_per_day {
+            let day = order.executed_at.date_naive();
+            let trades = orders.iter().filter(|o| o.executed_at.date_naive() == day).count();
+            if trades >= max as usize {
+                return Err(format!("daily limit of {max} trades reached"));
+            }
+        }
+        if order.side == Side::Buy {
+            let cash = self.season_orders(orders).iter().fold(self.starting_bankroll, |cash, o| match o.side {
+                Side::Buy => cash - o.price * o.amount as f64,
+                Side::Sell => cash + o.price * o.amount as f64,
+            });
+            if value > cash {
+                return Err(format!("order costs {value:.2} but only {cash:.2} of the bankroll is left"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateLeagueRequest {
+    pub name: String,
+    pub starting_bankroll: f64,
+    #[serde(default)]
+    pub symbols: Vec<String>,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    #[serde(default)]
+    pub rules: TradingRules,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JoinLeagueRequest {
+    pub user: String,
+}
+
+fn league_schema() -> arrow_schema::Schema {
+    use arrow_schema::{DataType, Field, Schema};
+    Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("starting_bankroll", DataType::Float64, false),
+        Field::new("symbols", DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))), false),
+        Field::new("start", DataType::Date32, false),
+        Field::new("end", DataType::Date32, false),
+        Field::new("max_order_value", DataType::Float64, true),
+        Field::new("max_trades_per_day", DataType::UInt32, true),
+    ])
+}
+
+fn member_schema() -> arrow_schema::Schema {
+    use arrow_schema::{DataType, Field, Schema};
+    Schema::new(vec![
+        Field::new("league_id", DataType::Utf8, false),
+        Field::new("user", DataType::Utf8, false),
+    ])
+}
+
+fn leagues_to_record_batch(leagues: &[&League]) -> anyhow::Result<arrow_array::RecordBatch> {
+    use arrow_array::builder::{ListBuilder, StringBuilder};
+    use arrow_array::{Date32Array, Float64Array, RecordBatch, StringArray, UInt32Array};
+
+    let mut symbols = ListBuilder::new(StringBuilder::new());
+    for league in leagues {
+        for symbol in &league.symbols {
+            symbols.values().append_value(symbol);
+        }
+        symbols.append(true);
+    }
+
+    Ok(RecordBatch::try_new(
+        Arc::new(league_schema()),
+        vec![
+            Arc::new(StringArray::from_iter_values(leagues.iter().map(|l| l.id.as_str()))),
+            Arc::new(StringArray::from_iter_values(leagues.iter().map(|l| l.name.as_str()))),
+            Arc::new(Float64Array::from_iter_values(leagues.iter().map(|l| l.starting_bankroll))),
+            Arc::new(symbols.finish()),
+            Arc::new(Date32Array::from_iter_values(leagues.iter().map(|l| date_to_days(l.start)))),
+            Arc::new(Date32Array::from_iter_values(leagues.iter().map(|l| date_to_days(l.end)))),
+            Arc::new(leagues.iter().map(|l| l.rules.max_order_value).collect::<Float64Array>()),
+            Arc::new(leagues.iter().map(|l| l.rules.max_trades_per_day).collect::<UInt32Array>()),
+        ],
+    )?)
+}
+
+/// Column `name` of `batch` as an array of type `T`.
+fn column<'a, T: 'static>(batch: &'a arrow_array::RecordBatch, name: &str) -> anyhow::Result<&'a T> {
+    batch
+        .column_by_name(name)
+        .with_context(|| format!("missing column {name}"))?
+        .as_any()
+        .downcast_ref()
+        .with_context(|| format!("column {name} has an unexpected type"))
+}
+
+fn batch_to_leagues(batch: &arrow_array::RecordBatch) -> anyhow::Result<Vec<League>> {
+    use arrow_array::{Date32Array, Float64Array, ListArray, StringArray, UInt32Array};
+
+    let id_array: &StringArray = column(batch, "id")?;
+    let name_array: &StringArray = column(batch, "name")?;
+    let bankroll_array: &Float64Array = column(batch, "starting_bankroll")?;
+    let symbols_array: &ListArray = column(batch, "symbols")?;
+    let start_array: &Date32Array = column(batch, "start")?;
+    let end_array: &Date32Array = column(batch, "end")?;
+    let max_value_array: &Float64Array = column(batch, "max_order_value")?;
+    let max_trades_array: &UInt32Array = column(batch, "max_trades_per_day")?;
+
+    (0..batch.num_rows())
+        .map(|i| {
+            let symbols = symbols_array.value(i);
+            let symbols: &StringArray = symbols.as_any().downcast_ref().context("league symbols are not strings")?;
+            Ok(League {
+                id: id_array.value(i).to_string(),
+                name: name_array.value(i).to_string(),
+                starting_bankroll: bankroll_array.value(i),
+                symbols: symbols.iter().flatten().map(str::to_string).collect(),
+                start: days_to_date(start_array.value(i))?,
+                end: days_to_date(end_array.value(i))?,
+                rules: TradingRules {
+                    max_order_value: max_value_array.is_valid(i).then(|| max_value_array.value(i)),
+                    max_trades_per_day: max_trades_array.is_valid(i).then(|| max_trades_array.value(i)),
+                },
+                members: Vec::new(),
+            })
+        })
+        .collect()
+}
+
+/// Leagues and their members, persisted under `data_dir` as
+/// `leagues.parquet` and `members.parquet`.
+///
+/// Changes are written to both files, atomically and on the blocking thread
+/// pool, before they are made visible in memory. `fs_lock` is held from
+/// reading the current leagues until the change is in memory, so changes
+/// never overwrite each other.
+#[derive(Clone)]
+pub struct LeagueStore {
+    data_dir: PathBuf,
+    inner: Arc<RwLock<HashMap<String, League>>>,
+    fs_lock: Arc<Mutex<()>>,
+}
+
+impl LeagueStore {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self {
+            data_dir,
+            inner: Arc::new(RwLock::new(HashMap::new())),
+            fs_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Read persisted leagues into memory.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let _lock = self.fs_lock.lock().await;
+        let data_dir = self.data_dir.clone();
+        let leagues = blocking(move || read_league_files(&data_dir)).await.context("failed to load leagues")?;
+        let mut map = self.inner.write().await;
+        *map = leagues.into_iter().map(|l| (l.id.clone(), l)).collect();
+        Ok(())
+    }
+
+    pub async fn create(&self, req: CreateLeagueRequest) -> Result<League, LeagueError> {
+        if req.name.trim().is_empty() {
+            return Err(LeagueError::Invalid("name must not be empty".into()));
+        }
+        if !req.starting_bankroll.is_finite() || req.starting_bankroll <= 0.0 {
+            return Err(LeagueError::Invalid("starting bankroll must be positive".into()));
+        }
+        if req.end < req.start {
+            return Err(LeagueError::Invalid("season must end after it starts".into()));
+        }
+        if (req.end - req.start).num_days() >= MAX_SEASON_DAYS {
+            return Err(LeagueError::Invalid(format!("season must be shorter than {MAX_SEASON_DAYS} days")));
+        }
+        let league = League {
+            id: Uuid::new_v4().to_string(),
+            name: req.name,
+            starting_bankroll: req.starting_bankroll,
+            symbols: req.symbols,
+            start: req.start,
+            end: req.end,
+            rules: req.rules,
+            members: Vec::new(),
+        };
+        let _lock = self.fs_lock.lock().await;
+        let mut leagues = self.all().await;
+        leagues.push(league.clone());
+        self.write_files(leagues).await.context("failed to persist league")?;
+        self.inner.write().await.insert(league.id.clone(), league.clone());
+        Ok(league)
+    }
+
+    /// Add `user` to the league as of `today`. Joining twice is a no-op.
+    pub async fn join(&self, id: &str, user: &str, today: NaiveDate) -> Result<League, LeagueError> {
+        let _lock = self.fs_lock.lock().await;
+        let mut league = self.get(id).await?;
+        if league.end < today {
+            return Err(LeagueError::Invalid(format!("season of league {id} has ended")));
+        }
+        if league.members.iter().any(|m| m == user) {
+            return Ok(league);
+        }
+        league.members.push(user.to_string());
+        let mut leagues = self.all().await;
+        leagues.retain(|l| l.id != league.id);
+        leagues.push(league.clone());
+        self.write_files(leagues).await.context("failed to persist league membership")?;
+        self.inner.write().await.insert(league.id.clone(), league.clone());
+        Ok(league)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<League, LeagueError> {
+        let map = self.inner.read().await;
+        map.get(id).cloned().ok_or_else(|| LeagueError::NotFound(id.to_string()))
+    }
+
+    pub async fn all(&self) -> Vec<League> {
+        let map = self.inner.read().await;
+        let mut leagues: Vec<_> = map.values().cloned().collect();
+        leagues.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.id.cmp(&b.id)));
+        leagues
+    }
+
+    /// Check `order` against every league `order.user` plays in whose season
+    /// covers the order's execution date. `orders` are the user's existing orders.
+    pub async fn check_order(&self, order: &Order, orders: &[Order]) -> Result<(), LeagueError> {
+        let map = self.inner.read().await;
+        let date = order.executed_at.date_naive();
+        for league in map.values() {
+            if !league.is_active(date) || !league.members.contains(&order.user) {
+                continue;
+            }
+            league
+                .check_order(order, orders)
+                .map_err(|reason| LeagueError::RuleViolation { league: league.name.clone(), reason })?;
+        }
+        Ok(())
+    }
+
+    /// Write `leagues` as all of the leagues. Callers hold `fs_lock`.
+    async fn write_files(&self, mut leagues: Vec<League>) -> anyhow::Result<()> {
+        let data_dir = self.data_dir.clone();
+        blocking(move || {
+            leagues.sort_by(|a, b| a.id.cmp(&b.id));
+            write_league_files(&data_dir, &leagues)
+        })
+        .await
+    }
+}
+
+fn write_league_files(data_dir: &Path, leagues: &[League]) -> anyhow::Result<()> {
+    use arrow_array::{RecordBatch, StringArray};
+
+    let refs: Vec<&League> = leagues.iter().collect();
+    let batch = leagues_to_record_batch(&refs)?;
+    let members: Vec<(&str, &str)> = leagues
+        .iter()
+        .flat_map(|l| l.members.iter().map(move |m| (l.id.as_str(), m.as_str())))
+        .collect();
+    let member_batch = RecordBatch::try_new(
+        Arc::new(member_schema()),
+        vec![
+            Arc::new(StringArray::from_iter_values(members.iter().map(|(id, _)| *id))),
+            Arc::new(StringArray::from_iter_values(members.iter().map(|(_, user)| *user))),
+        ],
+    )?;
+    write_atomically(&data_dir.join("leagues.parquet"), &batch)?;
+    write_atomically(&data_dir.join("members.parquet"), &member_batch)
+}
+
+fn read_league_files(data_dir: &Path) -> anyhow::Result<Vec<League>> {
+    use arrow_array::StringArray;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::File;
+
+    let league_path = data_dir.join("leagues.parquet");
+    if !league_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(league_path)?)?.build()?;
+    let mut leagues = Vec::new();
+    for batch in reader {
+        leagues.extend(batch_to_leagues(&batch?)?);
+    }
+
+    let member_path = data_dir.join("members.parquet");
+    if member_path.exists() {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(member_path)?)?.build()?;
+        for batch in reader {
+            let batch = batch?;
+            let ids: &StringArray = column(&batch, "league_id")?;
+            let users: &StringArray = column(&batch, "user")?;
+            for i in 0..batch.num_rows() {
+                if let Some(league) = leagues.iter_mut().find(|l| l.id == ids.value(i)) {
+                    league.members.push(users.value(i).to_string());
+                }
+            }
+        }
+    }
+    Ok(leagues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+    use tempfile::tempdir;
+
+    fn today() -> NaiveDate {
+        Utc::now().date_naive()
+    }
+
+    fn request() -> CreateLeagueRequest {
+        let today = today();
+        CreateLeagueRequest {
+            name: "office".into(),
+            starting_bankroll: 1_000.0,
+            symbols: vec!["AAPL".into(), "MSFT".into()],
+            start: today - Duration::days(1),
+            end: today + Duration::days(30),
+            rules: TradingRules { max_order_value: Some(500.0), max_trades_per_day: Some(2) },
+        }
+    }
+
+    #[tokio::test]
+    async fn create_join_and_reload() {
+        let dir = tempdir().unwrap();
+        let store = LeagueStore::new(dir.path().to_path_buf());
+        let league = store.create(request()).await.unwrap();
+        store.join(&league.id, "alice", today()).await.unwrap();
+        store.join(&league.id, "bob", today()).await.unwrap();
+        let joined = store.join(&league.id, "alice", today()).await.unwrap();
+        assert_eq!(joined.members, vec!["alice", "bob"]);
+
+        let reloaded = LeagueStore::new(dir.path().to_path_buf());
+        reloaded.load().await.unwrap();
+        assert_eq!(reloaded.get(&league.id).await.unwrap(), joined);
+        assert!(matches!(reloaded.get("missing").await, Err(LeagueError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn failed_writes_leave_leagues_unchanged() {
+        let dir = tempdir().unwrap();
+        let league_dir = dir.path().join("leagues");
+        let store = LeagueStore::new(league_dir.clone());
+        let league = store.create(request()).await.unwrap();
+
+        // a file in place of the directory makes every write fail
+        std::fs::remove_dir_all(&league_dir).unwrap();
+        std::fs::write(&league_dir, "").unwrap();
+        assert!(matches!(store.join(&league.id, "alice", today()).await, Err(LeagueError::Other(_))));
+        assert!(store.get(&league.id).await.unwrap().members.is_empty());
+        assert!(matches!(store.create(request()).await, Err(LeagueError::Other(_))));
+        assert_eq!(store.all().await, vec![league]);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_seasons() {
+        let dir = tempdir().unwrap();
+        let store = LeagueStore::new(dir.path().to_path_buf());
+        let mut req = request();
+        req.end = req.start - Duration::days(1);
+        assert!(matches!(store.create(req).await, Err(LeagueError::Invalid(_))));
+
+        let mut req = request();
+        req.start = NaiveDate::from_ymd_opt(1900, 1, 1).unwrap();
+        assert!(matches!(store.create(req).await, Err(LeagueError::Invalid(_))));
+        let mut req = request();
+        req.end = req.start + Duration::days(MAX_SEASON_DAYS - 1);
+        assert!(store.create(req).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn enforces_rules_for_members() {
+        let dir = tempdir().unwrap();
+        let store = LeagueStore::new(dir.path().to_path_buf());
+        let league = store.create(request()).await.unwrap();
+        store.join(&league.id, "alice", today()).await.unwrap();
+
+        let ok = Order::new("alice", "AAPL", Side::Buy, 4, 100.0);
+        store.check_order(&ok, &[]).await.unwrap();
+
+        let outside = Order::new("alice", "TSLA", Side::Buy, 1, 100.0);
+        assert!(matches!(store.check_order(&outside, &[]).await, Err(LeagueError::RuleViolation { .. })));
+        // non-members are unaffected
+        let other = Order::new("bob", "TSLA", Side::Buy, 1, 100.0);
+        store.check_order(&other, &[]).await.unwrap();
+
+        let too_big = Order::new("alice", "AAPL", Side::Buy, 6, 100.0);
+        assert!(store.check_order(&too_big, &[]).await.is_err());
+
+        let history = vec![ok.clone(), Order::new("alice", "MSFT", Side::Buy, 4, 100.0)];
+        assert!(store.check_order(&ok, &history).await.is_err());
+
+        // bankroll: 800 of 1000 already spent
+        let mut rules = request();
+        rules.rules = TradingRules::default();
+        let open = store.create(rules).await.unwrap();
+        store.join(&open.id, "carol", today()).await.unwrap();
+        let spent = vec![Order::new("carol", "AAPL", Side::Buy, 8, 100.0)];
+        let buy = Order::new("carol", "AAPL", Side::Buy, 3, 100.0);
+        assert!(store.check_order(&buy, &spent).await.is_err());
+    }
+}
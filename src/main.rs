@@ -4,19 +4,38 @@ mod market;
 mod state;
 mod portfolio;
 mod activity;
+mod account;
+mod league;
+mod leaderboard;
+mod orderbook;
+mod instrument;
+mod provider;
+mod config;
+mod replay;
+#[allow(dead_code)]
 mod strava;
+mod storage;
+mod ident;
+mod util;
+#[cfg(test)]
+mod testing;
 
-use axum::{routing::{get, post}, Router, response::IntoResponse, extract::{Path, State}, Json};
+use axum::{routing::{delete, get, post}, Router, response::IntoResponse, extract::{Path, Query, State}, http::HeaderMap, Json};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use tokio::net::TcpListener;
-use std::path::PathBuf;
 use std::sync::Arc;
-use std::collections::HashMap;
-use holdings::{HoldingStore, OrderRequest};
-use market::{MarketData, YahooFetcher};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use holdings::{HoldingStore, OrderRequest, BackfillOrderRequest};
+use market::{MarketData, MarketEvent, RateLimiter, SymbolStatus};
 use error::AppError;
 use state::AppState;
 use portfolio::HoldingsService;
 use activity::{ActivityStore, Activity};
+use account::Account;
+use league::{LeagueStore, CreateLeagueRequest, JoinLeagueRequest};
+use orderbook::OrderType;
+use replay::{ReplaySession, StartReplayRequest};
 use tracing::info;
 
 
@@ -24,16 +43,132 @@ async fn hello() -> impl IntoResponse {
     "Hello, world!"
 }
 
+/// Resolve `symbol` to its canonical form, rejecting malformed and unknown
+/// symbols.
+async fn validate_symbol(state: &AppState, symbol: &str) -> Result<String, AppError> {
+    ident::validate_symbol(symbol)?;
+    match state.market.resolve_instrument(symbol).await {
+        Ok(Some(instrument)) => Ok(instrument.symbol),
+        Ok(None) => Err(AppError::unprocessable(format!("unknown symbol {symbol}"))),
+        Err(e) => Err(AppError::new(
+            axum::http::StatusCode::BAD_GATEWAY,
+            format!("failed to look up symbol {symbol}: {e}"),
+        )),
+    }
+}
+
 async fn add_transaction(
     State(state): State<AppState>,
-    Json(req): Json<OrderRequest>,
+    Json(mut req): Json<OrderRequest>,
 ) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&req.user)?;
+    if state.market.replay().await.is_some() {
+        return Err(replay::ReplayError::Trading.into());
+    }
+    let now = state.market.now().await;
+    req.symbol = validate_symbol(&state, &req.symbol).await?;
+
+    if req.order_type != OrderType::Market {
+        let existing = match state.store.orders_for_user(&req.user).await {
+            Ok(orders) => orders,
+            Err(holdings::StoreError::NoOrders(_)) => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let pending = req.into_pending(now)?;
+        let mut provisional = holdings::Order::new(
+            pending.user.clone(),
+            pending.symbol.clone(),
+            pending.side,
+            pending.amount,
+            pending.reference_price(),
+        );
+        provisional.executed_at = pending.created_at;
+        state.leagues.check_order(&provisional, &existing).await?;
+        state
+            .store
+            .book()
+            .place(pending.clone())
+            .await
+            .map_err(|e| AppError::internal(e.to_string()))?;
+        return Ok((axum::http::StatusCode::ACCEPTED, Json(pending)).into_response());
+    }
+
+    let price = state.market.market_price(&req.symbol, now).await?;
+    let order = req.fill(price, now);
+    let leagues = state.leagues.clone();
     state
         .store
-        .add_order(req.into())
+        .add_order_checked(order.clone(), |order, existing| async move {
+            leagues.check_order(&order, &existing).await.map_err(AppError::from)
+        })
+        .await?;
+    Ok((axum::http::StatusCode::CREATED, Json(order)).into_response())
+}
+
+async fn list_pending_for_user(
+    Path(user): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&user)?;
+    let pending = state
+        .store
+        .book()
+        .for_user(&user)
         .await
-        .map(|_| axum::http::StatusCode::CREATED)
-        .map_err(AppError::from)
+        .map_err(|e| AppError::internal(e.to_string()))?;
+    Ok(Json(pending))
+}
+
+async fn cancel_order(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    match state.store.book().cancel(&id).await {
+        Ok(Some(order)) => Ok(Json(order)),
+        Ok(None) => Err(AppError::not_found(format!("no pending order with id {id}"))),
+        Err(e) => Err(AppError::internal(e.to_string())),
+    }
+}
+
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Err(AppError::new(axum::http::StatusCode::FORBIDDEN, "admin endpoints are disabled"));
+    };
+    match headers.get("x-admin-token").and_then(|v| v.to_str().ok()) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(AppError::new(axum::http::StatusCode::UNAUTHORIZED, "invalid admin token")),
+    }
+}
+
+/// Compare `a` and `b` in time that depends only on their lengths, so the
+/// admin token cannot be guessed one byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && std::hint::black_box(a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y))) == 0
+}
+
+async fn backfill_transaction(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(mut req): Json<BackfillOrderRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    ident::validate_user(&req.user)?;
+    req.symbol = validate_symbol(&state, &req.symbol).await?;
+    let order: holdings::Order = req.into();
+    state.store.add_order(order.clone()).await?;
+    Ok((axum::http::StatusCode::CREATED, Json(order)))
+}
+
+async fn backfill_market(
+    Path(symbol): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    let symbol = validate_symbol(&state, &symbol).await?;
+    let today = chrono::Utc::now().date_naive();
+    let report = state.market.backfill(&symbol, today).await?;
+    Ok(Json(report))
 }
 
 async fn list_orders(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
@@ -45,6 +180,7 @@ async fn list_orders_for_user(
     Path(user): Path<String>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&user)?;
     let orders = state.store.orders_for_user(&user).await?;
     Ok(Json(orders).into_response())
 }
@@ -57,9 +193,81 @@ async fn list_holdings(State(state): State<AppState>) -> impl IntoResponse {
 async fn list_holdings_for_user(
     Path(user): Path<String>,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&user)?;
     let holdings = state.holdings.for_user(&user).await;
-    Json(holdings)
+    Ok(Json(holdings))
+}
+
+async fn holdings_history(
+    Path(user): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&user)?;
+    Ok(Json(state.holdings.history(&user).await))
+}
+
+async fn get_account(
+    Path(user): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&user)?;
+    let cash = state.store.cash_for_user(&user).await?;
+    let orders = match state.store.orders_for_user(&user).await {
+        Ok(orders) => orders,
+        Err(holdings::StoreError::NoOrders(_)) => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let prices = state.market.prices().await;
+    Ok(Json(Account::new(&user, cash, &orders, &prices)))
+}
+
+async fn create_league(
+    State(state): State<AppState>,
+    Json(req): Json<CreateLeagueRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let league = state.leagues.create(req).await?;
+    Ok((axum::http::StatusCode::CREATED, Json(league)))
+}
+
+async fn list_leagues(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.leagues.all().await)
+}
+
+async fn get_league(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(state.leagues.get(&id).await?))
+}
+
+async fn join_league(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(req): Json<JoinLeagueRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_user(&req.user)?;
+    let today = state.market.now().await.date_naive();
+    Ok(Json(state.leagues.join(&id, &req.user, today).await?))
+}
+
+#[derive(serde::Deserialize)]
+struct LeaderboardQuery {
+    #[serde(default)]
+    rank_by: leaderboard::RankBy,
+}
+
+async fn league_leaderboard(
+    Path(id): Path<String>,
+    Query(query): Query<LeaderboardQuery>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let league = state.leagues.get(&id).await?;
+    let today = state.market.now().await.date_naive();
+    let standings = leaderboard::leaderboard(&league, &state.store, &state.market, today, query.rank_by)
+        .await
+        .map_err(|e| AppError::internal(e.to_string()))?;
+    Ok(Json(standings))
 }
 
 async fn market_prices(State(state): State<AppState>) -> Json<HashMap<String, f64>> {
@@ -67,6 +275,168 @@ async fn market_prices(State(state): State<AppState>) -> Json<HashMap<String, f6
     Json(prices)
 }
 
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+    #[serde(default)]
+    interval: market::Interval,
+}
+
+async fn market_history(
+    Path(symbol): Path<String>,
+    Query(query): Query<HistoryQuery>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_symbol(&symbol)?;
+    if let (Some(from), Some(to)) = (query.from, query.to)
+        && from > to
+    {
+        return Err(AppError::bad_request(format!("from {from} is after to {to}")));
+    }
+    match state.market.history(&symbol, query.from, query.to, query.interval).await {
+        Ok(Some(bars)) => Ok(Json(bars)),
+        Ok(None) => Err(AppError::not_found(format!("no price history for {symbol}"))),
+        Err(e) => Err(AppError::internal(e.to_string())),
+    }
+}
+
+async fn get_replay(State(state): State<AppState>) -> Json<Option<ReplaySession>> {
+    Json(state.market.replay().await)
+}
+
+async fn start_replay(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<StartReplayRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    let session = state.market.start_replay(req, &state.store, &state.holdings).await?;
+    Ok(Json(session))
+}
+
+async fn pause_replay(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.market.pause_replay().await?))
+}
+
+async fn resume_replay(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.market.resume_replay().await?))
+}
+
+async fn step_replay(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(state.market.step_replay(&state.store, &state.holdings).await?))
+}
+
+async fn reset_replay(State(state): State<AppState>, headers: HeaderMap) -> Result<impl IntoResponse, AppError> {
+    require_admin(&state, &headers)?;
+    state.market.reset_replay().await?;
+    Ok(axum::http::StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Deserialize)]
+struct StreamQuery {
+    /// Also stream this user's holding valuations.
+    user: Option<String>,
+}
+
+async fn market_events(
+    Query(query): Query<StreamQuery>,
+    State(state): State<AppState>,
+) -> Sse<impl futures::Stream<Item = Result<Event, std::convert::Infallible>>> {
+    use futures::StreamExt;
+    use futures::stream::PollNext;
+    use tokio_stream::wrappers::BroadcastStream;
+    use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+
+    fn received(event: Result<MarketEvent, BroadcastStreamRecvError>) -> std::future::Ready<Option<MarketEvent>> {
+        if let Err(e) = &event {
+            tracing::warn!("event stream client fell behind: {e}");
+        }
+        std::future::ready(event.ok())
+    }
+
+    let prices = BroadcastStream::new(state.market.subscribe()).filter_map(received);
+    let holdings = match &query.user {
+        Some(user) => BroadcastStream::new(state.market.subscribe_holdings(user)).filter_map(received).left_stream(),
+        None => futures::stream::empty().right_stream(),
+    };
+    // prices go first so each update's prices arrive before its holdings
+    let updates = futures::stream::select_with_strategy(prices, holdings, |_: &mut ()| PollNext::Left);
+    let snapshot = state.market.price_snapshot().await;
+    let stream = futures::stream::once(std::future::ready(snapshot))
+        .chain(updates)
+        .map(|event| Ok(Event::default().event(event.kind()).json_data(&event).expect("events serialize")));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn market_stream(
+    ws: WebSocketUpgrade,
+    Query(query): Query<StreamQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| stream_events(socket, state.market, query.user))
+}
+
+async fn stream_events(mut socket: WebSocket, market: Arc<MarketData>, user: Option<String>) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    async fn send(socket: &mut WebSocket, event: &MarketEvent) -> Result<(), axum::Error> {
+        let text = serde_json::to_string(event).expect("events serialize");
+        socket.send(Message::Text(text)).await
+    }
+
+    let mut prices = market.subscribe();
+    let mut holdings = user.map(|user| market.subscribe_holdings(&user));
+    if send(&mut socket, &market.price_snapshot().await).await.is_err() {
+        return;
+    }
+    loop {
+        // prices go first so each update's prices arrive before its holdings
+        let event = tokio::select! {
+            biased;
+            event = prices.recv() => event,
+            event = async {
+                match &mut holdings {
+                    Some(holdings) => holdings.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => event,
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        match event {
+            Ok(event) => {
+                if send(&mut socket, &event).await.is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(n)) => tracing::warn!("websocket client missed {n} events"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn market_status(State(state): State<AppState>) -> Json<BTreeMap<String, SymbolStatus>> {
+    Json(state.market.status().await)
+}
+
+async fn get_instrument(
+    Path(symbol): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    ident::validate_symbol(&symbol)?;
+    match state.market.resolve_instrument(&symbol).await {
+        Ok(Some(instrument)) => Ok(Json(instrument)),
+        Ok(None) => Err(AppError::not_found(format!("unknown symbol {symbol}"))),
+        Err(e) => Err(AppError::new(axum::http::StatusCode::BAD_GATEWAY, e.to_string())),
+    }
+}
+
 async fn market_symbols(State(state): State<AppState>) -> Json<Vec<String>> {
     let mut symbols = state.market.symbols().await;
     symbols.sort();
@@ -77,7 +447,8 @@ async fn get_activity(
     Path(id): Path<String>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    match state.activities.get(&id).await {
+    let activity = state.activities.get(&id).await.map_err(|e| AppError::internal(e.to_string()))?;
+    match activity {
         Some(act) => Ok(Json(act)),
         None => Err(AppError::not_found(format!("no activity with id {id}"))),
     }
@@ -89,11 +460,58 @@ async fn main() {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let store = HoldingStore::new(PathBuf::from("data"));
-    let fetcher = Arc::new(YahooFetcher::new().expect("failed to create fetcher"));
-    let market = Arc::new(MarketData::new(fetcher, PathBuf::from("data/market")));
-    let holdings = HoldingsService::new();
-    let activities = ActivityStore::new();
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let moved = storage::migrate_legacy_dirs(&config.data).expect("failed to migrate data directories");
+    if moved > 0 {
+        info!("moved {moved} directories to their encoded names");
+    }
+    let storage = storage::build(&config.data).expect("failed to open storage");
+    let leagues = LeagueStore::new(config.data.leagues_dir());
+    leagues.load().await.expect("failed to load leagues");
+    let store = HoldingStore::new(config.data.dir.clone())
+        .with_storage(storage.orders.clone())
+        .with_starting_cash(config.trading.starting_cash)
+        .with_leagues(leagues.clone());
+    let fetcher = provider::build(&config.providers).expect("failed to create quote provider");
+    let market_config = &config.market;
+    let retry = market::RetryPolicy { attempts: market_config.retry_attempts, ..Default::default() };
+    let mut market = MarketData::new(fetcher, config.data.market_dir())
+        .with_storage(storage.prices.clone())
+        .with_max_quote_age(chrono::Duration::hours(market_config.max_quote_age_hours))
+        .with_retry_policy(retry)
+        .with_concurrency(market_config.concurrency)
+        .with_fetch_timeout(std::time::Duration::from_secs(market_config.fetch_timeout_secs))
+        .with_backfill_years(market_config.backfill_years)
+        .with_unknown_symbol_ttl(std::time::Duration::from_secs(market_config.unknown_symbol_ttl_secs))
+        .with_update_interval(std::time::Duration::from_secs(market_config.update_interval_secs));
+    if let Some(per_second) = market_config.rate_limit {
+        market = market.with_rate_limit(RateLimiter::new(per_second).expect("invalid quote rate limit"));
+    }
+    let market = Arc::new(market);
+    let seed = config.data.instruments();
+    if seed.exists() {
+        let count = market.instruments().load_seed(&seed).await.expect("failed to load instrument seed");
+        info!("loaded {count} instruments from {}", seed.display());
+    }
+    let holdings = HoldingsService::new()
+        .with_lot_method(config.trading.lot_method)
+        .with_data_dir(config.data.dir.clone());
+    let snapshots = holdings.load().await.expect("failed to load holdings");
+    let users = store.load().await.expect("failed to load orders");
+    let orders = store.all_orders().await;
+    let now = market.now().await;
+    let symbols: HashSet<_> = orders.iter().map(|o| o.symbol.clone()).collect();
+    let closes = market.stored_closes(symbols, now.date_naive()).await.expect("failed to read stored prices");
+    holdings.rebuild(&orders, &closes, now).await.expect("failed to rebuild holdings");
+    info!("loaded {} orders for {users} users and {snapshots} holdings snapshots", orders.len());
+    let activities = ActivityStore::new().with_storage(storage.activities.clone());
 
     // seed sample activity for demo purposes
     activities
@@ -104,25 +522,55 @@ async fn main() {
             power: vec![150, 200],
             gps: vec![activity::GpsPoint { lat: 0.0, lon: 0.0 }],
         })
-        .await;
+        .await
+        .expect("failed to store demo activity");
 
-    let state = AppState { store: store.clone(), market: market.clone(), holdings: holdings.clone(), activities: activities.clone() };
+    let state = AppState {
+        store: store.clone(),
+        market: market.clone(),
+        holdings: holdings.clone(),
+        activities: activities.clone(),
+        leagues: leagues.clone(),
+        admin_token: config.admin_token.clone(),
+    };
 
     tokio::spawn(market.clone().run(store.clone(), holdings.clone()));
 
     let app = Router::new()
         .route("/", get(hello))
         .route("/holdings/transaction", post(add_transaction))
+        .route("/admin/holdings/transaction", post(backfill_transaction))
         .route("/holdings/orders", get(list_orders))
+        // GET takes a user, DELETE the id of a pending order
         .route("/holdings/orders/:user", get(list_orders_for_user))
+        .route("/orders/pending/:id", delete(cancel_order))
+        .route("/holdings/pending/:user", get(list_pending_for_user))
         .route("/holdings", get(list_holdings))
         .route("/holdings/:user", get(list_holdings_for_user))
+        .route("/holdings/:user/history", get(holdings_history))
+        .route("/accounts/:user", get(get_account))
+        .route("/leagues", get(list_leagues).post(create_league))
+        .route("/leagues/:id", get(get_league))
+        .route("/leagues/:id/join", post(join_league))
+        .route("/leagues/:id/leaderboard", get(league_leaderboard))
         .route("/market/prices", get(market_prices))
         .route("/market/symbols", get(market_symbols))
+        .route("/market/instruments/:symbol", get(get_instrument))
+        .route("/market/status", get(market_status))
+        .route("/market/events", get(market_events))
+        .route("/market/stream", get(market_stream))
+        .route("/market/history/:symbol", get(market_history))
+        .route("/admin/market/backfill/:symbol", post(backfill_market))
+        .route("/market/replay", get(get_replay))
+        .route("/admin/market/replay/start", post(start_replay))
+        .route("/admin/market/replay/pause", post(pause_replay))
+        .route("/admin/market/replay/resume", post(resume_replay))
+        .route("/admin/market/replay/step", post(step_replay))
+        .route("/admin/market/replay/reset", post(reset_replay))
         .route("/activities/:id", get(get_activity))
         .with_state(state);
 
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = TcpListener::bind(config.server.bind).await.unwrap();
     info!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app).await.unwrap();
 }
@@ -131,14 +579,25 @@ async fn main() {
 mod tests {
     use super::*;
     use axum::http::{Request, StatusCode};
-    use holdings::Order;
-    use market::{MarketData, QuoteFetcher};
+    use holdings::{Order, Side};
+    use market::MarketData;
     use state::AppState;
-    use async_trait::async_trait;
-    use yahoo_finance_api::Quote;
     use tower::ServiceExt; // for `oneshot`
     use axum::body::to_bytes;
     use tempfile::tempdir;
+    use testing::{daily_quote, live_quote, quote, TestFetcher};
+
+    /// State serving `store` and `market`, keeping leagues under `dir`.
+    fn test_state(dir: &std::path::Path, store: HoldingStore, market: Arc<MarketData>) -> AppState {
+        AppState {
+            store,
+            market,
+            holdings: HoldingsService::new(),
+            activities: ActivityStore::new(),
+            leagues: LeagueStore::new(dir.join("leagues")),
+            admin_token: None,
+        }
+    }
 
     #[tokio::test]
     async fn test_hello() {
@@ -159,24 +618,17 @@ mod tests {
     async fn test_add_and_list_orders() {
         let dir = tempdir().unwrap();
         let store = HoldingStore::new(dir.path().to_path_buf());
-        struct DummyFetcher;
-        #[async_trait]
-        impl QuoteFetcher for DummyFetcher {
-            async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-                Ok(Vec::new())
-            }
-        }
         let market_dir = dir.path().join("market");
-        let market = Arc::new(MarketData::new(Arc::new(DummyFetcher), market_dir));
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), market_dir));
         let holdings = HoldingsService::new();
-        let state = AppState { store: store.clone(), market, holdings: holdings.clone(), activities: ActivityStore::new() };
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market) };
         let app = Router::new()
             .route("/holdings/transaction", post(add_transaction))
             .route("/holdings/orders", get(list_orders))
             .route("/holdings/orders/:user", get(list_orders_for_user))
             .with_state(state);
 
-        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), amount: 5, price: 10.0 };
+        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), side: Side::Buy, amount: 5, note: None, ..Default::default() };
         let response = app.clone()
             .oneshot(Request::builder()
                 .method("POST")
@@ -187,6 +639,9 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::CREATED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let filled: Order = serde_json::from_slice(&body).unwrap();
+        assert_eq!(filled.price, 10.0);
 
         let response = app.clone()
             .oneshot(Request::builder().uri("/holdings/orders").body(axum::body::Body::empty()).unwrap())
@@ -229,22 +684,15 @@ mod tests {
         File::create(&file_path).unwrap();
 
         let store = HoldingStore::new(file_path);
-        struct DummyFetcher;
-        #[async_trait]
-        impl QuoteFetcher for DummyFetcher {
-            async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-                Ok(Vec::new())
-            }
-        }
         let market_dir = dir.path().join("market");
-        let market = Arc::new(MarketData::new(Arc::new(DummyFetcher), market_dir));
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), market_dir));
         let holdings = HoldingsService::new();
-        let state = AppState { store: store.clone(), market, holdings: holdings.clone(), activities: ActivityStore::new() };
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market) };
         let app = Router::new()
             .route("/holdings/transaction", post(add_transaction))
             .with_state(state);
 
-        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), amount: 5, price: 10.0 };
+        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), side: Side::Buy, amount: 5, note: None, ..Default::default() };
         let response = app
             .oneshot(Request::builder()
                 .method("POST")
@@ -262,129 +710,819 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_market_prices_endpoint() {
+    async fn test_sell_exceeding_position_is_rejected() {
         let dir = tempdir().unwrap();
         let store = HoldingStore::new(dir.path().to_path_buf());
         store
-            .add_order(Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 1.0 })
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 2, 10.0))
             .await
             .unwrap();
-
-        struct MockFetcher;
-        #[async_trait]
-        impl QuoteFetcher for MockFetcher {
-            async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-                Ok(vec![Quote { timestamp: 0, open: 10.0, high: 10.0, low: 10.0, volume: 0, close: 10.0, adjclose: 10.0 }])
-            }
-        }
-
         let market_dir = dir.path().join("market");
-        let market = Arc::new(MarketData::new(Arc::new(MockFetcher), market_dir));
-        let holdings = HoldingsService::new();
-        let state = AppState { store: store.clone(), market: market.clone(), holdings: holdings.clone(), activities: ActivityStore::new() };
-        market.update(&store, &holdings).await.unwrap();
-
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), market_dir));
+        let state = test_state(dir.path(), store.clone(), market);
         let app = Router::new()
-            .route("/market/prices", get(market_prices))
+            .route("/holdings/transaction", post(add_transaction))
             .with_state(state);
 
+        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), side: Side::Sell, amount: 3, note: None, ..Default::default() };
         let response = app
-            .oneshot(Request::builder().uri("/market/prices").body(axum::body::Body::empty()).unwrap())
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let prices: serde_json::Value = serde_json::from_slice(&body).unwrap();
-        assert_eq!(prices["AAPL"], 10.0);
+        let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err["error"], "cannot sell 3 AAPL: only 2 held");
     }
 
     #[tokio::test]
-    async fn test_market_symbols_endpoint() {
+    async fn test_trade_against_simulated_market() {
+        use provider::{SimulatedFetcher, SimulationParams};
+
         let dir = tempdir().unwrap();
+        let params = SimulationParams { seed: 1, ..SimulationParams::default() };
+        let expected = SimulatedFetcher::new(params.clone())
+            .bars("AAPL", chrono::Utc::now().date_naive())
+            .last()
+            .unwrap()
+            .close;
+        let market = Arc::new(MarketData::new(Arc::new(SimulatedFetcher::new(params)), dir.path().join("market")));
         let store = HoldingStore::new(dir.path().to_path_buf());
-        store
-            .add_order(Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 1.0 })
-            .await
-            .unwrap();
-
-        struct MockFetcher;
-        #[async_trait]
-        impl QuoteFetcher for MockFetcher {
-            async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-                Ok(vec![Quote { timestamp: 0, open: 10.0, high: 10.0, low: 10.0, volume: 0, close: 10.0, adjclose: 10.0 }])
-            }
-        }
-
-        let market_dir = dir.path().join("market");
-        let market = Arc::new(MarketData::new(Arc::new(MockFetcher), market_dir));
-        let holdings = HoldingsService::new();
-        let state = AppState { store: store.clone(), market: market.clone(), holdings: holdings.clone(), activities: ActivityStore::new() };
-        market.update(&store, &holdings).await.unwrap();
-
+        let state = test_state(dir.path(), store.clone(), market);
         let app = Router::new()
-            .route("/market/symbols", get(market_symbols))
+            .route("/holdings/transaction", post(add_transaction))
             .with_state(state);
 
+        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), side: Side::Buy, amount: 2, ..Default::default() };
         let response = app
-            .oneshot(Request::builder().uri("/market/symbols").body(axum::body::Body::empty()).unwrap())
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let symbols: Vec<String> = serde_json::from_slice(&body).unwrap();
-        assert_eq!(symbols, vec!["AAPL"]);
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(store.orders_for_user("alice").await.unwrap()[0].price, expected);
     }
 
     #[tokio::test]
-    async fn test_holdings_endpoint() {
+    async fn test_place_list_and_cancel_pending_order() {
         let dir = tempdir().unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), dir.path().join("market")));
         let store = HoldingStore::new(dir.path().to_path_buf());
-        store
-            .add_order(Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 1.0 })
+        let state = test_state(dir.path(), store.clone(), market);
+        let app = Router::new()
+            .route("/holdings/transaction", post(add_transaction))
+            .route("/holdings/orders/:user", get(list_orders_for_user))
+            .route("/orders/pending/:id", delete(cancel_order))
+            .route("/holdings/pending/:user", get(list_pending_for_user))
+            .with_state(state);
+
+        let order = OrderRequest {
+            user: "alice".into(),
+            symbol: "AAPL".into(),
+            amount: 2,
+            order_type: OrderType::Limit,
+            limit_price: Some(9.0),
+            time_in_force: orderbook::TimeInForce::Gtc,
+            ..Default::default()
+        };
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let pending: orderbook::PendingOrder = serde_json::from_slice(&body).unwrap();
+        assert!(store.all_orders().await.is_empty());
 
-        struct MockFetcher;
-        #[async_trait]
-        impl QuoteFetcher for MockFetcher {
-            async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-                Ok(vec![Quote { timestamp: 0, open: 10.0, high: 10.0, low: 10.0, volume: 0, close: 10.0, adjclose: 10.0 }])
-            }
-        }
+        let response = app.clone()
+            .oneshot(Request::builder().uri("/holdings/pending/alice").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let listed: Vec<orderbook::PendingOrder> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed, vec![pending.clone()]);
 
-        let market_dir = dir.path().join("market");
-        let market = Arc::new(MarketData::new(Arc::new(MockFetcher), market_dir));
-        let holdings = HoldingsService::new();
-        let state = AppState { store: store.clone(), market: market.clone(), holdings: holdings.clone(), activities: ActivityStore::new() };
-        market.update(&store, &holdings).await.unwrap();
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .method("DELETE")
+                .uri(format!("/orders/pending/{}", pending.id))
+                .body(axum::body::Body::empty())
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(store.book().for_user("alice").await.unwrap().is_empty());
+
+        let response = app
+            .oneshot(Request::builder()
+                .method("DELETE")
+                .uri(format!("/orders/pending/{}", pending.id))
+                .body(axum::body::Body::empty())
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 
+    #[tokio::test]
+    async fn test_unknown_symbols_are_rejected() {
+        let dir = tempdir().unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_symbol_quote("AAPL", live_quote(1.0))), dir.path().join("market")));
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let state = test_state(dir.path(), store.clone(), market);
         let app = Router::new()
-            .route("/holdings", get(list_holdings))
-            .route("/holdings/:user", get(list_holdings_for_user))
+            .route("/holdings/transaction", post(add_transaction))
+            .route("/market/instruments/:symbol", get(get_instrument))
             .with_state(state);
 
-        let response = app
-            .clone()
-            .oneshot(Request::builder().uri("/holdings/alice").body(axum::body::Body::empty()).unwrap())
+        let order = OrderRequest { user: "alice".into(), symbol: "APPL".into(), amount: 1, ..Default::default() };
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
             .await
             .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let holdings_resp: Vec<crate::portfolio::Holding> = serde_json::from_slice(&body).unwrap();
-        assert_eq!(holdings_resp.len(), 1);
-        assert_eq!(holdings_resp[0].current_price, 10.0);
+        let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err["error"], "unknown symbol APPL");
+        assert!(store.all_orders().await.is_empty());
 
-        let response = app
-            .oneshot(Request::builder().uri("/holdings").body(axum::body::Body::empty()).unwrap())
+        let response = app.clone()
+            .oneshot(Request::builder().uri("/market/instruments/AAPL").body(axum::body::Body::empty()).unwrap())
             .await
             .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
-        let all: Vec<crate::portfolio::Holding> = serde_json::from_slice(&body).unwrap();
-        assert_eq!(all.len(), 1);
-    }
+        let instrument: instrument::Instrument = serde_json::from_slice(&body).unwrap();
+        assert_eq!(instrument.symbol, "AAPL");
 
-    #[tokio::test]
+        let response = app
+            .oneshot(Request::builder().uri("/market/instruments/APPL").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_ids_are_rejected() {
+        let dir = tempdir().unwrap();
+        let market_dir = dir.path().join("market");
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), market_dir.clone()));
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let state = test_state(dir.path(), store.clone(), market);
+        let app = Router::new()
+            .route("/holdings/transaction", post(add_transaction))
+            .route("/holdings/orders/:user", get(list_orders_for_user))
+            .route("/market/history/:symbol", get(market_history))
+            .with_state(state);
+
+        for (user, symbol) in [("../market/AAPL", "AAPL"), ("alice", "../AAPL"), ("market", "AAPL")] {
+            let order = OrderRequest { user: user.into(), symbol: symbol.into(), amount: 1, ..Default::default() };
+            let response = app.clone()
+                .oneshot(Request::builder()
+                    .method("POST")
+                    .uri("/holdings/transaction")
+                    .header("content-type", "application/json")
+                    .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                    .unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{user} {symbol}");
+        }
+        assert!(store.all_orders().await.is_empty());
+        assert!(!market_dir.join("AAPL").exists());
+
+        for uri in ["/holdings/orders/..%2Fmarket", "/market/history/..%2F..%2Fetc"] {
+            let response = app.clone()
+                .oneshot(Request::builder().uri(uri).body(axum::body::Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_market_order_rejected_on_stale_quote() {
+        let dir = tempdir().unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), dir.path().join("market")));
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let state = test_state(dir.path(), store.clone(), market);
+        let app = Router::new()
+            .route("/holdings/transaction", post(add_transaction))
+            .with_state(state);
+
+        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), side: Side::Buy, amount: 1, note: None, ..Default::default() };
+        let response = app
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(err["error"].as_str().unwrap().contains("stale"));
+        assert!(store.all_orders().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_admin_backfill_requires_token() {
+        let dir = tempdir().unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), dir.path().join("market")));
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let state = AppState { admin_token: Some("secret".into()), ..test_state(dir.path(), store.clone(), market) };
+        let app = Router::new()
+            .route("/admin/holdings/transaction", post(backfill_transaction))
+            .with_state(state);
+
+        let req = serde_json::json!({
+            "user": "alice",
+            "symbol": "AAPL",
+            "amount": 2,
+            "price": 3.5,
+            "executed_at": "2020-01-02T15:00:00Z",
+        });
+        let request = |token: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/admin/holdings/transaction")
+                .header("content-type", "application/json")
+                .header("x-admin-token", token)
+                .body(axum::body::Body::from(serde_json::to_vec(&req).unwrap()))
+                .unwrap()
+        };
+
+        for token in ["wrong", "secreT", "secret!"] {
+            let response = app.clone().oneshot(request(token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = app.oneshot(request("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let orders = store.orders_for_user("alice").await.unwrap();
+        assert_eq!(orders[0].price, 3.5);
+        assert_eq!(orders[0].executed_at.to_rfc3339(), "2020-01-02T15:00:00+00:00");
+    }
+
+    #[tokio::test]
+    async fn test_admin_market_backfill() {
+        let dir = tempdir().unwrap();
+        let fetcher = TestFetcher::new().with_quote(quote(1.0)).with_history((0..3).map(|d| daily_quote(d, 1.0)).collect());
+        let market = Arc::new(MarketData::new(Arc::new(fetcher), dir.path().join("market")));
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let state = AppState { admin_token: Some("secret".into()), ..test_state(dir.path(), store, market.clone()) };
+        let app = Router::new()
+            .route("/admin/market/backfill/:symbol", post(backfill_market))
+            .with_state(state);
+        let request = |token: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/admin/market/backfill/AAPL")
+                .header("x-admin-token", token)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(market.bars("AAPL").await.unwrap().is_empty());
+
+        let response = app.oneshot(request("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["added"], 3);
+        assert_eq!(market.bars("AAPL").await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_admin_market_backfill_errors() {
+        let dir = tempdir().unwrap();
+        let fetcher = Arc::new(TestFetcher::new().with_quote(quote(1.0)).with_history(vec![daily_quote(0, 1.0)]));
+        // a file where the price directory should be makes every write fail
+        let blocked = dir.path().join("blocked");
+        std::fs::write(&blocked, b"").unwrap();
+        let broken = Arc::new(MarketData::new(fetcher.clone(), blocked));
+        let market = Arc::new(MarketData::new(fetcher.clone(), dir.path().join("market")));
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/admin/market/backfill/AAPL")
+                .header("x-admin-token", "secret")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        let app = |market| {
+            let store = HoldingStore::new(dir.path().to_path_buf());
+            let state = AppState { admin_token: Some("secret".into()), ..test_state(dir.path(), store, market) };
+            Router::new().route("/admin/market/backfill/:symbol", post(backfill_market)).with_state(state)
+        };
+
+        fetcher.fail_next_history(1);
+        let response = app(market).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = app(broken).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_replay_endpoints() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        // seed stored history for AAPL through the backfill path
+        // days 19_724 and 19_725 are 2024-01-02 and 2024-01-03
+        let fetcher = TestFetcher::new()
+            .with_quote(live_quote(99.0))
+            .with_history(vec![daily_quote(19_724, 10.0), daily_quote(19_725, 11.0)]);
+        let market = Arc::new(MarketData::new(Arc::new(fetcher), dir.path().join("market")));
+        market.backfill("AAPL", chrono::NaiveDate::from_ymd_opt(2024, 1, 3).unwrap()).await.unwrap();
+        let state = AppState { admin_token: Some("secret".into()), ..test_state(dir.path(), store.clone(), market.clone()) };
+        let app = Router::new()
+            .route("/holdings/transaction", post(add_transaction))
+            .route("/market/replay", get(get_replay))
+            .route("/admin/market/replay/start", post(start_replay))
+            .route("/admin/market/replay/step", post(step_replay))
+            .route("/admin/market/replay/reset", post(reset_replay))
+            .with_state(state);
+        let admin = |uri: &str, body: serde_json::Value| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("content-type", "application/json")
+                .header("x-admin-token", "secret")
+                .body(axum::body::Body::from(serde_json::to_vec(&body).unwrap()))
+                .unwrap()
+        };
+
+        let mut held = holdings::Order::new("alice", "AAPL", Side::Buy, 1, 9.0);
+        held.executed_at = "2023-12-29T21:00:00Z".parse().unwrap();
+        store.add_order(held).await.unwrap();
+        let start = serde_json::json!({ "start": "2024-01-02", "end": "2024-01-03" });
+        let response = app.clone().oneshot(admin("/admin/market/replay/start", start)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let order = OrderRequest { user: "alice".into(), symbol: "AAPL".into(), side: Side::Buy, amount: 1, ..Default::default() };
+        let response = app
+            .clone()
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(store.all_orders().await.len(), 1);
+
+        let response = app.clone().oneshot(admin("/admin/market/replay/step", serde_json::Value::Null)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/market/replay").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let session: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session["current"], "2024-01-03");
+        assert_eq!(session["status"], "finished");
+        assert_eq!(market.prices().await.get("AAPL"), Some(&11.0));
+
+        let response = app.clone().oneshot(admin("/admin/market/replay/reset", serde_json::Value::Null)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.oneshot(admin("/admin/market/replay/step", serde_json::Value::Null)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    /// Market with one AAPL position held by alice, quoted at 10.
+    async fn streaming_state(dir: &std::path::Path) -> AppState {
+        let store = HoldingStore::new(dir.to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), dir.join("market")));
+        test_state(dir, store, market)
+    }
+
+    #[tokio::test]
+    async fn test_market_events_stream() {
+        use futures::StreamExt;
+
+        let dir = tempdir().unwrap();
+        let state = streaming_state(dir.path()).await;
+        let app = Router::new()
+            .route("/market/events", get(market_events))
+            .with_state(state.clone());
+
+        let response = app
+            .oneshot(Request::builder().uri("/market/events?user=alice").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body().into_data_stream();
+        async fn next_event(body: &mut axum::body::BodyDataStream) -> String {
+            String::from_utf8(body.next().await.unwrap().unwrap().to_vec()).unwrap()
+        }
+
+        let snapshot = next_event(&mut body).await;
+        assert!(snapshot.starts_with("event: prices\n"), "{snapshot}");
+        assert!(snapshot.contains(r#""prices":{}"#));
+
+        state.market.update(&state.store, &state.holdings).await.unwrap();
+        let prices = next_event(&mut body).await;
+        assert!(prices.contains(r#""prices":{"AAPL":10.0}"#), "{prices}");
+        let holdings = next_event(&mut body).await;
+        assert!(holdings.starts_with("event: holdings\n"), "{holdings}");
+        assert!(holdings.contains(r#""user":"alice""#));
+    }
+
+    #[tokio::test]
+    async fn test_market_websocket_stream() {
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let dir = tempdir().unwrap();
+        let state = streaming_state(dir.path()).await;
+        let app = Router::new()
+            .route("/market/stream", get(market_stream))
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // bob has no holdings, so he only receives prices
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/market/stream?user=bob")).await.unwrap();
+        async fn next_event<S>(socket: &mut S) -> MarketEvent
+        where
+            S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+        {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("unexpected message {other:?}"),
+            }
+        }
+
+        assert!(matches!(next_event(&mut socket).await, MarketEvent::Prices { prices, .. } if prices.is_empty()));
+        state.market.update(&state.store, &state.holdings).await.unwrap();
+        assert!(matches!(next_event(&mut socket).await, MarketEvent::Prices { prices, .. } if prices["AAPL"] == 10.0));
+        state.market.update(&state.store, &state.holdings).await.unwrap();
+        assert!(matches!(next_event(&mut socket).await, MarketEvent::Prices { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_market_prices_endpoint() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0))
+            .await
+            .unwrap();
+
+        let market_dir = dir.path().join("market");
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), market_dir));
+        let holdings = HoldingsService::new();
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market.clone()) };
+        market.update(&store, &holdings).await.unwrap();
+
+        let app = Router::new()
+            .route("/market/prices", get(market_prices))
+            .with_state(state);
+
+        let response = app
+            .oneshot(Request::builder().uri("/market/prices").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let prices: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(prices["AAPL"], 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_market_symbols_endpoint() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0))
+            .await
+            .unwrap();
+
+        let market_dir = dir.path().join("market");
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), market_dir));
+        let holdings = HoldingsService::new();
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market.clone()) };
+        market.update(&store, &holdings).await.unwrap();
+
+        let app = Router::new()
+            .route("/market/symbols", get(market_symbols))
+            .with_state(state);
+
+        let response = app
+            .oneshot(Request::builder().uri("/market/symbols").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let symbols: Vec<String> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(symbols, vec!["AAPL"]);
+    }
+
+    #[tokio::test]
+    async fn test_market_status_endpoint() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        store.add_order(Order::new("alice", "GONE", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let retry = market::RetryPolicy { attempts: 1, base_delay: std::time::Duration::ZERO, ..Default::default() };
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0)).with_failing_symbol("GONE")), dir.path().join("market")).with_retry_policy(retry));
+        let holdings = HoldingsService::new();
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market.clone()) };
+        market.update(&store, &holdings).await.unwrap();
+
+        let app = Router::new()
+            .route("/market/status", get(market_status))
+            .with_state(state);
+
+        let response = app
+            .oneshot(Request::builder().uri("/market/status").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["AAPL"]["consecutive_failures"], 0);
+        assert_eq!(status["GONE"]["consecutive_failures"], 1);
+        assert_eq!(status["GONE"]["last_error"], "no data for GONE");
+    }
+
+    #[tokio::test]
+    async fn test_market_history_endpoint() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+
+        // 1970-01-01 was a Thursday; the third quote lands on Monday 1970-01-05
+        let fetcher = TestFetcher::new()
+            .with_quote(daily_quote(0, 10.0))
+            .with_quote(daily_quote(1, 12.0))
+            .with_quote(daily_quote(4, 11.0));
+        let market = Arc::new(MarketData::new(Arc::new(fetcher), dir.path().join("market")));
+        let holdings = HoldingsService::new();
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market.clone()) };
+        for _ in 0..3 {
+            market.update(&store, &holdings).await.unwrap();
+        }
+
+        let app = Router::new()
+            .route("/market/history/:symbol", get(market_history))
+            .with_state(state);
+        let get_json = |uri: &str| {
+            let app = app.clone();
+            let uri = uri.to_string();
+            async move {
+                let response = app.oneshot(Request::builder().uri(uri).body(axum::body::Body::empty()).unwrap()).await.unwrap();
+                let status = response.status();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default())
+            }
+        };
+
+        let (status, bars) = get_json("/market/history/AAPL?from=1970-01-02").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bars.as_array().unwrap().len(), 2);
+        assert_eq!(bars[0]["date"], "1970-01-02");
+
+        let (status, bars) = get_json("/market/history/AAPL?interval=1wk").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bars.as_array().unwrap().len(), 2);
+        assert_eq!(bars[0]["close"], 12.0);
+        assert_eq!(bars[0]["high"], 12.0);
+        assert_eq!(bars[0]["volume"], 2);
+
+        let (status, _) = get_json("/market/history/AAPL?from=1970-02-01&to=1970-01-01").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, bars) = get_json("/market/history/AAPL?to=1969-12-31").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(bars.as_array().unwrap().is_empty());
+        let (status, _) = get_json("/market/history/MSFT").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get_json("/market/history/AAPL?interval=1y").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_holdings_endpoint() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0))
+            .await
+            .unwrap();
+        store
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 3, 5.0))
+            .await
+            .unwrap();
+
+        let market_dir = dir.path().join("market");
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), market_dir));
+        let holdings = HoldingsService::new();
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market.clone()) };
+        market.update(&store, &holdings).await.unwrap();
+
+        let app = Router::new()
+            .route("/holdings", get(list_holdings))
+            .route("/holdings/:user", get(list_holdings_for_user))
+            .route("/holdings/:user/history", get(holdings_history))
+            .with_state(state);
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/holdings/alice/history").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let history: Vec<crate::portfolio::ValuationPoint> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].market_value, 40.0);
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/holdings/alice").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let holdings_resp: Vec<crate::portfolio::Holding> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(holdings_resp.len(), 1);
+        assert_eq!(holdings_resp[0].current_price, 10.0);
+        assert_eq!(holdings_resp[0].quantity, 4);
+        assert_eq!(holdings_resp[0].average_cost, 4.0);
+        assert_eq!(holdings_resp[0].unrealised_pnl, 24.0);
+
+        let response = app
+            .oneshot(Request::builder().uri("/holdings").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let all: Vec<crate::portfolio::Holding> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(all.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_account_endpoint() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf()).with_starting_cash(1_000.0);
+        store
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 10, 5.0))
+            .await
+            .unwrap();
+
+        let market_dir = dir.path().join("market");
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), market_dir));
+        let holdings = HoldingsService::new();
+        let state = AppState { holdings: holdings.clone(), ..test_state(dir.path(), store.clone(), market.clone()) };
+        market.update(&store, &holdings).await.unwrap();
+
+        let app = Router::new()
+            .route("/accounts/:user", get(get_account))
+            .with_state(state);
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/accounts/alice").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let account: Account = serde_json::from_slice(&body).unwrap();
+        assert_eq!(account.cash, 950.0);
+        assert_eq!(account.market_value, 100.0);
+        assert_eq!(account.equity, 1_050.0);
+
+        // users without orders still have their starting cash
+        let response = app
+            .oneshot(Request::builder().uri("/accounts/bob").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let account: Account = serde_json::from_slice(&body).unwrap();
+        assert_eq!(account.equity, 1_000.0);
+    }
+
+    #[tokio::test]
+    async fn test_league_endpoints() {
+        let dir = tempdir().unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(live_quote(10.0))), dir.path().join("market")));
+        let state = test_state(dir.path(), HoldingStore::new(dir.path().to_path_buf()), market);
+        let app = Router::new()
+            .route("/holdings/transaction", post(add_transaction))
+            .route("/leagues", get(list_leagues).post(create_league))
+            .route("/leagues/:id", get(get_league))
+            .route("/leagues/:id/join", post(join_league))
+            .route("/leagues/:id/leaderboard", get(league_leaderboard))
+            .with_state(state);
+
+        let today = chrono::Utc::now().date_naive();
+        let req = serde_json::json!({
+            "name": "office",
+            "starting_bankroll": 1000.0,
+            "symbols": ["AAPL"],
+            "start": today.to_string(),
+            "end": (today + chrono::Duration::days(30)).to_string(),
+        });
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/leagues")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&req).unwrap()))
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let league: league::League = serde_json::from_slice(&body).unwrap();
+
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri(format!("/leagues/{}/join", league.id))
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(r#"{"user":"alice"}"#))
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone()
+            .oneshot(Request::builder().uri(format!("/leagues/{}", league.id)).body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let fetched: league::League = serde_json::from_slice(&body).unwrap();
+        assert_eq!(fetched.members, vec!["alice"]);
+
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .uri(format!("/leagues/{}/leaderboard?rank_by=absolute_gain", league.id))
+                .body(axum::body::Body::empty())
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let standings: Vec<leaderboard::Standing> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(standings.len(), 1);
+        assert_eq!(standings[0].user, "alice");
+        assert_eq!(standings[0].equity, 1000.0);
+
+        // symbols outside the league universe are rejected for members
+        let order = OrderRequest { user: "alice".into(), symbol: "MSFT".into(), side: Side::Buy, amount: 1, note: None, ..Default::default() };
+        let response = app.clone()
+            .oneshot(Request::builder()
+                .method("POST")
+                .uri("/holdings/transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::to_vec(&order).unwrap()))
+                .unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = app
+            .oneshot(Request::builder().uri("/leagues/missing").body(axum::body::Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
     async fn test_get_activity_endpoint() {
         let store = ActivityStore::new();
         store
@@ -395,19 +1533,13 @@ mod tests {
                 power: vec![50],
                 gps: vec![activity::GpsPoint { lat: 0.0, lon: 0.0 }],
             })
-            .await;
-        struct NoopFetcher;
-        #[async_trait]
-        impl QuoteFetcher for NoopFetcher {
-            async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-                Ok(Vec::new())
-            }
-        }
+            .await
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new()), dir.path().join("market")));
         let state = AppState {
-            store: HoldingStore::new(std::path::PathBuf::from("/tmp")),
-            market: Arc::new(MarketData::new(Arc::new(NoopFetcher), std::path::PathBuf::from("/tmp"))),
-            holdings: HoldingsService::new(),
             activities: store.clone(),
+            ..test_state(dir.path(), HoldingStore::new(dir.path().to_path_buf()), market)
         };
         let app = Router::new()
             .route("/activities/:id", get(get_activity))
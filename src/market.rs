@@ -1,14 +1,42 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 use std::path::PathBuf;
 
-use chrono::{DateTime, Utc};
+use anyhow::Context;
+use futures::StreamExt;
+use chrono::{DateTime, NaiveDate, Utc};
 
 use axum::async_trait;
 use tokio::sync::RwLock;
 use yahoo_finance_api::{YahooConnector, Quote};
 
 use crate::holdings::HoldingStore;
+use crate::instrument::{Instrument, InstrumentRegistry};
+use crate::util::{date_to_days, days_to_date};
+use crate::replay::{ReplayError, ReplaySession, ReplayStatus, StartReplayRequest};
+use crate::storage::{ParquetPriceStorage, PriceStorage};
+
+/// Quotes older than this are too stale to fill market orders against. Long
+/// enough to keep trading on the previous session's daily bar over a weekend.
+pub const DEFAULT_MAX_QUOTE_AGE_HOURS: i64 = 96;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuoteError {
+    #[error("no quote available for {0}")]
+    Missing(String),
+    #[error("quote for {symbol} is stale: last updated {at}")]
+    Stale { symbol: String, at: DateTime<Utc> },
+    #[error(transparent)]
+    Fetch(#[from] anyhow::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackfillError {
+    #[error(transparent)]
+    Fetch(anyhow::Error),
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
 
 /// Stores historical quotes for a symbol.
 #[derive(Clone, Debug)]
@@ -16,39 +44,209 @@ pub struct PriceInfo {
     pub history: Vec<Quote>,
 }
 
+/// One day of trading for a symbol.
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
-pub struct DailyClose {
-    pub date: String,
+pub struct DailyBar {
+    pub date: NaiveDate,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
     pub close: f64,
+    pub adjclose: f64,
+    pub volume: u64,
+}
+
+impl DailyBar {
+    /// Bar for the UTC day `quote` was taken on.
+    fn from_quote(quote: &Quote) -> anyhow::Result<Self> {
+        let date = DateTime::<Utc>::from_timestamp(quote.timestamp, 0)
+            .context("invalid quote timestamp")?
+            .date_naive();
+        Ok(Self {
+            date,
+            open: quote.open,
+            high: quote.high,
+            low: quote.low,
+            close: quote.close,
+            adjclose: quote.adjclose,
+            volume: quote.volume,
+        })
+    }
+
+    /// Quote stamped at midnight UTC on the bar's date.
+    pub fn to_quote(&self) -> Quote {
+        Quote {
+            timestamp: self.date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp(),
+            open: self.open,
+            high: self.high,
+            low: self.low,
+            volume: self.volume,
+            close: self.close,
+            adjclose: self.adjclose,
+        }
+    }
+}
+
+/// Bar size returned by [`MarketData::history`].
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub enum Interval {
+    #[default]
+    #[serde(rename = "1d")]
+    Daily,
+    /// Calendar weeks starting on Monday.
+    #[serde(rename = "1wk")]
+    Weekly,
+    #[serde(rename = "1mo")]
+    Monthly,
+}
+
+impl Interval {
+    /// First day of the period containing `date`.
+    fn period_start(self, date: NaiveDate) -> NaiveDate {
+        use chrono::Datelike;
+        match self {
+            Interval::Daily => date,
+            Interval::Weekly => date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64),
+            Interval::Monthly => date.with_day(1).expect("day 1 exists in every month"),
+        }
+    }
+}
+
+/// Merge consecutive daily `bars` into one bar per `interval` period, dated
+/// by the first trading day in the period.
+pub fn resample(bars: &[DailyBar], interval: Interval) -> Vec<DailyBar> {
+    let mut out: Vec<(NaiveDate, DailyBar)> = Vec::new();
+    for bar in bars {
+        let period = interval.period_start(bar.date);
+        match out.last_mut() {
+            Some((p, agg)) if *p == period => {
+                agg.high = agg.high.max(bar.high);
+                agg.low = agg.low.min(bar.low);
+                agg.close = bar.close;
+                agg.adjclose = bar.adjclose;
+                agg.volume += bar.volume;
+            }
+            _ => out.push((period, bar.clone())),
+        }
+    }
+    out.into_iter().map(|(_, bar)| bar).collect()
+}
+
+/// Add `new` bars for dates missing from `existing`, keeping the result in
+/// date order. Returns the number of bars added.
+fn merge_bars(existing: &mut Vec<DailyBar>, new: impl IntoIterator<Item = DailyBar>) -> usize {
+    let mut by_date: BTreeMap<NaiveDate, DailyBar> = existing.drain(..).map(|b| (b.date, b)).collect();
+    let before = by_date.len();
+    for bar in new {
+        by_date.entry(bar.date).or_insert(bar);
+    }
+    let added = by_date.len() - before;
+    existing.extend(by_date.into_values());
+    added
+}
+
+/// Outcome of [`MarketData::backfill`].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+pub struct BackfillReport {
+    pub symbol: String,
+    /// Bars added to the stored history.
+    pub added: usize,
+    /// Bars stored after the backfill.
+    pub bars: usize,
 }
 
 fn price_schema() -> arrow_schema::Schema {
     use arrow_schema::{DataType, Field, Schema};
     Schema::new(vec![
-        Field::new("date", DataType::Utf8, false),
+        Field::new("date", DataType::Date32, false),
+        Field::new("open", DataType::Float64, false),
+        Field::new("high", DataType::Float64, false),
+        Field::new("low", DataType::Float64, false),
         Field::new("close", DataType::Float64, false),
+        Field::new("adjclose", DataType::Float64, false),
+        Field::new("volume", DataType::UInt64, false),
     ])
 }
 
-fn closes_to_record_batch(closes: &[DailyClose]) -> anyhow::Result<arrow_array::RecordBatch> {
-    use arrow_array::{Float64Array, RecordBatch, StringArray};
+pub(crate) fn bars_to_record_batch(bars: &[DailyBar]) -> anyhow::Result<arrow_array::RecordBatch> {
+    use arrow_array::{Date32Array, Float64Array, RecordBatch, UInt64Array};
     use std::sync::Arc as SyncArc;
 
     let schema = SyncArc::new(price_schema());
-    let date_array = StringArray::from_iter_values(closes.iter().map(|c| c.date.as_str()));
-    let close_array = Float64Array::from_iter_values(closes.iter().map(|c| c.close));
+    let date_array = Date32Array::from_iter_values(bars.iter().map(|b| date_to_days(b.date)));
+    let open_array = Float64Array::from_iter_values(bars.iter().map(|b| b.open));
+    let high_array = Float64Array::from_iter_values(bars.iter().map(|b| b.high));
+    let low_array = Float64Array::from_iter_values(bars.iter().map(|b| b.low));
+    let close_array = Float64Array::from_iter_values(bars.iter().map(|b| b.close));
+    let adjclose_array = Float64Array::from_iter_values(bars.iter().map(|b| b.adjclose));
+    let volume_array = UInt64Array::from_iter_values(bars.iter().map(|b| b.volume));
 
-    Ok(RecordBatch::try_new(schema, vec![SyncArc::new(date_array), SyncArc::new(close_array)])?)
+    Ok(RecordBatch::try_new(
+        schema,
+        vec![
+            SyncArc::new(date_array),
+            SyncArc::new(open_array),
+            SyncArc::new(high_array),
+            SyncArc::new(low_array),
+            SyncArc::new(close_array),
+            SyncArc::new(adjclose_array),
+            SyncArc::new(volume_array),
+        ],
+    )?)
 }
 
-fn batch_to_closes(batch: &arrow_array::RecordBatch) -> Vec<DailyClose> {
-    use arrow_array::{Float64Array, StringArray};
+/// Read bars from `batch`. Files written before bars were stored hold only a
+/// string `date` and a `close`, which is used for every price of the bar.
+pub(crate) fn batch_to_bars(batch: &arrow_array::RecordBatch) -> anyhow::Result<Vec<DailyBar>> {
+    use arrow_array::{Date32Array, Float64Array, StringArray, UInt64Array};
 
-    let date_array = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
-    let close_array = batch.column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+    let f64_col = |name: &str| {
+        batch
+            .column_by_name(name)
+            .and_then(|c| c.as_any().downcast_ref::<Float64Array>())
+            .with_context(|| format!("missing {name} column"))
+    };
+    let close_array = f64_col("close")?;
+
+    if let Some(date_array) = batch.column(0).as_any().downcast_ref::<StringArray>() {
+        return (0..batch.num_rows())
+            .map(|i| {
+                let close = close_array.value(i);
+                Ok(DailyBar {
+                    date: date_array.value(i).parse().context("invalid date")?,
+                    open: close,
+                    high: close,
+                    low: close,
+                    close,
+                    adjclose: close,
+                    volume: 0,
+                })
+            })
+            .collect();
+    }
+
+    let date_array = batch.column(0).as_any().downcast_ref::<Date32Array>().context("missing date column")?;
+    let open_array = f64_col("open")?;
+    let high_array = f64_col("high")?;
+    let low_array = f64_col("low")?;
+    let adjclose_array = f64_col("adjclose")?;
+    let volume_array = batch
+        .column_by_name("volume")
+        .and_then(|c| c.as_any().downcast_ref::<UInt64Array>())
+        .context("missing volume column")?;
 
     (0..batch.num_rows())
-        .map(|i| DailyClose { date: date_array.value(i).to_string(), close: close_array.value(i) })
+        .map(|i| {
+            Ok(DailyBar {
+                date: days_to_date(date_array.value(i))?,
+                open: open_array.value(i),
+                high: high_array.value(i),
+                low: low_array.value(i),
+                close: close_array.value(i),
+                adjclose: adjclose_array.value(i),
+                volume: volume_array.value(i),
+            })
+        })
         .collect()
 }
 
@@ -62,6 +260,22 @@ impl PriceInfo {
 #[async_trait]
 pub trait QuoteFetcher: Send + Sync {
     async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>>;
+
+    /// Describe `symbol`, or `None` if the provider does not know it.
+    ///
+    /// The default treats any symbol with quotes as a valid instrument.
+    async fn lookup_instrument(&self, symbol: &str) -> anyhow::Result<Option<Instrument>> {
+        let quotes = self.fetch_quotes(symbol).await?;
+        Ok((!quotes.is_empty()).then(|| Instrument::bare(symbol)))
+    }
+
+    /// Daily quotes for `symbol` from `start` to `end` inclusive.
+    ///
+    /// Providers without historical data return nothing, which leaves
+    /// backfills as no-ops.
+    async fn fetch_history(&self, _symbol: &str, _start: NaiveDate, _end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        Ok(Vec::new())
+    }
 }
 
 /// Implementation of [`QuoteFetcher`] that queries yahoo finance.
@@ -81,6 +295,133 @@ impl QuoteFetcher for YahooFetcher {
         let response = self.connector.get_latest_quotes(symbol, "1d").await?;
         Ok(response.quotes()?)
     }
+
+    async fn lookup_instrument(&self, symbol: &str) -> anyhow::Result<Option<Instrument>> {
+        use yahoo_finance_api::YahooError;
+
+        let response = match self.connector.get_latest_quotes(symbol, "1d").await {
+            Ok(r) => r,
+            Err(YahooError::ApiError(_) | YahooError::NoResult | YahooError::NoQuotes) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let meta = response.metadata()?;
+        Ok(Some(Instrument {
+            name: meta.long_name.or(meta.short_name).unwrap_or_else(|| meta.symbol.clone()),
+            symbol: meta.symbol,
+            exchange: Some(meta.exchange_name),
+            currency: meta.currency,
+            asset_type: Some(meta.instrument_type),
+        }))
+    }
+
+    async fn fetch_history(&self, symbol: &str, start: NaiveDate, end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        use yahoo_finance_api::time::OffsetDateTime;
+
+        let to_offset = |date: NaiveDate| -> anyhow::Result<OffsetDateTime> {
+            let ts = date.and_hms_opt(0, 0, 0).context("invalid date")?.and_utc().timestamp();
+            Ok(OffsetDateTime::from_unix_timestamp(ts)?)
+        };
+        // the end of the range is exclusive on yahoo's side
+        let end = end.succ_opt().context("invalid date")?;
+        let response = self.connector.get_quote_history(symbol, to_offset(start)?, to_offset(end)?).await?;
+        Ok(response.quotes()?)
+    }
+}
+
+/// Spaces out requests to the quote provider so that at most `per_second`
+/// are made, however many tasks share it.
+pub struct RateLimiter {
+    interval: std::time::Duration,
+    next_slot: tokio::sync::Mutex<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    /// Fails unless `per_second` is a positive rate whose interval between
+    /// requests fits in a [`std::time::Duration`].
+    pub fn new(per_second: f64) -> anyhow::Result<Self> {
+        if !(per_second > 0.0 && per_second.is_finite()) {
+            anyhow::bail!("rate limit must be a positive number of requests per second, got {per_second}");
+        }
+        let interval = std::time::Duration::try_from_secs_f64(1.0 / per_second)
+            .with_context(|| format!("rate limit of {per_second} requests per second is too low"))?;
+        Ok(Self { interval, next_slot: tokio::sync::Mutex::new(tokio::time::Instant::now()) })
+    }
+
+    /// Wait until the next request slot is free and reserve it.
+    async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next_slot.lock().await;
+            let slot = (*next).max(tokio::time::Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+/// Update pushed to streaming clients after every market data refresh.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketEvent {
+    /// Latest price of every tracked symbol.
+    Prices { at: DateTime<Utc>, prices: BTreeMap<String, f64> },
+    /// Revalued positions of a single user.
+    Holdings { at: DateTime<Utc>, user: String, holdings: Vec<crate::portfolio::Holding> },
+}
+
+impl MarketEvent {
+    /// Short name of the event kind.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MarketEvent::Prices { .. } => "prices",
+            MarketEvent::Holdings { .. } => "holdings",
+        }
+    }
+}
+
+/// Events buffered per subscriber before slow ones start missing updates.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Health of the quote feed for a single symbol.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize, PartialEq)]
+pub struct SymbolStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    pub consecutive_failures: u32,
+    /// The first-sight history backfill failed and is retried on every refresh.
+    #[serde(default)]
+    pub backfill_pending: bool,
+}
+
+/// How often a failed fetch is retried within a single update.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry; doubled for every further retry.
+    pub base_delay: std::time::Duration,
+    /// Longest delay between two attempts.
+    pub max_delay: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            base_delay: std::time::Duration::from_millis(500),
+            max_delay: std::time::Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay after failed attempt number `attempt`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> std::time::Duration {
+        2u32.checked_pow(attempt)
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
 }
 
 /// In-memory store of market data refreshed in the background.
@@ -88,112 +429,490 @@ impl QuoteFetcher for YahooFetcher {
 pub struct MarketData {
     fetcher: Arc<dyn QuoteFetcher>,
     inner: Arc<RwLock<HashMap<String, PriceInfo>>>,
-    data_dir: PathBuf,
-    fs_lock: Arc<tokio::sync::Mutex<()>>,
+    storage: Arc<dyn PriceStorage>,
+    max_quote_age: chrono::Duration,
+    instruments: InstrumentRegistry,
+    status: Arc<RwLock<HashMap<String, SymbolStatus>>>,
+    retry: RetryPolicy,
+    concurrency: usize,
+    fetch_timeout: std::time::Duration,
+    /// Shared by every request to the provider, if set.
+    rate_limit: Option<Arc<RateLimiter>>,
+    backfill_years: u32,
+    update_interval: std::time::Duration,
+    /// When each symbol the provider did not know was looked up, so
+    /// repeated orders for a typo do not each cost a provider request.
+    unknown_symbols: Arc<std::sync::Mutex<HashMap<String, std::time::Instant>>>,
+    unknown_symbol_ttl: std::time::Duration,
+    replay: Arc<RwLock<Option<ReplaySession>>>,
+    /// Held while the replay clock moves and the prices of the new day are
+    /// loaded, so concurrent steps publish their days in order.
+    replay_step: Arc<tokio::sync::Mutex<()>>,
+    /// Wakes the background loop when a replay starts, pauses or stops.
+    wake: Arc<tokio::sync::Notify>,
+    events: tokio::sync::broadcast::Sender<MarketEvent>,
+    /// One channel per user with live holdings subscribers, so clients only
+    /// buffer their own valuations.
+    holding_events: Arc<std::sync::Mutex<HashMap<String, tokio::sync::broadcast::Sender<MarketEvent>>>>,
 }
 
-const UPDATE_INTERVAL_SECS: u64 = 120;
+/// Seconds between live refreshes unless configured otherwise.
+pub const DEFAULT_UPDATE_INTERVAL_SECS: u64 = 120;
+/// Symbols fetched at the same time during an update unless configured otherwise.
+pub const DEFAULT_FETCH_CONCURRENCY: usize = 8;
+/// Seconds a single quote request may take unless configured otherwise.
+pub const DEFAULT_FETCH_TIMEOUT_SECS: u64 = 10;
+/// Years of daily history fetched for a newly tracked symbol unless configured otherwise.
+pub const DEFAULT_BACKFILL_YEARS: u32 = 5;
+/// Seconds a symbol unknown to the provider is remembered as unknown unless configured otherwise.
+pub const DEFAULT_UNKNOWN_SYMBOL_TTL_SECS: u64 = 60;
 
 impl MarketData {
+    /// Store daily bars as Parquet files under `data_dir`.
     pub fn new(fetcher: Arc<dyn QuoteFetcher>, data_dir: PathBuf) -> Self {
         Self {
             fetcher,
             inner: Arc::new(RwLock::new(HashMap::new())),
-            data_dir,
-            fs_lock: Arc::new(tokio::sync::Mutex::new(())),
+            storage: Arc::new(ParquetPriceStorage::new(data_dir)),
+            max_quote_age: chrono::Duration::hours(DEFAULT_MAX_QUOTE_AGE_HOURS),
+            instruments: InstrumentRegistry::new(),
+            status: Arc::new(RwLock::new(HashMap::new())),
+            retry: RetryPolicy::default(),
+            concurrency: DEFAULT_FETCH_CONCURRENCY,
+            fetch_timeout: std::time::Duration::from_secs(DEFAULT_FETCH_TIMEOUT_SECS),
+            rate_limit: None,
+            backfill_years: DEFAULT_BACKFILL_YEARS,
+            update_interval: std::time::Duration::from_secs(DEFAULT_UPDATE_INTERVAL_SECS),
+            unknown_symbols: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            unknown_symbol_ttl: std::time::Duration::from_secs(DEFAULT_UNKNOWN_SYMBOL_TTL_SECS),
+            replay: Arc::new(RwLock::new(None)),
+            replay_step: Arc::new(tokio::sync::Mutex::new(())),
+            wake: Arc::new(tokio::sync::Notify::new()),
+            events: tokio::sync::broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            holding_events: Arc::new(std::sync::Mutex::new(HashMap::new())),
         }
     }
 
-    async fn write_symbol_file(&self, symbol: &str, data: &[DailyClose]) -> anyhow::Result<()> {
-        use parquet::arrow::ArrowWriter;
-        use std::fs::{create_dir_all, File};
+    /// Receive the prices published after each update.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<MarketEvent> {
+        self.events.subscribe()
+    }
 
-        let _lock = self.fs_lock.lock().await;
+    /// Receive `user`'s holdings, revalued after each update.
+    pub fn subscribe_holdings(&self, user: &str) -> tokio::sync::broadcast::Receiver<MarketEvent> {
+        let mut channels = self.holding_events.lock().unwrap();
+        channels
+            .entry(user.to_string())
+            .or_insert_with(|| tokio::sync::broadcast::channel(EVENT_CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
 
-        let sym_dir = self.data_dir.join(symbol);
-        create_dir_all(&sym_dir)?;
-        let file_path = sym_dir.join("prices.parquet");
+    /// The channel for `user`'s holdings, if anyone is still listening on it.
+    fn holdings_channel(&self, user: &str) -> Option<tokio::sync::broadcast::Sender<MarketEvent>> {
+        let mut channels = self.holding_events.lock().unwrap();
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels.get(user).cloned()
+    }
 
-        let batch = closes_to_record_batch(data)?;
-        let file = File::create(file_path)?;
-        let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
-        writer.write(&batch)?;
-        writer.close()?;
-        Ok(())
+    /// Current prices as an event, for clients that just subscribed.
+    pub async fn price_snapshot(&self) -> MarketEvent {
+        let prices = self.prices().await.into_iter().collect();
+        MarketEvent::Prices { at: self.now().await, prices }
     }
 
-    async fn read_symbol_file(&self, symbol: &str) -> anyhow::Result<Vec<DailyClose>> {
-        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
-        use std::fs::File;
+    /// Keep daily bars in `storage` instead of the default Parquet files.
+    pub fn with_storage(mut self, storage: Arc<dyn PriceStorage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Refresh live quotes every `interval` in [`MarketData::run`].
+    pub fn with_update_interval(mut self, interval: std::time::Duration) -> Self {
+        self.update_interval = interval;
+        self
+    }
+
+    /// Fetch `years` of daily history when backfilling a symbol.
+    pub fn with_backfill_years(mut self, years: u32) -> Self {
+        self.backfill_years = years;
+        self
+    }
+
+    /// Fetch at most `limit` symbols at the same time during an update.
+    pub fn with_concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = limit.max(1);
+        self
+    }
 
-        let file_path = self.data_dir.join(symbol).join("prices.parquet");
-        if !file_path.exists() {
-            return Ok(Vec::new());
+    /// Give up on a single quote request after `timeout`.
+    pub fn with_fetch_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.fetch_timeout = timeout;
+        self
+    }
+
+    /// Send requests to the provider no faster than `limiter` allows.
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Retry failed fetches according to `policy`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Remember symbols the provider does not know for `ttl` before asking
+    /// about them again. A zero `ttl` asks every time.
+    pub fn with_unknown_symbol_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.unknown_symbol_ttl = ttl;
+        self
+    }
+
+    /// Reject market orders against quotes older than `age`.
+    pub fn with_max_quote_age(mut self, age: chrono::Duration) -> Self {
+        self.max_quote_age = age;
+        self
+    }
+
+    /// Send one request to the provider once the rate limit allows it. Only
+    /// the request itself counts toward the fetch timeout, not the wait for
+    /// its turn.
+    async fn request<T>(&self, call: impl std::future::Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        if let Some(limiter) = &self.rate_limit {
+            limiter.acquire().await;
         }
+        tokio::time::timeout(self.fetch_timeout, call)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", self.fetch_timeout)))
+    }
+
+    /// Fetch quotes for `symbol`, retrying with exponential backoff.
+    async fn fetch_with_retry(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
+        let mut attempt = 0;
+        loop {
+            tracing::info!("fetching quotes for {symbol}");
+            match self.request(self.fetcher.fetch_quotes(symbol)).await {
+                Ok(q) => {
+                    tracing::info!("received {} quotes for {symbol}", q.len());
+                    return Ok(q);
+                }
+                Err(e) if attempt + 1 >= self.retry.attempts => return Err(e),
+                Err(e) => {
+                    let delay = self.retry.delay(attempt);
+                    tracing::warn!("fetching quotes for {symbol} failed, retrying in {delay:?}: {e}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Fetch daily history for `symbol` up to `today` and merge it into the
+    /// stored bars. Dates that are already stored are left untouched.
+    pub async fn backfill(&self, symbol: &str, today: NaiveDate) -> Result<BackfillReport, BackfillError> {
+        let start = today
+            .checked_sub_months(chrono::Months::new(12 * self.backfill_years))
+            .context("backfill range out of bounds")?;
+        tracing::info!("backfilling {symbol} from {start} to {today}");
+        let quotes = self
+            .request(self.fetcher.fetch_history(symbol, start, today))
+            .await
+            .map_err(BackfillError::Fetch)?;
+        let fetched = quotes
+            .iter()
+            .map(DailyBar::from_quote)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(BackfillError::Fetch)?;
+
+        let mut history = self.storage.bars(symbol).await?;
+        let known: HashSet<NaiveDate> = history.iter().map(|b| b.date).collect();
+        let new: Vec<DailyBar> = fetched.iter().filter(|b| !known.contains(&b.date)).cloned().collect();
+        let added = merge_bars(&mut history, fetched);
+        if added > 0 {
+            self.storage.upsert_bars(symbol, &new).await?;
+        }
+        tracing::info!("backfilled {added} bars for {symbol}");
+        Ok(BackfillReport { symbol: symbol.to_string(), added, bars: history.len() })
+    }
 
-        let _lock = self.fs_lock.lock().await;
-        let file = File::open(file_path)?;
-        let builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
-        let mut reader = builder.build()?;
-        let mut prices = Vec::new();
-        while let Some(batch) = reader.next() {
-            let batch = batch?;
-            prices.extend(batch_to_closes(&batch));
+    /// Fetch the latest quotes for `symbol` and persist today's bar. The
+    /// first time a symbol is seen its history is backfilled as well.
+    async fn refresh_symbol(&self, sym: &str) -> anyhow::Result<Vec<Quote>> {
+        let quotes = self.fetch_with_retry(sym).await?;
+        if let Some(last) = quotes.last() {
+            let bar = DailyBar::from_quote(last)?;
+            let history = self.storage.bars(sym).await?;
+            if history.is_empty() || self.backfill_pending(sym).await {
+                let result = self.backfill(sym, bar.date).await;
+                if let Err(e) = &result {
+                    tracing::warn!("failed to backfill {sym}, retrying on the next refresh: {e:#}");
+                }
+                let mut status = self.status.write().await;
+                status.entry(sym.to_string()).or_default().backfill_pending = result.is_err();
+            }
+            // the session is still trading; keep the latest snapshot of its bar
+            if history.last() != Some(&bar) {
+                self.storage.upsert_bars(sym, std::slice::from_ref(&bar)).await?;
+            }
         }
-        Ok(prices)
+        Ok(quotes)
+    }
+
+    async fn backfill_pending(&self, symbol: &str) -> bool {
+        self.status.read().await.get(symbol).is_some_and(|s| s.backfill_pending)
+    }
+
+    async fn record_success(&self, symbol: &str) {
+        let mut status = self.status.write().await;
+        let entry = status.entry(symbol.to_string()).or_default();
+        entry.last_success = Some(Utc::now());
+        entry.consecutive_failures = 0;
+    }
+
+    async fn record_failure(&self, symbol: &str, err: &anyhow::Error) {
+        let mut status = self.status.write().await;
+        let entry = status.entry(symbol.to_string()).or_default();
+        entry.last_error = Some(format!("{err:#}"));
+        entry.last_error_at = Some(Utc::now());
+        entry.consecutive_failures += 1;
+    }
+
+    /// Refresh status of every symbol that has been fetched.
+    pub async fn status(&self) -> BTreeMap<String, SymbolStatus> {
+        let status = self.status.read().await;
+        status.iter().map(|(s, st)| (s.clone(), st.clone())).collect()
     }
 
     /// Refresh quotes for all symbols held in `store` and record holdings.
+    ///
+    /// A symbol whose quotes cannot be fetched keeps its previous quotes and
+    /// does not stop the other symbols from being refreshed.
     pub async fn update(
         &self,
         store: &HoldingStore,
         holdings: &crate::portfolio::HoldingsService,
     ) -> anyhow::Result<()> {
         let orders = store.all_orders().await;
-        let symbols: HashSet<_> = orders.iter().map(|o| o.symbol.clone()).collect();
+        let mut symbols: HashSet<_> = orders.iter().map(|o| o.symbol.clone()).collect();
+        symbols.extend(store.book().symbols().await);
 
+        let previous = self.inner.read().await.clone();
+        let mut results = futures::stream::iter(symbols)
+            .map(|sym| async move {
+                let result = self.refresh_symbol(&sym).await;
+                (sym, result)
+            })
+            .buffer_unordered(self.concurrency);
         let mut map = HashMap::new();
-        for sym in symbols {
-            tracing::info!("fetching quotes for {sym}");
-            let quotes = match self.fetcher.fetch_quotes(&sym).await {
-                Ok(q) => {
-                    tracing::info!("received {} quotes for {sym}", q.len());
-                    q
+        while let Some((sym, result)) = results.next().await {
+            match result {
+                Ok(quotes) => {
+                    self.record_success(&sym).await;
+                    map.insert(sym, PriceInfo { history: quotes });
                 }
                 Err(e) => {
-                    tracing::error!("failed to fetch quotes for {sym}: {e}");
-                    return Err(e);
-                }
-            };
-            if let Some(last) = quotes.last() {
-                let date = DateTime::<Utc>::from_timestamp(last.timestamp, 0)
-                    .expect("invalid timestamp")
-                    .date_naive()
-                    .to_string();
-                let close = last.close;
-                let mut history = self.read_symbol_file(&sym).await?;
-                if history.last().map(|h| h.date.as_str()) != Some(date.as_str()) {
-                    history.push(DailyClose { date: date.clone(), close });
-                    self.write_symbol_file(&sym, &history).await?;
+                    tracing::error!("failed to refresh quotes for {sym}: {e:#}");
+                    self.record_failure(&sym, &e).await;
+                    // keep serving the last known quotes for this symbol
+                    if let Some(info) = previous.get(&sym) {
+                        map.insert(sym, info.clone());
+                    }
                 }
             }
-            map.insert(sym, PriceInfo { history: quotes });
         }
 
-        let mut guard = self.inner.write().await;
-        *guard = map.clone();
-        drop(guard);
+        // a replay may have started while fetching; its prices win
+        let _step = self.replay_step.lock().await;
+        if self.replay.read().await.is_some() {
+            tracing::info!("replay started during the update, dropping live quotes");
+            return Ok(());
+        }
+        self.apply_prices(map, store, holdings, Utc::now()).await;
+        Ok(())
+    }
 
-        let now = Utc::now();
+    /// Serve the stored bars of `now`'s day for all symbols held in `store`,
+    /// instead of fetching live quotes, and record holdings at `now`.
+    pub async fn update_at(
+        &self,
+        store: &HoldingStore,
+        holdings: &crate::portfolio::HoldingsService,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let orders = store.all_orders().await;
+        let mut symbols: HashSet<_> = orders.iter().map(|o| o.symbol.clone()).collect();
+        symbols.extend(store.book().symbols().await);
+
+        let mut map = HashMap::new();
+        for sym in symbols {
+            if let Some(info) = self.stored_prices_at(&sym, now.date_naive()).await? {
+                map.insert(sym, info);
+            }
+        }
+        self.apply_prices(map, store, holdings, now).await;
+        Ok(())
+    }
+
+    /// Stored bars of `symbol` up to and including `date`.
+    async fn stored_prices_at(&self, symbol: &str, date: NaiveDate) -> anyhow::Result<Option<PriceInfo>> {
+        let history: Vec<Quote> = self
+            .storage
+            .bars(symbol)
+            .await?
+            .iter()
+            .take_while(|b| b.date <= date)
+            .map(DailyBar::to_quote)
+            .collect();
+        Ok((!history.is_empty()).then_some(PriceInfo { history }))
+    }
+
+    /// Latest stored close of each of `symbols` on or before `date`. Symbols
+    /// without stored bars are left out.
+    pub async fn stored_closes(
+        &self,
+        symbols: impl IntoIterator<Item = String>,
+        date: NaiveDate,
+    ) -> anyhow::Result<HashMap<String, f64>> {
+        let mut closes = HashMap::new();
+        for symbol in symbols {
+            if let Some(price) = self.stored_prices_at(&symbol, date).await?.and_then(|info| info.latest_price()) {
+                closes.insert(symbol, price);
+            }
+        }
+        Ok(closes)
+    }
+
+    /// Publish `map` as the current prices, fill pending orders against them
+    /// and record holdings at `now`.
+    ///
+    /// While replaying, pending orders are left alone and holdings are only
+    /// valued for the event stream, from the orders executed by `now`, and no
+    /// snapshots are kept.
+    async fn apply_prices(
+        &self,
+        map: HashMap<String, PriceInfo>,
+        store: &HoldingStore,
+        holdings: &crate::portfolio::HoldingsService,
+        now: DateTime<Utc>,
+    ) {
         let price_map: HashMap<_, _> = map
             .iter()
             .filter_map(|(s, info)| info.latest_price().map(|p| (s.clone(), p)))
             .collect();
-        for order in orders {
-            if let Some(price) = price_map.get(&order.symbol) {
-                holdings.record(&order, *price, now).await;
+        *self.inner.write().await = map;
+        let replaying = self.replay.read().await.is_some();
+
+        if !replaying {
+            match store.match_pending(&price_map, now).await {
+                Ok(filled) if !filled.is_empty() => tracing::info!("filled {} pending orders", filled.len()),
+                Ok(_) => {}
+                Err(e) => tracing::error!("failed to match pending orders: {e}"),
+            }
+        }
+
+        let mut by_user: HashMap<String, Vec<_>> = HashMap::new();
+        for order in store.all_orders().await.into_iter().filter(|o| o.executed_at <= now) {
+            by_user.entry(order.user.clone()).or_default().push(order);
+        }
+        // sending only fails when nobody is listening
+        let prices = price_map.iter().map(|(s, p)| (s.clone(), *p)).collect();
+        let _ = self.events.send(MarketEvent::Prices { at: now, prices });
+        for (user, orders) in by_user {
+            if !replaying
+                && let Err(e) = holdings.record(&user, &orders, &price_map, now).await
+            {
+                tracing::error!("failed to record holdings for {user}: {e:#}");
             }
+            if let Some(channel) = self.holdings_channel(&user) {
+                let holdings = if replaying {
+                    holdings.value(&user, &orders, &price_map, now)
+                } else {
+                    holdings.for_user(&user).await
+                };
+                let _ = channel.send(MarketEvent::Holdings { at: now, user, holdings });
+            }
+        }
+    }
+
+    /// Current time: the replay clock while a replay session exists,
+    /// otherwise the wall clock.
+    pub async fn now(&self) -> DateTime<Utc> {
+        match &*self.replay.read().await {
+            Some(session) => session.now(),
+            None => Utc::now(),
+        }
+    }
+
+    /// The replay session, if any.
+    pub async fn replay(&self) -> Option<ReplaySession> {
+        self.replay.read().await.clone()
+    }
+
+    /// Start replaying stored bars from `req.start`, replacing any running
+    /// replay.
+    pub async fn start_replay(
+        &self,
+        req: StartReplayRequest,
+        store: &HoldingStore,
+        holdings: &crate::portfolio::HoldingsService,
+    ) -> Result<ReplaySession, ReplayError> {
+        let session = ReplaySession::new(req)?;
+        let _step = self.replay_step.lock().await;
+        let previous = self.replay.write().await.replace(session.clone());
+        if let Err(e) = self.update_at(store, holdings, session.now()).await {
+            // stay in whatever mode we were in, so live quotes keep flowing
+            *self.replay.write().await = previous;
+            return Err(e.into());
+        }
+        self.wake.notify_one();
+        Ok(session)
+    }
+
+    async fn set_replay_status(&self, status: ReplayStatus) -> Result<ReplaySession, ReplayError> {
+        let mut guard = self.replay.write().await;
+        let session = guard.as_mut().ok_or(ReplayError::NotActive)?;
+        if session.status != ReplayStatus::Finished {
+            session.status = status;
         }
+        self.wake.notify_one();
+        Ok(session.clone())
+    }
+
+    /// Stop the replay clock without leaving replay mode.
+    pub async fn pause_replay(&self) -> Result<ReplaySession, ReplayError> {
+        self.set_replay_status(ReplayStatus::Paused).await
+    }
+
+    /// Restart a paused replay clock.
+    pub async fn resume_replay(&self) -> Result<ReplaySession, ReplayError> {
+        self.set_replay_status(ReplayStatus::Running).await
+    }
+
+    /// Advance the replay by one day and update prices and holdings.
+    pub async fn step_replay(
+        &self,
+        store: &HoldingStore,
+        holdings: &crate::portfolio::HoldingsService,
+    ) -> Result<ReplaySession, ReplayError> {
+        let _step = self.replay_step.lock().await;
+        let session = {
+            let mut guard = self.replay.write().await;
+            let session = guard.as_mut().ok_or(ReplayError::NotActive)?;
+            session.advance();
+            session.clone()
+        };
+        self.update_at(store, holdings, session.now()).await?;
+        self.wake.notify_one();
+        Ok(session)
+    }
+
+    /// Leave replay mode and go back to live quotes.
+    pub async fn reset_replay(&self) -> Result<(), ReplayError> {
+        let _step = self.replay_step.lock().await;
+        self.replay.write().await.take().ok_or(ReplayError::NotActive)?;
+        self.inner.write().await.clear();
+        self.wake.notify_one();
         Ok(())
     }
 
@@ -206,6 +925,105 @@ impl MarketData {
             .collect()
     }
 
+    /// Registry of instruments that orders are validated against.
+    pub fn instruments(&self) -> &InstrumentRegistry {
+        &self.instruments
+    }
+
+    /// Look `symbol` up in the registry, asking the quote provider and
+    /// caching the answer for symbols not seen before. Symbols the provider
+    /// does not know are remembered for a short while as well.
+    pub async fn resolve_instrument(&self, symbol: &str) -> anyhow::Result<Option<Instrument>> {
+        if let Some(instrument) = self.instruments.get(symbol).await {
+            return Ok(Some(instrument));
+        }
+        if self.known_unknown(symbol) {
+            return Ok(None);
+        }
+        let found = self.request(self.fetcher.lookup_instrument(symbol)).await?;
+        match &found {
+            Some(instrument) => self.instruments.insert(instrument.clone()).await,
+            None if !self.unknown_symbol_ttl.is_zero() => {
+                self.unknown_symbols.lock().unwrap().insert(symbol.to_string(), std::time::Instant::now());
+            }
+            None => {}
+        }
+        Ok(found)
+    }
+
+    /// Whether the provider said it did not know `symbol` within the last
+    /// `unknown_symbol_ttl`. Forgets the symbols looked up before that.
+    fn known_unknown(&self, symbol: &str) -> bool {
+        let mut unknown = self.unknown_symbols.lock().unwrap();
+        unknown.retain(|_, at| at.elapsed() < self.unknown_symbol_ttl);
+        unknown.contains_key(symbol)
+    }
+
+    /// Latest price for `symbol` to fill a market order at `now`.
+    ///
+    /// Symbols that are not tracked yet, or whose cached quote has gone
+    /// stale, are fetched on demand.
+    pub async fn market_price(&self, symbol: &str, now: DateTime<Utc>) -> Result<f64, QuoteError> {
+        let cached = {
+            let guard = self.inner.read().await;
+            guard.get(symbol).and_then(|info| info.history.last().cloned())
+        };
+        let replaying = self.replay.read().await.is_some();
+        let fresh = |q: &Quote| {
+            DateTime::<Utc>::from_timestamp(q.timestamp, 0).is_some_and(|at| now - at <= self.max_quote_age)
+        };
+        let quote = match cached {
+            Some(q) if replaying || fresh(&q) => q,
+            None if replaying => {
+                let info = self
+                    .stored_prices_at(symbol, now.date_naive())
+                    .await?
+                    .ok_or_else(|| QuoteError::Missing(symbol.to_string()))?;
+                let last = info.history.last().cloned().expect("stored prices are not empty");
+                self.inner.write().await.insert(symbol.to_string(), info);
+                last
+            }
+            _ => {
+                let quotes = self.request(self.fetcher.fetch_quotes(symbol)).await?;
+                let last = quotes.last().cloned().ok_or_else(|| QuoteError::Missing(symbol.to_string()))?;
+                let mut guard = self.inner.write().await;
+                guard.insert(symbol.to_string(), PriceInfo { history: quotes });
+                last
+            }
+        };
+        let at = DateTime::<Utc>::from_timestamp(quote.timestamp, 0)
+            .ok_or_else(|| QuoteError::Missing(symbol.to_string()))?;
+        if now - at > self.max_quote_age {
+            return Err(QuoteError::Stale { symbol: symbol.to_string(), at });
+        }
+        Ok(quote.close)
+    }
+
+    /// Stored daily bars for `symbol`, oldest first.
+    pub async fn bars(&self, symbol: &str) -> anyhow::Result<Vec<DailyBar>> {
+        self.storage.bars(symbol).await
+    }
+
+    /// Stored bars for `symbol` between `from` and `to` inclusive, resampled
+    /// to `interval`, or `None` if nothing is stored for the symbol.
+    pub async fn history(
+        &self,
+        symbol: &str,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        interval: Interval,
+    ) -> anyhow::Result<Option<Vec<DailyBar>>> {
+        let bars = self.storage.bars(symbol).await?;
+        if bars.is_empty() {
+            return Ok(None);
+        }
+        let in_range: Vec<DailyBar> = bars
+            .into_iter()
+            .filter(|b| from.is_none_or(|from| b.date >= from) && to.is_none_or(|to| b.date <= to))
+            .collect();
+        Ok(Some(resample(&in_range, interval)))
+    }
+
     /// Get list of currently tracked symbols.
     pub async fn symbols(&self) -> Vec<String> {
         let guard = self.inner.read().await;
@@ -218,13 +1036,39 @@ impl MarketData {
         store: HoldingStore,
         holdings: crate::portfolio::HoldingsService,
     ) {
-        use tokio::time::{sleep, Duration};
+        use tokio::time::Duration;
         loop {
-            tracing::info!("running market data update");
-            if let Err(e) = self.update(&store, &holdings).await {
-                tracing::error!("market data update failed: {e}");
+            match self.replay().await {
+                None => {
+                    tracing::info!("running market data update");
+                    if let Err(e) = self.update(&store, &holdings).await {
+                        tracing::error!("market data update failed: {e}");
+                    }
+                    self.wait(self.update_interval).await;
+                }
+                Some(session) if session.status == ReplayStatus::Running => {
+                    if !self.wait(Duration::from_secs(session.step_secs)).await {
+                        // the replay was changed while waiting
+                        continue;
+                    }
+                    match self.step_replay(&store, &holdings).await {
+                        Ok(session) => tracing::info!("replaying {}", session.current),
+                        Err(e) => tracing::error!("replay step failed: {e}"),
+                    }
+                }
+                Some(_) => {
+                    self.wait(self.update_interval).await;
+                }
             }
-            sleep(Duration::from_secs(UPDATE_INTERVAL_SECS)).await;
+        }
+    }
+
+    /// Sleep for `duration` unless woken by a replay change first. Returns
+    /// whether the full duration elapsed.
+    async fn wait(&self, duration: std::time::Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = self.wake.notified() => false,
         }
     }
 }
@@ -232,39 +1076,10 @@ impl MarketData {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::holdings::Order;
+    use crate::holdings::{Order, Side};
+    use crate::testing::{daily_quote, live_quote, quote, TestFetcher};
     use tempfile::tempdir;
 
-    struct MockFetcher {
-        data: HashMap<String, Vec<Quote>>,
-    }
-
-    #[async_trait]
-    impl QuoteFetcher for MockFetcher {
-        async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
-            Ok(self.data.get(symbol).cloned().unwrap_or_default())
-        }
-    }
-
-    fn sample_quote(price: f64) -> Quote {
-        Quote { timestamp: 0, open: price, high: price, low: price, volume: 0, close: price, adjclose: price }
-    }
-
-    struct SeqFetcher {
-        data: std::sync::Mutex<std::collections::VecDeque<Vec<Quote>>>,
-    }
-
-    #[async_trait]
-    impl QuoteFetcher for SeqFetcher {
-        async fn fetch_quotes(&self, _symbol: &str) -> anyhow::Result<Vec<Quote>> {
-            Ok(self
-                .data
-                .lock()
-                .unwrap()
-                .pop_front()
-                .unwrap_or_default())
-        }
-    }
 
     #[tokio::test]
     async fn test_update_prices() {
@@ -272,23 +1087,20 @@ mod tests {
         let store = HoldingStore::new(dir.path().to_path_buf());
 
         store
-            .add_order(Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 1.0 })
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0))
             .await
             .unwrap();
         store
-            .add_order(Order { user: "bob".into(), symbol: "MSFT".into(), amount: 1, price: 2.0 })
+            .add_order(Order::new("bob", "MSFT", Side::Buy, 1, 2.0))
             .await
             .unwrap();
         // duplicate symbol
         store
-            .add_order(Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 1.0 })
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0))
             .await
             .unwrap();
 
-        let mut quotes = HashMap::new();
-        quotes.insert("AAPL".into(), vec![sample_quote(10.0)]);
-        quotes.insert("MSFT".into(), vec![sample_quote(20.0)]);
-        let fetcher = Arc::new(MockFetcher { data: quotes });
+        let fetcher = Arc::new(TestFetcher::new().with_symbol_quote("AAPL", quote(10.0)).with_symbol_quote("MSFT", quote(20.0)));
         let market_dir = dir.path().join("market");
         let market = MarketData::new(fetcher, market_dir);
         let holdings = crate::portfolio::HoldingsService::new();
@@ -303,18 +1115,238 @@ mod tests {
         assert_eq!(symbols, vec!["AAPL", "MSFT"]);
     }
 
+    fn no_delay(attempts: u32) -> RetryPolicy {
+        RetryPolicy { attempts, base_delay: std::time::Duration::ZERO, ..RetryPolicy::default() }
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        use std::time::Duration;
+
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(10), Duration::from_secs(30));
+        assert_eq!(policy.delay(40), Duration::from_secs(30));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn failing_symbol_does_not_block_others() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        store.add_order(Order::new("alice", "GONE", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let fetcher = TestFetcher::new().with_quote(quote(10.0)).with_failing_symbol("GONE");
+        let market = MarketData::new(Arc::new(fetcher), dir.path().join("market")).with_retry_policy(no_delay(2));
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+        market.update(&store, &holdings).await.unwrap();
+
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+        let status = market.status().await;
+        assert_eq!(status["AAPL"].consecutive_failures, 0);
+        assert!(status["AAPL"].last_success.is_some());
+        assert_eq!(status["GONE"].consecutive_failures, 2);
+        assert_eq!(status["GONE"].last_error.as_deref(), Some("no data for GONE"));
+        assert!(status["GONE"].last_success.is_none());
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let fetcher = Arc::new(TestFetcher::new().with_quote(quote(10.0)));
+        fetcher.fail_next(2);
+        let market = MarketData::new(fetcher.clone(), dir.path().join("market")).with_retry_policy(no_delay(3));
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+        assert_eq!(market.status().await["AAPL"].consecutive_failures, 0);
+
+        // keeps the last known price when every attempt fails
+        fetcher.fail_next(5);
+        market.update(&store, &holdings).await.unwrap();
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+        assert_eq!(market.status().await["AAPL"].consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn update_fetches_concurrently_up_to_limit() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        for sym in ["A", "B", "C", "D", "E", "F"] {
+            store.add_order(Order::new("alice", sym, Side::Buy, 1, 1.0)).await.unwrap();
+        }
+
+        let fetcher = Arc::new(TestFetcher::new().with_quote(quote(10.0)).with_delay(std::time::Duration::from_millis(50)));
+        let market = MarketData::new(fetcher.clone(), dir.path().join("market")).with_concurrency(3);
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+
+        assert_eq!(market.prices().await.len(), 6);
+        assert_eq!(fetcher.max_in_flight(), 3);
+    }
+
+    #[tokio::test]
+    async fn slow_fetch_times_out() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let fetcher = Arc::new(TestFetcher::new().with_quote(quote(10.0)).with_delay(std::time::Duration::from_secs(5)));
+        let market = MarketData::new(fetcher, dir.path().join("market"))
+            .with_retry_policy(no_delay(1))
+            .with_fetch_timeout(std::time::Duration::from_millis(20));
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+
+        assert!(market.prices().await.is_empty());
+        let status = market.status().await;
+        assert!(status["AAPL"].last_error.as_deref().unwrap().starts_with("timed out"));
+    }
+
+    #[test]
+    fn rate_limiter_rejects_unusable_rates() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e-300] {
+            assert!(RateLimiter::new(rate).is_err(), "{rate}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_spaces_out_requests() {
+        let limiter = Arc::new(RateLimiter::new(20.0).unwrap());
+        let started = std::time::Instant::now();
+        let calls = (0..4).map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.acquire().await })
+        });
+        for call in calls.collect::<Vec<_>>() {
+            call.await.unwrap();
+        }
+        // the first request goes out immediately, the other three 50ms apart
+        assert!(started.elapsed() >= std::time::Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn waiting_for_the_rate_limit_does_not_time_out() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        for sym in ["A", "B", "C", "D"] {
+            store.add_order(Order::new("alice", sym, Side::Buy, 1, 1.0)).await.unwrap();
+        }
+
+        let fetcher = Arc::new(TestFetcher::new().with_quote(quote(10.0)));
+        // every request waits well past the timeout for its turn
+        let market = MarketData::new(fetcher, dir.path().join("market"))
+            .with_retry_policy(no_delay(1))
+            .with_fetch_timeout(std::time::Duration::from_millis(20))
+            .with_rate_limit(RateLimiter::new(20.0).unwrap());
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+
+        assert_eq!(market.prices().await.len(), 4);
+        assert!(market.status().await.values().all(|s| s.last_error.is_none()));
+    }
+
+    #[tokio::test]
+    async fn update_fills_pending_orders() {
+        use crate::orderbook::{OrderType, PendingOrder, TimeInForce};
+
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let pending = PendingOrder::new("alice".into(), "AAPL".into(), Side::Buy, 2, OrderType::Limit, Some(11.0), None, TimeInForce::Gtc, Utc::now()).unwrap();
+        store.book().place(pending).await.unwrap();
+
+        let market = MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), dir.path().join("market"));
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+
+        let orders = store.orders_for_user("alice").await.unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].price, 10.0);
+        assert_eq!(holdings.for_user("alice").await[0].quantity, 2);
+    }
+
+    #[tokio::test]
+    async fn resolve_instrument_prefers_registry_then_provider() {
+        let dir = tempdir().unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new().with_symbol_quote("MSFT", quote(10.0))), dir.path().to_path_buf());
+        market
+            .instruments()
+            .insert(Instrument { name: "Apple Inc.".into(), ..Instrument::bare("AAPL") })
+            .await;
+
+        assert_eq!(market.resolve_instrument("AAPL").await.unwrap().unwrap().name, "Apple Inc.");
+        assert_eq!(market.resolve_instrument("MSFT").await.unwrap(), Some(Instrument::bare("MSFT")));
+        assert!(market.instruments().get("MSFT").await.is_some());
+        assert_eq!(market.resolve_instrument("APPL").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn resolve_instrument_remembers_unknown_symbols() {
+        let dir = tempdir().unwrap();
+        let fetcher = Arc::new(TestFetcher::new().with_symbol_quote("MSFT", quote(10.0)));
+        let market = MarketData::new(fetcher.clone(), dir.path().to_path_buf());
+
+        assert_eq!(market.resolve_instrument("APPL").await.unwrap(), None);
+        // answered without asking the provider, which would fail now
+        fetcher.fail_next(1);
+        assert_eq!(market.resolve_instrument("APPL").await.unwrap(), None);
+        assert!(market.resolve_instrument("MSFT").await.is_err());
+
+        let market = market.with_unknown_symbol_ttl(std::time::Duration::ZERO);
+        assert_eq!(market.resolve_instrument("APPL").await.unwrap(), None);
+        fetcher.fail_next(1);
+        assert!(market.resolve_instrument("APPL").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn market_price_fetches_untracked_symbols_and_rejects_stale_quotes() {
+        let dir = tempdir().unwrap();
+        let now = Utc::now();
+        let fetcher = TestFetcher::new()
+            .with_symbol_quote("AAPL", Quote { timestamp: now.timestamp() - 60, ..quote(10.0) })
+            .with_symbol_quote("OLD", quote(5.0));
+        let market = MarketData::new(Arc::new(fetcher), dir.path().to_path_buf());
+
+        assert_eq!(market.market_price("AAPL", now).await.unwrap(), 10.0);
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+        assert!(matches!(market.market_price("OLD", now).await, Err(QuoteError::Stale { .. })));
+        assert!(matches!(market.market_price("NONE", now).await, Err(QuoteError::Missing(_))));
+
+        let lenient = market.with_max_quote_age(chrono::Duration::days(365 * 100));
+        assert_eq!(lenient.market_price("OLD", now).await.unwrap(), 5.0);
+    }
+
+    #[tokio::test]
+    async fn market_price_refetches_stale_cached_quotes() {
+        let dir = tempdir().unwrap();
+        let now = Utc::now();
+        let fetcher = Arc::new(TestFetcher::new().with_quote(quote(5.0)).with_quote(live_quote(10.0)));
+        let market = MarketData::new(fetcher, dir.path().to_path_buf());
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        market.update(&store, &crate::portfolio::HoldingsService::new()).await.unwrap();
+        assert_eq!(market.prices().await.get("AAPL"), Some(&5.0));
+
+        assert_eq!(market.market_price("AAPL", now).await.unwrap(), 10.0);
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+    }
+
     #[tokio::test]
     async fn test_persist_daily_closes() {
         let dir = tempdir().unwrap();
         let store = HoldingStore::new(dir.path().to_path_buf());
         store
-            .add_order(Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 1.0 })
+            .add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0))
             .await
             .unwrap();
 
-        let day1 = vec![Quote { timestamp: 0, open: 0.0, high: 0.0, low: 0.0, volume: 0, close: 10.0, adjclose: 10.0 }];
-        let day2 = vec![Quote { timestamp: 86_400, open: 0.0, high: 0.0, low: 0.0, volume: 0, close: 12.0, adjclose: 12.0 }];
-        let fetcher = Arc::new(SeqFetcher { data: std::sync::Mutex::new(std::collections::VecDeque::from(vec![day1, day2])) });
+        let fetcher = Arc::new(TestFetcher::new().with_quote(daily_quote(0, 10.0)).with_quote(daily_quote(1, 12.0)));
         let market_dir = dir.path().join("market");
         let market = MarketData::new(fetcher, market_dir.clone());
         let holdings = crate::portfolio::HoldingsService::new();
@@ -322,9 +1354,345 @@ mod tests {
         market.update(&store, &holdings).await.unwrap();
         market.update(&store, &holdings).await.unwrap();
 
-        let history = market.read_symbol_file("AAPL").await.unwrap();
+        let history = market.bars("AAPL").await.unwrap();
         assert_eq!(history.len(), 2);
         assert_eq!(history[0].close, 10.0);
         assert_eq!(history[1].close, 12.0);
     }
+
+    #[tokio::test]
+    async fn test_persist_daily_bars() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let morning = Quote { timestamp: 3_600, open: 9.0, high: 10.5, low: 8.5, volume: 100, close: 10.0, adjclose: 9.9 };
+        let evening = Quote { timestamp: 7_200, open: 9.0, high: 11.0, low: 8.5, volume: 250, close: 10.8, adjclose: 10.7 };
+        let fetcher = Arc::new(TestFetcher::new().with_quote(morning).with_quote(evening));
+        let market = MarketData::new(fetcher, dir.path().join("market"));
+        let holdings = crate::portfolio::HoldingsService::new();
+
+        market.update(&store, &holdings).await.unwrap();
+        market.update(&store, &holdings).await.unwrap();
+
+        let bars = market.bars("AAPL").await.unwrap();
+        assert_eq!(
+            bars,
+            vec![DailyBar {
+                date: NaiveDate::from_ymd_opt(1970, 1, 1).unwrap(),
+                open: 9.0,
+                high: 11.0,
+                low: 8.5,
+                close: 10.8,
+                adjclose: 10.7,
+                volume: 250,
+            }]
+        );
+    }
+
+    fn bar(date: &str, open: f64, high: f64, low: f64, close: f64, volume: u64) -> DailyBar {
+        DailyBar { date: date.parse().unwrap(), open, high, low, close, adjclose: close, volume }
+    }
+
+    #[test]
+    fn resample_weekly_and_monthly() {
+        let bars = vec![
+            bar("2024-01-29", 10.0, 11.0, 9.0, 10.5, 100),
+            bar("2024-01-31", 10.5, 12.0, 10.0, 11.5, 200),
+            bar("2024-02-01", 11.5, 11.8, 8.0, 9.0, 300),
+            bar("2024-02-05", 9.0, 9.5, 8.5, 9.2, 400),
+        ];
+
+        assert_eq!(resample(&bars, Interval::Daily), bars);
+        assert_eq!(
+            resample(&bars, Interval::Weekly),
+            vec![bar("2024-01-29", 10.0, 12.0, 8.0, 9.0, 600), bar("2024-02-05", 9.0, 9.5, 8.5, 9.2, 400)]
+        );
+        assert_eq!(
+            resample(&bars, Interval::Monthly),
+            vec![bar("2024-01-29", 10.0, 12.0, 9.0, 11.5, 300), bar("2024-02-01", 11.5, 11.8, 8.0, 9.2, 700)]
+        );
+    }
+
+    /// Serves a fixed daily history and a live quote on its last day.
+    fn history_fetcher() -> TestFetcher {
+        TestFetcher::new()
+            .with_history(vec![daily_quote(0, 10.0), daily_quote(1, 11.0), daily_quote(2, 12.0)])
+            .with_quote(daily_quote(2, 99.0))
+    }
+
+    #[tokio::test]
+    async fn new_symbol_is_backfilled_on_first_update() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let fetcher = Arc::new(history_fetcher());
+        let market = MarketData::new(fetcher.clone(), dir.path().join("market")).with_backfill_years(2);
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+        market.update(&store, &holdings).await.unwrap();
+
+        let closes: Vec<f64> = market.bars("AAPL").await.unwrap().iter().map(|b| b.close).collect();
+        // the live quote replaces the backfilled bar for the current day
+        assert_eq!(closes, vec![10.0, 11.0, 99.0]);
+        let day = |d| NaiveDate::from_ymd_opt(1970, 1, d).unwrap();
+        assert_eq!(fetcher.history_requests(), vec![(NaiveDate::from_ymd_opt(1968, 1, 3).unwrap(), day(3))]);
+    }
+
+    #[tokio::test]
+    async fn failed_backfill_is_retried() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+
+        let fetcher = Arc::new(history_fetcher());
+        fetcher.fail_next_history(1);
+        let market = MarketData::new(fetcher.clone(), dir.path().join("market"));
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+        assert!(market.status().await["AAPL"].backfill_pending);
+        assert_eq!(market.bars("AAPL").await.unwrap().len(), 1);
+
+        market.update(&store, &holdings).await.unwrap();
+        assert!(!market.status().await["AAPL"].backfill_pending);
+        assert_eq!(market.bars("AAPL").await.unwrap().len(), 3);
+        market.update(&store, &holdings).await.unwrap();
+        assert_eq!(fetcher.history_requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn hung_backfill_times_out() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new().with_quote(daily_quote(2, 12.0)).with_hanging_history()), dir.path().join("market"))
+            .with_fetch_timeout(std::time::Duration::from_millis(50));
+        let holdings = crate::portfolio::HoldingsService::new();
+        market.update(&store, &holdings).await.unwrap();
+        assert!(market.status().await["AAPL"].backfill_pending);
+        assert_eq!(market.prices().await.get("AAPL"), Some(&12.0));
+    }
+
+    #[tokio::test]
+    async fn backfill_keeps_stored_dates() {
+        let dir = tempdir().unwrap();
+        let fetcher = Arc::new(history_fetcher());
+        let market = MarketData::new(fetcher, dir.path().to_path_buf());
+        let stored = DailyBar::from_quote(&daily_quote(1, 50.0)).unwrap();
+        market.storage.upsert_bars("AAPL", std::slice::from_ref(&stored)).await.unwrap();
+
+        let today = NaiveDate::from_ymd_opt(1970, 1, 3).unwrap();
+        let report = market.backfill("AAPL", today).await.unwrap();
+        assert_eq!(report, BackfillReport { symbol: "AAPL".into(), added: 2, bars: 3 });
+        let closes: Vec<f64> = market.bars("AAPL").await.unwrap().iter().map(|b| b.close).collect();
+        assert_eq!(closes, vec![10.0, 50.0, 12.0]);
+
+        let again = market.backfill("AAPL", today).await.unwrap();
+        assert_eq!(again.added, 0);
+    }
+
+    #[tokio::test]
+    async fn replay_serves_stored_bars_at_virtual_time() {
+        use crate::orderbook::{OrderType, PendingOrder, TimeInForce};
+
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let mut before = Order::new("alice", "AAPL", Side::Buy, 1, 1.0);
+        before.executed_at = "2023-12-29T21:00:00Z".parse().unwrap();
+        store.add_order(before).await.unwrap();
+        // placed after the replayed period, so not held yet while replaying it
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 5, 1.0)).await.unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new()), dir.path().join("market"));
+        let bars: Vec<DailyBar> = [10.0, 8.0, 12.0]
+            .iter()
+            .enumerate()
+            .map(|(i, c)| bar(&format!("2024-01-0{}", i + 2), *c, *c, *c, *c, 0))
+            .collect();
+        market.storage.upsert_bars("AAPL", &bars).await.unwrap();
+        let holdings = crate::portfolio::HoldingsService::new();
+
+        let start = StartReplayRequest { start: "2024-01-01".parse().unwrap(), end: "2024-01-04".parse().unwrap(), step_secs: None };
+        let mut valuations = market.subscribe_holdings("alice");
+        let session = market.start_replay(start, &store, &holdings).await.unwrap();
+        // nothing stored yet on the first day
+        assert!(market.prices().await.is_empty());
+        assert_eq!(market.now().await, session.now());
+
+        market.step_replay(&store, &holdings).await.unwrap();
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+        let now = market.now().await;
+        assert_eq!(market.market_price("AAPL", now).await.unwrap(), 10.0);
+
+        let limit = PendingOrder::new("alice".into(), "AAPL".into(), Side::Buy, 2, OrderType::Limit, Some(9.0), None, TimeInForce::Gtc, now).unwrap();
+        store.book().place(limit).await.unwrap();
+        let paused = market.pause_replay().await.unwrap();
+        assert_eq!(paused.status, ReplayStatus::Paused);
+        let stepped = market.step_replay(&store, &holdings).await.unwrap();
+        assert_eq!(stepped.current, "2024-01-03".parse::<NaiveDate>().unwrap());
+        // trading is paused during a replay, even though 8 is below the limit
+        assert_eq!(store.orders_for_user("alice").await.unwrap().len(), 2);
+        assert_eq!(store.book().for_user("alice").await.unwrap().len(), 1);
+        let valued = loop {
+            let MarketEvent::Holdings { at, holdings, .. } = valuations.recv().await.unwrap() else { continue };
+            if at == stepped.now() {
+                break holdings;
+            }
+        };
+        assert_eq!((valued[0].quantity, valued[0].current_price), (1, 8.0));
+        // replayed valuations are streamed but never kept as snapshots
+        assert!(holdings.for_user("alice").await.is_empty());
+
+        let last = market.step_replay(&store, &holdings).await.unwrap();
+        assert_eq!(last.status, ReplayStatus::Finished);
+        assert_eq!(market.prices().await.get("AAPL"), Some(&12.0));
+
+        market.reset_replay().await.unwrap();
+        assert!(market.replay().await.is_none());
+        assert!(market.prices().await.is_empty());
+        assert!(matches!(market.reset_replay().await, Err(ReplayError::NotActive)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_replay_steps_publish_days_in_order() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new()), dir.path().join("market"));
+        let bars: Vec<DailyBar> =
+            (2..=9).map(|d| bar(&format!("2024-01-0{d}"), d as f64, d as f64, d as f64, d as f64, 0)).collect();
+        market.storage.upsert_bars("AAPL", &bars).await.unwrap();
+        let holdings = crate::portfolio::HoldingsService::new();
+        let start = StartReplayRequest { start: "2024-01-01".parse().unwrap(), end: "2024-01-09".parse().unwrap(), step_secs: None };
+        market.start_replay(start, &store, &holdings).await.unwrap();
+
+        let steps = (0..4).map(|_| market.step_replay(&store, &holdings));
+        for stepped in futures::future::join_all(steps).await {
+            stepped.unwrap();
+        }
+        let session = market.replay().await.unwrap();
+        assert_eq!(session.current, "2024-01-05".parse::<NaiveDate>().unwrap());
+        assert_eq!(market.prices().await.get("AAPL"), Some(&5.0));
+    }
+
+    #[tokio::test]
+    async fn replay_started_during_live_update_wins() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        let mut held = Order::new("alice", "AAPL", Side::Buy, 1, 1.0);
+        held.executed_at = "2023-12-29T21:00:00Z".parse().unwrap();
+        store.add_order(held).await.unwrap();
+        let gate = Arc::new(tokio::sync::Notify::new());
+        let market = Arc::new(MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(99.0)).with_gate(gate.clone())), dir.path().join("market")));
+        market.storage.upsert_bars("AAPL", &[bar("2024-01-02", 10.0, 10.0, 10.0, 10.0, 0)]).await.unwrap();
+        let holdings = crate::portfolio::HoldingsService::new();
+
+        let live = tokio::spawn({
+            let (market, store, holdings) = (market.clone(), store.clone(), holdings.clone());
+            async move { market.update(&store, &holdings).await }
+        });
+        tokio::task::yield_now().await;
+        let start = StartReplayRequest { start: "2024-01-02".parse().unwrap(), end: "2024-01-04".parse().unwrap(), step_secs: None };
+        market.start_replay(start, &store, &holdings).await.unwrap();
+        gate.notify_one();
+        live.await.unwrap().unwrap();
+
+        assert_eq!(market.prices().await.get("AAPL"), Some(&10.0));
+        assert!(holdings.for_user("alice").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_replay_start_stays_live() {
+        struct BrokenStorage;
+        #[async_trait]
+        impl PriceStorage for BrokenStorage {
+            async fn bars(&self, _symbol: &str) -> anyhow::Result<Vec<DailyBar>> {
+                anyhow::bail!("storage unavailable")
+            }
+            async fn upsert_bars(&self, _symbol: &str, _bars: &[DailyBar]) -> anyhow::Result<()> {
+                anyhow::bail!("storage unavailable")
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 1, 1.0)).await.unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new()), dir.path().join("market"))
+            .with_storage(Arc::new(BrokenStorage));
+        let holdings = crate::portfolio::HoldingsService::new();
+
+        let start = StartReplayRequest { start: "2024-01-01".parse().unwrap(), end: "2024-01-04".parse().unwrap(), step_secs: None };
+        assert!(market.start_replay(start, &store, &holdings).await.is_err());
+        assert!(market.replay().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn update_publishes_events() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 2, 1.0)).await.unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), dir.path().join("market"));
+        let holdings = crate::portfolio::HoldingsService::new();
+        let mut events = market.subscribe();
+        let mut alice = market.subscribe_holdings("alice");
+        let mut bob = market.subscribe_holdings("bob");
+
+        market.update(&store, &holdings).await.unwrap();
+
+        let prices = events.recv().await.unwrap();
+        assert!(matches!(&prices, MarketEvent::Prices { prices, .. } if prices["AAPL"] == 10.0));
+        assert!(events.try_recv().is_err(), "holdings stay off the shared channel");
+        let MarketEvent::Holdings { user, holdings, .. } = alice.recv().await.unwrap() else { panic!("expected holdings") };
+        assert_eq!(user, "alice");
+        assert_eq!(holdings[0].unrealised_pnl, 18.0);
+        assert!(bob.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn holdings_channels_are_dropped_with_their_last_subscriber() {
+        let dir = tempdir().unwrap();
+        let store = HoldingStore::new(dir.path().to_path_buf());
+        store.add_order(Order::new("alice", "AAPL", Side::Buy, 2, 1.0)).await.unwrap();
+        let market = MarketData::new(Arc::new(TestFetcher::new().with_quote(quote(10.0))), dir.path().join("market"));
+        let holdings = crate::portfolio::HoldingsService::new();
+        drop(market.subscribe_holdings("alice"));
+        drop(market.subscribe_holdings("bob"));
+
+        market.update(&store, &holdings).await.unwrap();
+
+        assert!(market.holding_events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reads_legacy_close_files() {
+        use arrow_array::{Float64Array, RecordBatch, StringArray};
+        use arrow_schema::{DataType, Field, Schema};
+        use parquet::arrow::ArrowWriter;
+
+        let dir = tempdir().unwrap();
+        let sym_dir = dir.path().join("AAPL");
+        std::fs::create_dir_all(&sym_dir).unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("date", DataType::Utf8, false),
+            Field::new("close", DataType::Float64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["2024-01-02", "2024-01-03"])),
+                Arc::new(Float64Array::from(vec![10.0, 12.0])),
+            ],
+        )
+        .unwrap();
+        let file = std::fs::File::create(sym_dir.join("prices.parquet")).unwrap();
+        let mut writer = ArrowWriter::try_new(file, schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let market = MarketData::new(Arc::new(TestFetcher::new()), dir.path().to_path_buf());
+        let bars = market.bars("AAPL").await.unwrap();
+        assert_eq!(bars.len(), 2);
+        assert_eq!(bars[1].date, NaiveDate::from_ymd_opt(2024, 1, 3).unwrap());
+        assert_eq!((bars[1].open, bars[1].low, bars[1].close, bars[1].volume), (12.0, 12.0, 12.0, 0));
+    }
 }
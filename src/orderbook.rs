@@ -0,0 +1,498 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use arrow_array::Array;
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::holdings::Side;
+use crate::storage::files::{blocking, entity_dir, users_with_files, write_atomically, KeyedLocks};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    /// Filled immediately at the latest quote.
+    #[default]
+    Market,
+    /// Filled once the price is at or better than `limit_price`.
+    Limit,
+    /// Becomes a market order once the price crosses `stop_price`.
+    Stop,
+    /// Becomes a limit order once the price crosses `stop_price`.
+    StopLimit,
+}
+
+impl OrderType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderType::Market => "market",
+            OrderType::Limit => "limit",
+            OrderType::Stop => "stop",
+            OrderType::StopLimit => "stop_limit",
+        }
+    }
+}
+
+impl std::str::FromStr for OrderType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "market" => Ok(OrderType::Market),
+            "limit" => Ok(OrderType::Limit),
+            "stop" => Ok(OrderType::Stop),
+            "stop_limit" => Ok(OrderType::StopLimit),
+            other => Err(anyhow::anyhow!("unknown order type {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce {
+    /// Expires at the end of the UTC day it was placed on.
+    #[default]
+    Day,
+    /// Stays open until filled or cancelled.
+    Gtc,
+}
+
+impl TimeInForce {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeInForce::Day => "day",
+            TimeInForce::Gtc => "gtc",
+        }
+    }
+}
+
+impl std::str::FromStr for TimeInForce {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(TimeInForce::Day),
+            "gtc" => Ok(TimeInForce::Gtc),
+            other => Err(anyhow::anyhow!("unknown time in force {other}")),
+        }
+    }
+}
+
+/// Limit, stop or stop-limit order waiting for the market to reach its price.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PendingOrder {
+    pub id: String,
+    pub user: String,
+    pub symbol: String,
+    pub side: Side,
+    pub amount: i64,
+    pub order_type: OrderType,
+    pub limit_price: Option<f64>,
+    pub stop_price: Option<f64>,
+    pub time_in_force: TimeInForce,
+    pub created_at: DateTime<Utc>,
+    /// Whether the stop price of a stop-limit order has been crossed.
+    pub triggered: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl PendingOrder {
+    /// Build a pending order, checking the prices its type needs are present.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        user: String,
+        symbol: String,
+        side: Side,
+        amount: i64,
+        order_type: OrderType,
+        limit_price: Option<f64>,
+        stop_price: Option<f64>,
+        time_in_force: TimeInForce,
+        now: DateTime<Utc>,
+    ) -> Result<Self, String> {
+        let needs_limit = matches!(order_type, OrderType::Limit | OrderType::StopLimit);
+        let needs_stop = matches!(order_type, OrderType::Stop | OrderType::StopLimit);
+        if order_type == OrderType::Market {
+            return Err("market orders are not queued".into());
+        }
+        if amount <= 0 {
+            return Err(format!("amount must be positive, got {amount}"));
+        }
+        for (name, needed, price) in [("limit_price", needs_limit, limit_price), ("stop_price", needs_stop, stop_price)] {
+            match (needed, price) {
+                (true, None) => return Err(format!("{} orders require {name}", order_type.as_str())),
+                (true, Some(p)) if !p.is_finite() || p <= 0.0 => {
+                    return Err(format!("{name} must be positive, got {p}"));
+                }
+                (false, Some(_)) => return Err(format!("{} orders do not take {name}", order_type.as_str())),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            id: Uuid::new_v4().to_string(),
+            user,
+            symbol,
+            side,
+            amount,
+            order_type,
+            limit_price,
+            stop_price,
+            time_in_force,
+            created_at: now.trunc_subsecs(6),
+            triggered: false,
+            note: None,
+        })
+    }
+
+    /// Price used to check the order against league rules when it is placed.
+    pub fn reference_price(&self) -> f64 {
+        self.limit_price.or(self.stop_price).unwrap_or_default()
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.time_in_force == TimeInForce::Day && now.date_naive() > self.created_at.date_naive()
+    }
+
+    fn stop_crossed(&self, price: f64) -> bool {
+        let stop = self.stop_price.unwrap_or_default();
+        match self.side {
+            Side::Buy => price >= stop,
+            Side::Sell => price <= stop,
+        }
+    }
+
+    fn limit_reached(&self, price: f64) -> bool {
+        let limit = self.limit_price.unwrap_or_default();
+        match self.side {
+            Side::Buy => price <= limit,
+            Side::Sell => price >= limit,
+        }
+    }
+
+    /// Evaluate the order against `price`, returning whether it should fill.
+    /// Crossing the stop of a stop-limit order marks it as triggered.
+    fn evaluate(&mut self, price: f64) -> bool {
+        match self.order_type {
+            OrderType::Market => true,
+            OrderType::Limit => self.limit_reached(price),
+            OrderType::Stop => self.stop_crossed(price),
+            OrderType::StopLimit => {
+                if !self.triggered && self.stop_crossed(price) {
+                    self.triggered = true;
+                }
+                self.triggered && self.limit_reached(price)
+            }
+        }
+    }
+}
+
+/// Outcome of evaluating the book against a set of prices.
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    /// Orders whose conditions were met, with the price to fill them at.
+    pub fills: Vec<(PendingOrder, f64)>,
+    pub expired: Vec<PendingOrder>,
+}
+
+fn pending_schema() -> arrow_schema::Schema {
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("user", DataType::Utf8, false),
+        Field::new("symbol", DataType::Utf8, false),
+        Field::new("side", DataType::Utf8, false),
+        Field::new("amount", DataType::Int64, false),
+        Field::new("order_type", DataType::Utf8, false),
+        Field::new("limit_price", DataType::Float64, true),
+        Field::new("stop_price", DataType::Float64, true),
+        Field::new("time_in_force", DataType::Utf8, false),
+        Field::new("created_at", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("triggered", DataType::Boolean, false),
+        Field::new("note", DataType::Utf8, true),
+    ])
+}
+
+fn pending_to_record_batch(orders: &[PendingOrder]) -> anyhow::Result<arrow_array::RecordBatch> {
+    use arrow_array::{BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+
+    Ok(RecordBatch::try_new(
+        Arc::new(pending_schema()),
+        vec![
+            Arc::new(StringArray::from_iter_values(orders.iter().map(|o| o.id.as_str()))),
+            Arc::new(StringArray::from_iter_values(orders.iter().map(|o| o.user.as_str()))),
+            Arc::new(StringArray::from_iter_values(orders.iter().map(|o| o.symbol.as_str()))),
+            Arc::new(StringArray::from_iter_values(orders.iter().map(|o| o.side.as_str()))),
+            Arc::new(Int64Array::from_iter_values(orders.iter().map(|o| o.amount))),
+            Arc::new(StringArray::from_iter_values(orders.iter().map(|o| o.order_type.as_str()))),
+            Arc::new(orders.iter().map(|o| o.limit_price).collect::<Float64Array>()),
+            Arc::new(orders.iter().map(|o| o.stop_price).collect::<Float64Array>()),
+            Arc::new(StringArray::from_iter_values(orders.iter().map(|o| o.time_in_force.as_str()))),
+            Arc::new(
+                TimestampMicrosecondArray::from_iter_values(orders.iter().map(|o| o.created_at.timestamp_micros()))
+                    .with_timezone("UTC"),
+            ),
+            Arc::new(orders.iter().map(|o| Some(o.triggered)).collect::<BooleanArray>()),
+            Arc::new(orders.iter().map(|o| o.note.as_deref()).collect::<StringArray>()),
+        ],
+    )?)
+}
+
+fn batch_to_pending(batch: &arrow_array::RecordBatch) -> anyhow::Result<Vec<PendingOrder>> {
+    use arrow_array::{BooleanArray, Float64Array, Int64Array, StringArray, TimestampMicrosecondArray};
+
+    let string = |i: usize| batch.column(i).as_any().downcast_ref::<StringArray>().unwrap();
+    let float = |i: usize| batch.column(i).as_any().downcast_ref::<Float64Array>().unwrap();
+    let amount_array = batch.column(4).as_any().downcast_ref::<Int64Array>().unwrap();
+    let created_array = batch.column(9).as_any().downcast_ref::<TimestampMicrosecondArray>().unwrap();
+    let triggered_array = batch.column(10).as_any().downcast_ref::<BooleanArray>().unwrap();
+    let (limit_array, stop_array, note_array) = (float(6), float(7), string(11));
+
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(PendingOrder {
+                id: string(0).value(i).to_string(),
+                user: string(1).value(i).to_string(),
+                symbol: string(2).value(i).to_string(),
+                side: string(3).value(i).parse()?,
+                amount: amount_array.value(i),
+                order_type: string(5).value(i).parse()?,
+                limit_price: limit_array.is_valid(i).then(|| limit_array.value(i)),
+                stop_price: stop_array.is_valid(i).then(|| stop_array.value(i)),
+                time_in_force: string(8).value(i).parse()?,
+                created_at: DateTime::<Utc>::from_timestamp_micros(created_array.value(i))
+                    .context("invalid order timestamp")?,
+                triggered: triggered_array.value(i),
+                note: note_array.is_valid(i).then(|| note_array.value(i).to_string()),
+            })
+        })
+        .collect()
+}
+
+/// Open limit and stop orders, persisted per user as `<user>/pending.parquet`
+/// behind a lock per user.
+#[derive(Clone)]
+pub struct OrderBook {
+    data_dir: PathBuf,
+    inner: Arc<RwLock<HashMap<String, Vec<PendingOrder>>>>,
+    user_locks: Arc<KeyedLocks>,
+}
+
+impl OrderBook {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self {
+            data_dir,
+            inner: Arc::new(RwLock::new(HashMap::new())),
+            user_locks: Arc::new(KeyedLocks::new()),
+        }
+    }
+
+    /// Read the pending orders of every user found under the data directory.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let data_dir = self.data_dir.clone();
+        let users = blocking(move || {
+            users_with_files(&data_dir, &["pending.parquet"]).context("failed to scan for pending order files")
+        })
+        .await?;
+        for user in users {
+            self.load_user(&user).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn place(&self, order: PendingOrder) -> anyhow::Result<()> {
+        self.load_user(&order.user).await?;
+        {
+            let mut map = self.inner.write().await;
+            map.entry(order.user.clone()).or_default().push(order.clone());
+        }
+        self.write_user_file(&order.user)
+            .await
+            .context("failed to persist pending order")
+    }
+
+    pub async fn for_user(&self, user: &str) -> anyhow::Result<Vec<PendingOrder>> {
+        self.load_user(user).await
+    }
+
+    /// Remove the open order with `id`, returning it if it existed.
+    pub async fn cancel(&self, id: &str) -> anyhow::Result<Option<PendingOrder>> {
+        let cancelled = {
+            let mut map = self.inner.write().await;
+            map.values_mut().find_map(|orders| {
+                let idx = orders.iter().position(|o| o.id == id)?;
+                Some(orders.remove(idx))
+            })
+        };
+        if let Some(order) = &cancelled {
+            self.write_user_file(&order.user)
+                .await
+                .context("failed to persist cancellation")?;
+        }
+        Ok(cancelled)
+    }
+
+    /// Symbols with open orders that need quotes.
+    pub async fn symbols(&self) -> HashSet<String> {
+        let map = self.inner.read().await;
+        map.values().flatten().map(|o| o.symbol.clone()).collect()
+    }
+
+    /// Expire day orders from earlier days and pull out every order whose
+    /// conditions are met at `prices`. Orders without a price stay open.
+    pub async fn evaluate(&self, prices: &HashMap<String, f64>, now: DateTime<Utc>) -> anyhow::Result<Evaluation> {
+        let mut evaluation = Evaluation::default();
+        let mut changed = Vec::new();
+        {
+            let mut map = self.inner.write().await;
+            for (user, orders) in map.iter_mut() {
+                let before = orders.clone();
+                let mut open = Vec::new();
+                for mut order in orders.drain(..) {
+                    if order.is_expired(now) {
+                        evaluation.expired.push(order);
+                        continue;
+                    }
+                    match prices.get(&order.symbol) {
+                        Some(price) if order.evaluate(*price) => evaluation.fills.push((order, *price)),
+                        _ => open.push(order),
+                    }
+                }
+                *orders = open;
+                if *orders != before {
+                    changed.push(user.clone());
+                }
+            }
+        }
+        for user in changed {
+            self.write_user_file(&user)
+                .await
+                .context("failed to persist order book")?;
+        }
+        Ok(evaluation)
+    }
+
+    async fn load_user(&self, user: &str) -> anyhow::Result<Vec<PendingOrder>> {
+        {
+            let map = self.inner.read().await;
+            if let Some(orders) = map.get(user) {
+                return Ok(orders.clone());
+            }
+        }
+
+        let loaded = self.read_user_file(user)
+            .await
+            .with_context(|| format!("failed to load pending orders for {user}"))?;
+        let mut map = self.inner.write().await;
+        Ok(map.entry(user.to_string()).or_insert(loaded).clone())
+    }
+
+    async fn write_user_file(&self, user: &str) -> anyhow::Result<()> {
+        let _lock = self.user_locks.lock(user).await;
+
+        let user_dir = entity_dir(&self.data_dir, user);
+        let map = self.inner.read().await;
+        let orders = map.get(user).cloned().unwrap_or_default();
+        drop(map);
+
+        blocking(move || {
+            let batch = pending_to_record_batch(&orders)?;
+            write_atomically(&user_dir.join("pending.parquet"), &batch)
+        })
+        .await
+    }
+
+    async fn read_user_file(&self, user: &str) -> anyhow::Result<Vec<PendingOrder>> {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+        use std::fs::File;
+
+        let _lock = self.user_locks.lock(user).await;
+        let file_path = entity_dir(&self.data_dir, user).join("pending.parquet");
+        blocking(move || {
+            if !file_path.exists() {
+                return Ok(Vec::new());
+            }
+            let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(file_path)?)?.build()?;
+            let mut orders = Vec::new();
+            for batch in reader {
+                orders.extend(batch_to_pending(&batch?)?);
+            }
+            Ok(orders)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use tempfile::tempdir;
+
+    fn pending(side: Side, order_type: OrderType, limit: Option<f64>, stop: Option<f64>) -> PendingOrder {
+        PendingOrder::new("alice".into(), "AAPL".into(), side, 1, order_type, limit, stop, TimeInForce::Gtc, Utc::now())
+            .unwrap()
+    }
+
+    fn prices(price: f64) -> HashMap<String, f64> {
+        HashMap::from([("AAPL".to_string(), price)])
+    }
+
+    #[test]
+    fn validates_required_prices() {
+        let now = Utc::now();
+        let new = |t, l, s| PendingOrder::new("a".into(), "X".into(), Side::Buy, 1, t, l, s, TimeInForce::Day, now);
+        assert!(new(OrderType::Limit, None, None).is_err());
+        assert!(new(OrderType::Stop, Some(1.0), Some(1.0)).is_err());
+        assert!(new(OrderType::StopLimit, Some(1.0), None).is_err());
+        assert!(new(OrderType::Limit, Some(-1.0), None).is_err());
+        assert!(new(OrderType::StopLimit, Some(1.0), Some(2.0)).is_ok());
+    }
+
+    #[test]
+    fn evaluates_trigger_conditions() {
+        assert!(!pending(Side::Buy, OrderType::Limit, Some(10.0), None).evaluate(10.5));
+        assert!(pending(Side::Buy, OrderType::Limit, Some(10.0), None).evaluate(9.5));
+        assert!(pending(Side::Sell, OrderType::Limit, Some(10.0), None).evaluate(10.0));
+        assert!(pending(Side::Sell, OrderType::Stop, None, Some(8.0)).evaluate(7.9));
+        assert!(!pending(Side::Buy, OrderType::Stop, None, Some(12.0)).evaluate(11.0));
+
+        // stop-limit buy: triggers above 12, then fills at or below 12.5
+        let mut order = pending(Side::Buy, OrderType::StopLimit, Some(12.5), Some(12.0));
+        assert!(!order.evaluate(11.0));
+        assert!(!order.evaluate(13.0));
+        assert!(order.triggered);
+        assert!(order.evaluate(12.4));
+    }
+
+    #[tokio::test]
+    async fn evaluate_fills_expires_and_persists() {
+        let dir = tempdir().unwrap();
+        let book = OrderBook::new(dir.path().to_path_buf());
+        let now = Utc::now();
+        let limit = pending(Side::Buy, OrderType::Limit, Some(10.0), None);
+        let resting = pending(Side::Buy, OrderType::Limit, Some(5.0), None);
+        let mut day = pending(Side::Sell, OrderType::Stop, None, Some(1.0));
+        day.time_in_force = TimeInForce::Day;
+        day.created_at = now - Duration::days(1);
+        for order in [&limit, &resting, &day] {
+            book.place(order.clone()).await.unwrap();
+        }
+
+        let evaluation = book.evaluate(&prices(9.0), now).await.unwrap();
+        assert_eq!(evaluation.fills.len(), 1);
+        assert_eq!(evaluation.fills[0].0.id, limit.id);
+        assert_eq!(evaluation.fills[0].1, 9.0);
+        assert_eq!(evaluation.expired.len(), 1);
+
+        let reloaded = OrderBook::new(dir.path().to_path_buf());
+        assert_eq!(reloaded.for_user("alice").await.unwrap(), vec![resting.clone()]);
+        assert_eq!(reloaded.cancel(&resting.id).await.unwrap(), Some(resting));
+        assert!(reloaded.for_user("alice").await.unwrap().is_empty());
+        assert_eq!(reloaded.cancel("missing").await.unwrap(), None);
+    }
+}
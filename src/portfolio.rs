@@ -1,64 +1,503 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use chrono::{DateTime, Utc};
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use crate::holdings::Order;
+use crate::holdings::{Order, Side};
+use crate::storage::files::{
+    append_line, blocking, entity_dir, remove_if_exists, users_with_files, write_atomically, KeyedLocks,
+    DEFAULT_COMPACT_AFTER,
+};
 
+const HOLDINGS_FILE: &str = "holdings.parquet";
+/// Append-only log of snapshots recorded since [`HOLDINGS_FILE`] was last
+/// written, one JSON object per line.
+const HOLDINGS_LOG_FILE: &str = "holdings.log";
+
+/// Net position of a user in a single symbol.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Holding {
     pub user: String,
     pub symbol: String,
-    pub original_price: f64,
+    pub quantity: i64,
+    pub average_cost: f64,
     pub current_price: f64,
-    pub amount: i64,
+    pub realised_pnl: f64,
+    pub unrealised_pnl: f64,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Clone, Default)]
+/// How sells are matched against earlier buys when realising gains.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LotMethod {
+    /// Sells consume the oldest lots first.
+    Fifo,
+    /// Sells consume the newest lots first.
+    Lifo,
+    /// Every sell is matched at the weighted average cost of the position.
+    #[default]
+    AverageCost,
+}
+
+impl std::str::FromStr for LotMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(LotMethod::Fifo),
+            "lifo" => Ok(LotMethod::Lifo),
+            "average_cost" => Ok(LotMethod::AverageCost),
+            other => Err(anyhow::anyhow!("unknown lot method {other}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Lot {
+    quantity: i64,
+    price: f64,
+}
+
+/// Running totals for one symbol while replaying a user's orders.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Position {
+    pub quantity: i64,
+    pub average_cost: f64,
+    pub realised_pnl: f64,
+    lots: VecDeque<Lot>,
+}
+
+impl Position {
+    /// Apply a single fill to the position, matching sells with `method`.
+    pub fn apply(&mut self, order: &Order, method: LotMethod) {
+        match order.side {
+            Side::Buy => {
+                self.quantity += order.amount;
+                match (method, self.lots.front_mut()) {
+                    (LotMethod::AverageCost, Some(lot)) => {
+                        let cost = lot.price * lot.quantity as f64 + order.price * order.amount as f64;
+                        lot.quantity += order.amount;
+                        lot.price = cost / lot.quantity as f64;
+                    }
+                    _ => self.lots.push_back(Lot { quantity: order.amount, price: order.price }),
+                }
+            }
+            Side::Sell => {
+                self.quantity -= order.amount;
+                let mut remaining = order.amount;
+                while remaining > 0 {
+                    let lot = match method {
+                        LotMethod::Lifo => self.lots.back_mut(),
+                        LotMethod::Fifo | LotMethod::AverageCost => self.lots.front_mut(),
+                    };
+                    let Some(lot) = lot else {
+                        // selling more than was bought; nothing left to match against
+                        self.realised_pnl += (order.price - self.average_cost) * remaining as f64;
+                        break;
+                    };
+                    let matched = remaining.min(lot.quantity);
+                    self.realised_pnl += (order.price - lot.price) * matched as f64;
+                    lot.quantity -= matched;
+                    remaining -= matched;
+                    if lot.quantity == 0 {
+                        match method {
+                            LotMethod::Lifo => self.lots.pop_back(),
+                            LotMethod::Fifo | LotMethod::AverageCost => self.lots.pop_front(),
+                        };
+                    }
+                }
+            }
+        }
+        let open: i64 = self.lots.iter().map(|l| l.quantity).sum();
+        let cost: f64 = self.lots.iter().map(|l| l.price * l.quantity as f64).sum();
+        self.average_cost = if open > 0 { cost / open as f64 } else { 0.0 };
+    }
+
+    pub fn unrealised_pnl(&self, price: f64) -> f64 {
+        (price - self.average_cost) * self.quantity as f64
+    }
+}
+
+/// Net `orders` into one [`Position`] per symbol, applying them in execution order.
+pub fn net_positions(orders: &[Order], method: LotMethod) -> BTreeMap<String, Position> {
+    let mut sorted: Vec<&Order> = orders.iter().collect();
+    sorted.sort_by_key(|o| o.executed_at);
+
+    let mut positions: BTreeMap<String, Position> = BTreeMap::new();
+    for order in sorted {
+        positions.entry(order.symbol.clone()).or_default().apply(order, method);
+    }
+    positions
+}
+
+/// Value of a user's portfolio at the end of one day.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ValuationPoint {
+    pub date: NaiveDate,
+    /// Quantity times price, summed over open positions.
+    pub market_value: f64,
+    /// Average cost times quantity, summed over open positions.
+    pub cost_basis: f64,
+    pub realised_pnl: f64,
+    pub unrealised_pnl: f64,
+}
+
+fn holding_schema() -> arrow_schema::Schema {
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    Schema::new(vec![
+        Field::new("user", DataType::Utf8, false),
+        Field::new("symbol", DataType::Utf8, false),
+        Field::new("quantity", DataType::Int64, false),
+        Field::new("average_cost", DataType::Float64, false),
+        Field::new("current_price", DataType::Float64, false),
+        Field::new("realised_pnl", DataType::Float64, false),
+        Field::new("unrealised_pnl", DataType::Float64, false),
+        Field::new("updated_at", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+    ])
+}
+
+fn holdings_to_record_batch(holdings: &[Holding]) -> anyhow::Result<arrow_array::RecordBatch> {
+    use arrow_array::{Float64Array, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+
+    let float_column = |f: fn(&Holding) -> f64| Arc::new(Float64Array::from_iter_values(holdings.iter().map(f)));
+    Ok(RecordBatch::try_new(
+        Arc::new(holding_schema()),
+        vec![
+            Arc::new(StringArray::from_iter_values(holdings.iter().map(|h| h.user.as_str()))),
+            Arc::new(StringArray::from_iter_values(holdings.iter().map(|h| h.symbol.as_str()))),
+            Arc::new(Int64Array::from_iter_values(holdings.iter().map(|h| h.quantity))),
+            float_column(|h| h.average_cost),
+            float_column(|h| h.current_price),
+            float_column(|h| h.realised_pnl),
+            float_column(|h| h.unrealised_pnl),
+            Arc::new(
+                TimestampMicrosecondArray::from_iter_values(holdings.iter().map(|h| h.updated_at.timestamp_micros()))
+                    .with_timezone("UTC"),
+            ),
+        ],
+    )?)
+}
+
+fn batch_to_holdings(batch: &arrow_array::RecordBatch) -> anyhow::Result<Vec<Holding>> {
+    use arrow_array::{Float64Array, Int64Array, StringArray, TimestampMicrosecondArray};
+
+    let column = |name: &str| batch.column_by_name(name).with_context(|| format!("missing column {name}"));
+    let strings = |name: &str| -> anyhow::Result<&StringArray> {
+        column(name)?.as_any().downcast_ref().with_context(|| format!("column {name} is not a string"))
+    };
+    let floats = |name: &str| -> anyhow::Result<&Float64Array> {
+        column(name)?.as_any().downcast_ref().with_context(|| format!("column {name} is not a float"))
+    };
+    let user = strings("user")?;
+    let symbol = strings("symbol")?;
+    let quantity: &Int64Array = column("quantity")?.as_any().downcast_ref().context("column quantity is not an integer")?;
+    let average_cost = floats("average_cost")?;
+    let current_price = floats("current_price")?;
+    let realised_pnl = floats("realised_pnl")?;
+    let unrealised_pnl = floats("unrealised_pnl")?;
+    let updated_at: &TimestampMicrosecondArray =
+        column("updated_at")?.as_any().downcast_ref().context("column updated_at is not a timestamp")?;
+
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Holding {
+                user: user.value(i).to_string(),
+                symbol: symbol.value(i).to_string(),
+                quantity: quantity.value(i),
+                average_cost: average_cost.value(i),
+                current_price: current_price.value(i),
+                realised_pnl: realised_pnl.value(i),
+                unrealised_pnl: unrealised_pnl.value(i),
+                updated_at: DateTime::<Utc>::from_timestamp_micros(updated_at.value(i))
+                    .context("invalid snapshot timestamp")?,
+            })
+        })
+        .collect()
+}
+
+/// Keeps daily snapshots of every user's net positions.
+///
+/// Each user has at most one snapshot per symbol per day; recording again on
+/// the same day replaces it. Queries return the latest snapshot per symbol.
+/// With a data directory, recorded snapshots are appended to
+/// `<user>/holdings.log`, and once the log holds `compact_after` of them all
+/// of the user's snapshots are written to `<user>/holdings.parquet` and the
+/// log is removed, behind a lock per user.
+#[derive(Clone)]
 pub struct HoldingsService {
     inner: Arc<RwLock<HashMap<String, Vec<Holding>>>>,
+    lot_method: LotMethod,
+    data_dir: Option<PathBuf>,
+    /// Per user, the number of snapshots in their log since it was last compacted.
+    user_locks: Arc<KeyedLocks<usize>>,
+    compact_after: usize,
+}
+
+impl Default for HoldingsService {
+    fn default() -> Self {
+        Self {
+            inner: Arc::default(),
+            lot_method: LotMethod::default(),
+            data_dir: None,
+            user_locks: Arc::default(),
+            compact_after: DEFAULT_COMPACT_AFTER,
+        }
+    }
 }
 
 impl HoldingsService {
     pub fn new() -> Self {
-        Self { inner: Arc::new(RwLock::new(HashMap::new())) }
+        Self::default()
+    }
+
+    /// Use `method` to match sells against buys when computing realised P&L.
+    pub fn with_lot_method(mut self, method: LotMethod) -> Self {
+        self.lot_method = method;
+        self
+    }
+
+    /// Persist snapshots under `data_dir`, next to the users' orders.
+    pub fn with_data_dir(mut self, data_dir: PathBuf) -> Self {
+        self.data_dir = Some(data_dir);
+        self
     }
 
-    pub async fn record(&self, order: &Order, current_price: f64, now: DateTime<Utc>) {
+    /// Read the persisted snapshots of every user, returning how many were read.
+    ///
+    /// Logs left behind, e.g. by a restart, are compacted.
+    pub async fn load(&self) -> anyhow::Result<usize> {
+        let Some(data_dir) = &self.data_dir else {
+            return Ok(0);
+        };
+        let scan_dir = data_dir.clone();
+        let users = blocking(move || {
+            users_with_files(&scan_dir, &[HOLDINGS_FILE, HOLDINGS_LOG_FILE]).context("failed to scan for holdings files")
+        })
+        .await?;
+        let mut loaded = HashMap::new();
+        for user in users {
+            let mut logged = self.user_locks.lock(&user).await;
+            let user_dir = entity_dir(data_dir, &user);
+            let entries = blocking(move || {
+                let (entries, recovered) = read_user_holdings(&user_dir)?;
+                if recovered {
+                    compact_user_holdings(&user_dir, &entries)?;
+                }
+                Ok(entries)
+            })
+            .await
+            .with_context(|| format!("failed to load holdings for {user}"))?;
+            *logged = 0;
+            loaded.insert(user, entries);
+        }
+        let count = loaded.values().map(Vec::len).sum();
+        *self.inner.write().await = loaded;
+        Ok(count)
+    }
+
+    /// Rebuild `user`'s positions from `orders` and value them at `prices`.
+    ///
+    /// Symbols without a price keep the price of their previous snapshot and
+    /// are skipped if they have never been priced.
+    pub async fn record(
+        &self,
+        user: &str,
+        orders: &[Order],
+        prices: &HashMap<String, f64>,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let mut logged = self.user_locks.lock(user).await;
         let mut map = self.inner.write().await;
-        let entries = map.entry(order.user.clone()).or_default();
-        if let Some(existing) = entries.iter_mut().find(|h| {
-            h.symbol == order.symbol
-                && (h.original_price - order.price).abs() < f64::EPSILON
-                && h.amount == order.amount
-                && h.updated_at.date_naive() == now.date_naive()
-        }) {
-            existing.current_price = current_price;
-            existing.updated_at = now;
+        let entries = map.entry(user.to_string()).or_default();
+        let recorded = self.valued(entries, user, orders, prices, now);
+        for holding in &recorded {
+            upsert(entries, holding.clone());
+        }
+        let Some(data_dir) = &self.data_dir else {
+            return Ok(());
+        };
+        let user_dir = entity_dir(data_dir, user);
+        let count = *logged + recorded.len();
+        let write = if count >= self.compact_after {
+            let entries = entries.clone();
+            drop(map);
+            blocking(move || compact_user_holdings(&user_dir, &entries)).await
         } else {
-            entries.push(Holding {
-                user: order.user.clone(),
-                symbol: order.symbol.clone(),
-                original_price: order.price,
+            drop(map);
+            let mut lines = Vec::new();
+            for holding in &recorded {
+                serde_json::to_writer(&mut lines, holding)?;
+                lines.push(b'\n');
+            }
+            blocking(move || append_line(&user_dir.join(HOLDINGS_LOG_FILE), &lines)).await
+        };
+        write.with_context(|| format!("failed to persist holdings for {user}"))?;
+        *logged = if count >= self.compact_after { 0 } else { count };
+        Ok(())
+    }
+
+    /// `user`'s positions from `orders` valued at `prices`, without keeping a
+    /// snapshot, e.g. while replaying a past period. Symbols without a price
+    /// are skipped.
+    pub fn value(&self, user: &str, orders: &[Order], prices: &HashMap<String, f64>, now: DateTime<Utc>) -> Vec<Holding> {
+        self.valued(&[], user, orders, prices, now)
+    }
+
+    fn valued(
+        &self,
+        entries: &[Holding],
+        user: &str,
+        orders: &[Order],
+        prices: &HashMap<String, f64>,
+        now: DateTime<Utc>,
+    ) -> Vec<Holding> {
+        let mut holdings = Vec::new();
+        for (symbol, position) in net_positions(orders, self.lot_method) {
+            let previous_price = entries
+                .iter()
+                .filter(|h| h.symbol == symbol)
+                .max_by_key(|h| h.updated_at)
+                .map(|h| h.current_price);
+            let Some(current_price) = prices.get(&symbol).copied().or(previous_price) else {
+                continue;
+            };
+            holdings.push(Holding {
+                user: user.to_string(),
+                symbol,
+                quantity: position.quantity,
+                average_cost: position.average_cost,
                 current_price,
-                amount: order.amount,
-                updated_at: now,
+                realised_pnl: position.realised_pnl,
+                unrealised_pnl: position.unrealised_pnl(current_price),
+                updated_at: now.trunc_subsecs(6),
             });
         }
+        holdings
+    }
+
+    /// Record a snapshot for every user with `orders`, grouped by user and
+    /// valued at `prices`, e.g. after loading orders at startup.
+    pub async fn rebuild(&self, orders: &[Order], prices: &HashMap<String, f64>, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let mut by_user: BTreeMap<&str, Vec<Order>> = BTreeMap::new();
+        for order in orders {
+            by_user.entry(order.user.as_str()).or_default().push(order.clone());
+        }
+        for (user, orders) in by_user {
+            self.record(user, &orders, prices, now).await?;
+        }
+        Ok(())
     }
 
     pub async fn all(&self) -> Vec<Holding> {
         let map = self.inner.read().await;
-        map.values().flatten().cloned().collect()
+        map.values().flat_map(|entries| latest(entries)).collect()
     }
 
     pub async fn for_user(&self, user: &str) -> Vec<Holding> {
         let map = self.inner.read().await;
-        map.get(user).cloned().unwrap_or_default()
+        map.get(user).map(|entries| latest(entries)).unwrap_or_default()
+    }
+
+    /// Daily value of `user`'s portfolio, oldest first.
+    ///
+    /// A symbol without a snapshot on some day is valued at its most recent
+    /// earlier snapshot.
+    pub async fn history(&self, user: &str) -> Vec<ValuationPoint> {
+        let map = self.inner.read().await;
+        let Some(entries) = map.get(user) else {
+            return Vec::new();
+        };
+        let mut sorted: Vec<&Holding> = entries.iter().collect();
+        sorted.sort_by_key(|h| h.updated_at);
+
+        let mut history = Vec::new();
+        let mut current: BTreeMap<&str, &Holding> = BTreeMap::new();
+        for (i, holding) in sorted.iter().enumerate() {
+            current.insert(holding.symbol.as_str(), holding);
+            let date = holding.updated_at.date_naive();
+            if sorted.get(i + 1).is_some_and(|next| next.updated_at.date_naive() == date) {
+                continue;
+            }
+            let mut point = ValuationPoint { date, market_value: 0.0, cost_basis: 0.0, realised_pnl: 0.0, unrealised_pnl: 0.0 };
+            for h in current.values() {
+                point.market_value += h.current_price * h.quantity as f64;
+                point.cost_basis += h.average_cost * h.quantity as f64;
+                point.realised_pnl += h.realised_pnl;
+                point.unrealised_pnl += h.unrealised_pnl;
+            }
+            history.push(point);
+        }
+        history
+    }
+}
+
+/// Put `holding` in place of the snapshot of the same symbol on the same day,
+/// or add it.
+fn upsert(entries: &mut Vec<Holding>, holding: Holding) {
+    let date = holding.updated_at.date_naive();
+    match entries.iter_mut().find(|h| h.symbol == holding.symbol && h.updated_at.date_naive() == date) {
+        Some(existing) => *existing = holding,
+        None => entries.push(holding),
+    }
+}
+
+/// Snapshots in `user_dir` from Parquet, updated with any left in the log, and
+/// whether there was a log. A torn last line from a crash during an append is
+/// dropped.
+fn read_user_holdings(user_dir: &Path) -> anyhow::Result<(Vec<Holding>, bool)> {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let file_path = user_dir.join(HOLDINGS_FILE);
+    let log_path = user_dir.join(HOLDINGS_LOG_FILE);
+    let mut entries = Vec::new();
+    if file_path.exists() {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(file_path)?)?.build()?;
+        for batch in reader {
+            entries.extend(batch_to_holdings(&batch?)?);
+        }
+    }
+    if !log_path.exists() {
+        return Ok((entries, false));
+    }
+
+    let log = std::fs::read_to_string(&log_path)?;
+    let lines: Vec<&str> = log.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Holding>(line) {
+            Ok(holding) => upsert(&mut entries, holding),
+            Err(e) if i + 1 == lines.len() && !log.ends_with('\n') => {
+                tracing::warn!("dropping incomplete holdings log entry in {}: {e}", user_dir.display());
+            }
+            Err(e) => return Err(e).with_context(|| format!("corrupt holdings log entry on line {}", i + 1)),
+        }
+    }
+    Ok((entries, true))
+}
+
+/// Write `entries` as all of the snapshots in `user_dir` and drop the log.
+fn compact_user_holdings(user_dir: &Path, entries: &[Holding]) -> anyhow::Result<()> {
+    write_atomically(&user_dir.join(HOLDINGS_FILE), &holdings_to_record_batch(entries)?)?;
+    Ok(remove_if_exists(&user_dir.join(HOLDINGS_LOG_FILE))?)
+}
+
+/// Most recent snapshot for each symbol, ordered by symbol.
+fn latest(entries: &[Holding]) -> Vec<Holding> {
+    let mut by_symbol: BTreeMap<&str, &Holding> = BTreeMap::new();
+    for h in entries {
+        let slot = by_symbol.entry(h.symbol.as_str()).or_insert(h);
+        if h.updated_at > slot.updated_at {
+            *slot = h;
+        }
     }
+    by_symbol.into_values().cloned().collect()
 }
 
 #[cfg(test)]
@@ -66,28 +505,160 @@ mod tests {
     use super::*;
     use chrono::Duration;
 
-    fn order() -> Order {
-        Order { user: "alice".into(), symbol: "AAPL".into(), amount: 1, price: 10.0 }
+    fn order(side: Side, amount: i64, price: f64) -> Order {
+        Order::new("alice", "AAPL", side, amount, price)
+    }
+
+    fn prices(price: f64) -> HashMap<String, f64> {
+        HashMap::from([("AAPL".to_string(), price)])
+    }
+
+    #[test]
+    fn nets_buys_and_sells() {
+        let orders = vec![
+            order(Side::Buy, 10, 10.0),
+            order(Side::Buy, 10, 20.0),
+            order(Side::Sell, 5, 25.0),
+        ];
+        let positions = net_positions(&orders, LotMethod::AverageCost);
+        let pos = &positions["AAPL"];
+        assert_eq!(pos.quantity, 15);
+        assert_eq!(pos.average_cost, 15.0);
+        assert_eq!(pos.realised_pnl, 50.0);
+        assert_eq!(pos.unrealised_pnl(16.0), 15.0);
+    }
+
+    #[test]
+    fn lot_methods_realise_different_gains() {
+        let orders = vec![
+            order(Side::Buy, 10, 10.0),
+            order(Side::Buy, 10, 20.0),
+            order(Side::Sell, 15, 30.0),
+        ];
+        let fifo = &net_positions(&orders, LotMethod::Fifo)["AAPL"];
+        assert_eq!(fifo.quantity, 5);
+        assert_eq!(fifo.realised_pnl, 200.0 + 50.0);
+        assert_eq!(fifo.average_cost, 20.0);
+
+        let lifo = &net_positions(&orders, LotMethod::Lifo)["AAPL"];
+        assert_eq!(lifo.quantity, 5);
+        assert_eq!(lifo.realised_pnl, 100.0 + 100.0);
+        assert_eq!(lifo.average_cost, 10.0);
+
+        let avg = &net_positions(&orders, LotMethod::AverageCost)["AAPL"];
+        assert_eq!(avg.quantity, 5);
+        assert_eq!(avg.realised_pnl, 225.0);
+        assert_eq!(avg.average_cost, 15.0);
+    }
+
+    #[tokio::test]
+    async fn record_uses_configured_lot_method() {
+        let svc = HoldingsService::new().with_lot_method(LotMethod::Fifo);
+        let orders = vec![order(Side::Buy, 1, 10.0), order(Side::Buy, 1, 20.0), order(Side::Sell, 1, 25.0)];
+        svc.record("alice", &orders, &prices(25.0), Utc::now()).await.unwrap();
+        let holdings = svc.for_user("alice").await;
+        assert_eq!(holdings[0].realised_pnl, 15.0);
+        assert_eq!(holdings[0].average_cost, 20.0);
+    }
+
+    #[tokio::test]
+    async fn record_aggregates_orders_into_one_holding() {
+        let svc = HoldingsService::new();
+        let orders = vec![order(Side::Buy, 1, 10.0), order(Side::Buy, 3, 14.0)];
+        svc.record("alice", &orders, &prices(15.0), Utc::now()).await.unwrap();
+        let holdings = svc.for_user("alice").await;
+        assert_eq!(holdings.len(), 1);
+        assert_eq!(holdings[0].quantity, 4);
+        assert_eq!(holdings[0].average_cost, 13.0);
+        assert_eq!(holdings[0].unrealised_pnl, 8.0);
     }
 
     #[tokio::test]
     async fn record_updates_same_day() {
         let svc = HoldingsService::new();
         let now = Utc::now();
-        svc.record(&order(), 11.0, now).await;
-        svc.record(&order(), 12.0, now + Duration::hours(1)).await;
+        let orders = vec![order(Side::Buy, 1, 10.0)];
+        svc.record("alice", &orders, &prices(11.0), now).await.unwrap();
+        svc.record("alice", &orders, &prices(12.0), now + Duration::hours(1)).await.unwrap();
         let holdings = svc.for_user("alice").await;
         assert_eq!(holdings.len(), 1);
         assert_eq!(holdings[0].current_price, 12.0);
+        assert_eq!(svc.inner.read().await["alice"].len(), 1);
     }
 
     #[tokio::test]
-    async fn record_new_day_adds_entry() {
+    async fn record_new_day_adds_snapshot() {
         let svc = HoldingsService::new();
-        let now = Utc::now();
-        svc.record(&order(), 11.0, now).await;
-        svc.record(&order(), 12.0, now + Duration::days(1)).await;
+        let now = Utc::now().trunc_subsecs(6);
+        let orders = vec![order(Side::Buy, 1, 10.0)];
+        svc.record("alice", &orders, &prices(11.0), now).await.unwrap();
+        svc.record("alice", &orders, &HashMap::new(), now + Duration::days(1)).await.unwrap();
+        assert_eq!(svc.inner.read().await["alice"].len(), 2);
         let holdings = svc.for_user("alice").await;
-        assert_eq!(holdings.len(), 2);
+        assert_eq!(holdings.len(), 1);
+        assert_eq!(holdings[0].current_price, 11.0);
+        assert_eq!(holdings[0].updated_at, now + Duration::days(1));
+    }
+
+    #[tokio::test]
+    async fn rebuild_groups_orders_by_user() {
+        let svc = HoldingsService::new();
+        let now = Utc::now();
+        let orders = vec![
+            order(Side::Buy, 2, 10.0),
+            Order::new("bob", "AAPL", Side::Buy, 1, 12.0),
+            Order::new("bob", "MSFT", Side::Buy, 1, 20.0),
+        ];
+        svc.rebuild(&orders, &prices(15.0), now).await.unwrap();
+        assert_eq!(svc.for_user("alice").await[0].unrealised_pnl, 10.0);
+        // MSFT has no price yet, so only AAPL can be valued
+        assert_eq!(svc.for_user("bob").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn snapshots_persist_and_build_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let svc = HoldingsService::new().with_data_dir(dir.path().to_path_buf());
+        let day = Utc::now() - Duration::days(2);
+        let mut orders = vec![order(Side::Buy, 2, 10.0)];
+        svc.record("alice", &orders, &prices(11.0), day).await.unwrap();
+        orders.push(Order::new("alice", "MSFT", Side::Buy, 1, 20.0));
+        let msft = HashMap::from([("MSFT".to_string(), 25.0)]);
+        svc.record("alice", &orders, &msft, day + Duration::days(1)).await.unwrap();
+
+        let restarted = HoldingsService::new().with_data_dir(dir.path().to_path_buf());
+        assert_eq!(restarted.load().await.unwrap(), 3);
+        assert_eq!(restarted.for_user("alice").await, svc.for_user("alice").await);
+        let history = restarted.history("alice").await;
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].date, history[0].market_value, history[0].cost_basis), (day.date_naive(), 22.0, 20.0));
+        assert_eq!((history[1].market_value, history[1].unrealised_pnl), (47.0, 7.0));
+        assert!(restarted.history("bob").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_log_is_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut svc = HoldingsService::new().with_data_dir(dir.path().to_path_buf());
+        svc.compact_after = 3;
+        let user_dir = dir.path().join("alice");
+        let day = Utc::now() - Duration::days(2);
+        let orders = vec![order(Side::Buy, 2, 10.0)];
+        svc.record("alice", &orders, &prices(11.0), day).await.unwrap();
+        svc.record("alice", &orders, &prices(12.0), day + Duration::hours(1)).await.unwrap();
+        assert!(!user_dir.join("holdings.parquet").exists());
+        assert_eq!(std::fs::read_to_string(user_dir.join("holdings.log")).unwrap().lines().count(), 2);
+
+        svc.record("alice", &orders, &prices(13.0), day + Duration::days(1)).await.unwrap();
+        assert!(user_dir.join("holdings.parquet").exists());
+        assert!(!user_dir.join("holdings.log").exists());
+
+        // a later same-day snapshot in the log replaces the compacted one
+        svc.record("alice", &orders, &prices(14.0), day + Duration::days(1) + Duration::hours(1)).await.unwrap();
+        let restarted = HoldingsService::new().with_data_dir(dir.path().to_path_buf());
+        assert_eq!(restarted.load().await.unwrap(), 2);
+        assert!(!user_dir.join("holdings.log").exists());
+        let values: Vec<f64> = restarted.history("alice").await.iter().map(|p| p.market_value).collect();
+        assert_eq!(values, vec![24.0, 28.0]);
     }
 }
@@ -0,0 +1,674 @@
+//! Quote providers other than Yahoo Finance and a way to chain them.
+
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError};
+
+use anyhow::{bail, Context};
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate};
+use serde::Deserialize;
+use yahoo_finance_api::Quote;
+
+use crate::config::ProvidersConfig;
+use crate::instrument::Instrument;
+use crate::market::{DailyBar, QuoteFetcher, YahooFetcher};
+use crate::storage::files::{blocking, KeyedLocks};
+
+/// Replays daily bars stored on disk, for running without network access.
+///
+/// Each symbol is read from `<dir>/<SYMBOL>.parquet`, in the same layout as
+/// the market data files, or from `<dir>/<SYMBOL>.csv`. CSV files need a
+/// header row with `date` (`YYYY-MM-DD`) and `close` columns and may add
+/// `open`, `high`, `low`, `adjclose` and `volume`.
+///
+/// Files are read on the blocking thread pool the first time a symbol is
+/// requested and kept in memory after that, so changes to them are only seen
+/// after a restart.
+pub struct FileFetcher {
+    dir: PathBuf,
+    /// Per symbol, its bars once they have been read.
+    bars: KeyedLocks<Option<Arc<Vec<DailyBar>>>>,
+}
+
+impl FileFetcher {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, bars: KeyedLocks::new() }
+    }
+
+    async fn read_bars(&self, symbol: &str) -> anyhow::Result<Arc<Vec<DailyBar>>> {
+        crate::ident::validate_symbol(symbol)?;
+        let mut cached = self.bars.lock(symbol).await;
+        if let Some(bars) = &*cached {
+            return Ok(bars.clone());
+        }
+        let dir = self.dir.clone();
+        let symbol = symbol.to_string();
+        let bars = Arc::new(blocking(move || read_bar_file(&dir, &symbol)).await?);
+        *cached = Some(bars.clone());
+        Ok(bars)
+    }
+}
+
+/// Bars of `symbol` from its Parquet or CSV file in `dir`, oldest first.
+fn read_bar_file(dir: &Path, symbol: &str) -> anyhow::Result<Vec<DailyBar>> {
+    let parquet = dir.join(format!("{symbol}.parquet"));
+    let csv = dir.join(format!("{symbol}.csv"));
+    let mut bars = if parquet.exists() {
+        read_parquet(&parquet)?
+    } else if csv.exists() {
+        parse_csv(&std::fs::read_to_string(&csv)?)?
+    } else {
+        bail!("no price file for {symbol} in {}", dir.display());
+    };
+    bars.sort_by_key(|b| b.date);
+    Ok(bars)
+}
+
+fn read_parquet(path: &Path) -> anyhow::Result<Vec<DailyBar>> {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let file = std::fs::File::open(path)?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    let mut bars = Vec::new();
+    for batch in reader {
+        bars.extend(crate::market::batch_to_bars(&batch?)?);
+    }
+    Ok(bars)
+}
+
+fn parse_csv(text: &str) -> anyhow::Result<Vec<DailyBar>> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header: Vec<String> = lines
+        .next()
+        .context("empty price file")?
+        .split(',')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .collect();
+    let column = |name: &str| header.iter().position(|h| h == name);
+    let date_col = column("date").context("missing date column")?;
+    let close_col = column("close").context("missing close column")?;
+
+    lines
+        .enumerate()
+        .map(|(row, line)| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let number = |col: Option<usize>| -> anyhow::Result<Option<f64>> {
+                match col.and_then(|c| fields.get(c)) {
+                    Some(v) if !v.is_empty() => Ok(Some(v.parse().with_context(|| format!("invalid number {v:?} on row {}", row + 1))?)),
+                    _ => Ok(None),
+                }
+            };
+            let date = fields.get(date_col).context("missing date")?;
+            let close = number(Some(close_col))?.context("missing close")?;
+            Ok(DailyBar {
+                date: date.parse().with_context(|| format!("invalid date {date:?} on row {}", row + 1))?,
+                open: number(column("open"))?.unwrap_or(close),
+                high: number(column("high"))?.unwrap_or(close),
+                low: number(column("low"))?.unwrap_or(close),
+                close,
+                adjclose: number(column("adjclose"))?.unwrap_or(close),
+                volume: number(column("volume"))?.unwrap_or(0.0) as u64,
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl QuoteFetcher for FileFetcher {
+    async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
+        Ok(self.read_bars(symbol).await?.last().map(DailyBar::to_quote).into_iter().collect())
+    }
+
+    async fn fetch_history(&self, symbol: &str, start: NaiveDate, end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        Ok(self
+            .read_bars(symbol)
+            .await?
+            .iter()
+            .filter(|b| b.date >= start && b.date <= end)
+            .map(DailyBar::to_quote)
+            .collect())
+    }
+}
+
+/// Where each quote field lives in a JSON response, as JSON pointers.
+///
+/// `quotes` points at the array of quotes (or a single quote object) in the
+/// response; the other pointers are relative to each quote. Missing `open`,
+/// `high`, `low` and `adjclose` values fall back to the close and a missing
+/// volume to zero.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FieldMapping {
+    pub quotes: String,
+    pub timestamp: String,
+    pub open: String,
+    pub high: String,
+    pub low: String,
+    pub close: String,
+    pub adjclose: String,
+    pub volume: String,
+}
+
+impl Default for FieldMapping {
+    fn default() -> Self {
+        Self {
+            quotes: String::new(),
+            timestamp: "/timestamp".into(),
+            open: "/open".into(),
+            high: "/high".into(),
+            low: "/low".into(),
+            close: "/close".into(),
+            adjclose: "/adjclose".into(),
+            volume: "/volume".into(),
+        }
+    }
+}
+
+impl FieldMapping {
+    fn quotes(&self, body: &serde_json::Value) -> anyhow::Result<Vec<Quote>> {
+        let items = body.pointer(&self.quotes).with_context(|| format!("no quotes at {:?}", self.quotes))?;
+        match items {
+            serde_json::Value::Array(items) => items.iter().map(|item| self.quote(item)).collect(),
+            item => Ok(vec![self.quote(item)?]),
+        }
+    }
+
+    fn quote(&self, item: &serde_json::Value) -> anyhow::Result<Quote> {
+        let number = |pointer: &str| item.pointer(pointer).and_then(json_number);
+        let close = number(&self.close).with_context(|| format!("no close at {:?}", self.close))?;
+        let timestamp = item
+            .pointer(&self.timestamp)
+            .and_then(json_timestamp)
+            .with_context(|| format!("no timestamp at {:?}", self.timestamp))?;
+        Ok(Quote {
+            timestamp,
+            open: number(&self.open).unwrap_or(close),
+            high: number(&self.high).unwrap_or(close),
+            low: number(&self.low).unwrap_or(close),
+            close,
+            adjclose: number(&self.adjclose).unwrap_or(close),
+            volume: number(&self.volume).unwrap_or(0.0) as u64,
+        })
+    }
+}
+
+/// Numbers may be sent as JSON numbers or strings.
+fn json_number(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Unix seconds, an RFC 3339 timestamp or a `YYYY-MM-DD` date.
+fn json_timestamp(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_i64(),
+        serde_json::Value::String(s) => DateTime::parse_from_rfc3339(s)
+            .map(|t| t.timestamp())
+            .ok()
+            .or_else(|| s.parse::<NaiveDate>().ok().map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())),
+        _ => None,
+    }
+}
+
+/// Fetches quotes from any JSON API.
+///
+/// `url` is a template in which `{symbol}` is replaced by the percent-encoded
+/// symbol. History is only available when `history_url` is set; it may also
+/// use `{start}` and `{end}`, replaced by `YYYY-MM-DD` dates.
+pub struct HttpJsonFetcher {
+    client: reqwest::Client,
+    url: String,
+    history_url: Option<String>,
+    fields: FieldMapping,
+}
+
+impl HttpJsonFetcher {
+    pub fn new(url: impl Into<String>, fields: FieldMapping) -> Self {
+        Self { client: reqwest::Client::new(), url: url.into(), history_url: None, fields }
+    }
+
+    pub fn with_history_url(mut self, url: impl Into<String>) -> Self {
+        self.history_url = Some(url.into());
+        self
+    }
+
+    async fn get(&self, url: String) -> anyhow::Result<Vec<Quote>> {
+        let body: serde_json::Value = self.client.get(url).send().await?.error_for_status()?.json().await?;
+        self.fields.quotes(&body)
+    }
+}
+
+#[async_trait]
+impl QuoteFetcher for HttpJsonFetcher {
+    async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
+        self.get(self.url.replace("{symbol}", &encode_url_component(symbol))).await
+    }
+
+    async fn fetch_history(&self, symbol: &str, start: NaiveDate, end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        let Some(template) = &self.history_url else {
+            return Ok(Vec::new());
+        };
+        let url = template
+            .replace("{symbol}", &encode_url_component(symbol))
+            .replace("{start}", &start.to_string())
+            .replace("{end}", &end.to_string());
+        self.get(url).await
+    }
+}
+
+/// `value` with every byte other than a letter, digit, `-`, `.`, `_` or `~`
+/// written as `%XX`, so it can go anywhere in a URL.
+fn encode_url_component(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Tries each fetcher in turn until one succeeds.
+pub struct FallbackFetcher {
+    fetchers: Vec<Arc<dyn QuoteFetcher>>,
+}
+
+impl FallbackFetcher {
+    pub fn new(fetchers: Vec<Arc<dyn QuoteFetcher>>) -> Self {
+        Self { fetchers }
+    }
+}
+
+#[async_trait]
+impl QuoteFetcher for FallbackFetcher {
+    async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
+        let mut errors = Vec::new();
+        for fetcher in &self.fetchers {
+            match fetcher.fetch_quotes(symbol).await {
+                Ok(quotes) if !quotes.is_empty() => return Ok(quotes),
+                Ok(_) => errors.push(format!("no quotes for {symbol}")),
+                Err(e) => errors.push(format!("{e:#}")),
+            }
+        }
+        bail!("all providers failed: {}", errors.join("; "))
+    }
+
+    async fn lookup_instrument(&self, symbol: &str) -> anyhow::Result<Option<Instrument>> {
+        let mut last_err = None;
+        for fetcher in &self.fetchers {
+            match fetcher.lookup_instrument(symbol).await {
+                Ok(Some(instrument)) => return Ok(Some(instrument)),
+                Ok(None) => {}
+                Err(e) => last_err = Some(e),
+            }
+        }
+        last_err.map_or(Ok(None), Err)
+    }
+
+    async fn fetch_history(&self, symbol: &str, start: NaiveDate, end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        let mut last_err = None;
+        for fetcher in &self.fetchers {
+            match fetcher.fetch_history(symbol, start, end).await {
+                Ok(quotes) if !quotes.is_empty() => return Ok(quotes),
+                Ok(_) => {}
+                Err(e) => last_err = Some(e),
+            }
+        }
+        last_err.map_or(Ok(Vec::new()), Err)
+    }
+}
+
+/// Parameters of the price paths generated by [`SimulatedFetcher`].
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationParams {
+    /// Annualised drift of the log price.
+    pub drift: f64,
+    /// Annualised volatility.
+    pub volatility: f64,
+    /// Price of every symbol on `origin`.
+    pub start_price: f64,
+    /// First day of every path.
+    pub origin: NaiveDate,
+    pub seed: u64,
+}
+
+impl Default for SimulationParams {
+    fn default() -> Self {
+        Self {
+            drift: 0.05,
+            volatility: 0.2,
+            start_price: 100.0,
+            origin: NaiveDate::from_ymd_opt(2020, 1, 1).unwrap(),
+            seed: 0,
+        }
+    }
+}
+
+/// SplitMix64, small and fast enough for generating price paths. Not for
+/// anything that needs to be unpredictable.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in (0, 1].
+    fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal draw (Box-Muller).
+    fn next_normal(&mut self) -> f64 {
+        let (u1, u2) = (self.next_f64(), self.next_f64());
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+/// FNV-1a, so a symbol maps to the same path on every platform and release.
+fn symbol_hash(symbol: &str) -> u64 {
+    symbol.bytes().fold(0xCBF2_9CE4_8422_2325, |h, b| (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+/// Generates reproducible geometric Brownian motion price paths, one per
+/// symbol, so the game can run without a network connection.
+///
+/// Every calendar day from `origin` is one step. The path of a symbol depends
+/// only on the parameters and the symbol, so the same seed always produces
+/// the same prices. Paths are kept once generated and only extended when a
+/// later day is requested.
+pub struct SimulatedFetcher {
+    params: SimulationParams,
+    paths: std::sync::Mutex<HashMap<String, SimulatedPath>>,
+}
+
+/// The bars of one symbol generated so far and the generator to extend them.
+struct SimulatedPath {
+    rng: SplitMix64,
+    bars: Vec<DailyBar>,
+}
+
+impl SimulatedFetcher {
+    pub fn new(params: SimulationParams) -> Self {
+        Self { params, paths: std::sync::Mutex::new(HashMap::new()) }
+    }
+
+    /// Daily bars for `symbol` from `origin` up to and including `until`.
+    #[cfg(test)]
+    pub fn bars(&self, symbol: &str, until: NaiveDate) -> Vec<DailyBar> {
+        self.with_bars(symbol, until, <[DailyBar]>::to_vec)
+    }
+
+    /// Call `f` with the bars of `symbol` up to and including `until`,
+    /// generating the days not generated yet.
+    fn with_bars<T>(&self, symbol: &str, until: NaiveDate, f: impl FnOnce(&[DailyBar]) -> T) -> T {
+        let p = &self.params;
+        let dt = 1.0 / 365.0;
+        let step_drift = (p.drift - p.volatility * p.volatility / 2.0) * dt;
+        let step_vol = p.volatility * dt.sqrt();
+
+        let mut paths = self.paths.lock().unwrap_or_else(PoisonError::into_inner);
+        let path = paths
+            .entry(symbol.to_string())
+            .or_insert_with(|| SimulatedPath { rng: SplitMix64(p.seed ^ symbol_hash(symbol)), bars: Vec::new() });
+        let (mut prev, mut date) = match path.bars.last() {
+            Some(last) => (last.close, last.date.succ_opt().expect("date in range")),
+            None => (p.start_price, p.origin),
+        };
+        while date <= until {
+            let rng = &mut path.rng;
+            let close = prev * (step_drift + step_vol * rng.next_normal()).exp();
+            // intraday extremes beyond the open/close range
+            let high = prev.max(close) * (1.0 + step_vol * rng.next_f64() / 2.0);
+            let low = prev.min(close) * (1.0 - step_vol * rng.next_f64() / 2.0);
+            let volume = 500_000 + (rng.next_u64() % 1_000_000);
+            path.bars.push(DailyBar { date, open: prev, high, low, close, adjclose: close, volume });
+            prev = close;
+            date = date.succ_opt().expect("date in range");
+        }
+        let end = path.bars.partition_point(|b| b.date <= until);
+        f(&path.bars[..end])
+    }
+}
+
+#[async_trait]
+impl QuoteFetcher for SimulatedFetcher {
+    async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
+        let today = chrono::Utc::now().date_naive();
+        Ok(self.with_bars(symbol, today, |bars| bars.last().map(DailyBar::to_quote).into_iter().collect()))
+    }
+
+    async fn fetch_history(&self, symbol: &str, start: NaiveDate, end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        Ok(self.with_bars(symbol, end, |bars| {
+            let first = bars.partition_point(|b| b.date < start);
+            bars[first..].iter().map(DailyBar::to_quote).collect()
+        }))
+    }
+}
+
+/// Quote provider names accepted in the configured chain.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    Yahoo,
+    File,
+    Http,
+    Simulated,
+}
+
+impl FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "yahoo" => Ok(ProviderKind::Yahoo),
+            "file" => Ok(ProviderKind::File),
+            "http" => Ok(ProviderKind::Http),
+            "simulated" => Ok(ProviderKind::Simulated),
+            other => Err(format!("unknown quote provider {other:?}")),
+        }
+    }
+}
+
+/// Build the fetcher chain described by `config`, trying providers in the
+/// order of `config.chain`.
+pub fn build(config: &ProvidersConfig) -> anyhow::Result<Arc<dyn QuoteFetcher>> {
+    let mut fetchers: Vec<Arc<dyn QuoteFetcher>> = Vec::new();
+    for kind in &config.chain {
+        let fetcher: Arc<dyn QuoteFetcher> = match kind {
+            ProviderKind::Yahoo => Arc::new(YahooFetcher::new()?),
+            ProviderKind::File => {
+                let file = config.file.as_ref().context("the file provider is not configured")?;
+                Arc::new(FileFetcher::new(file.dir.clone()))
+            }
+            ProviderKind::Http => {
+                let http = config.http.as_ref().context("the http provider is not configured")?;
+                let mut fetcher = HttpJsonFetcher::new(http.url.clone(), http.fields.clone());
+                if let Some(history) = &http.history_url {
+                    fetcher = fetcher.with_history_url(history.clone());
+                }
+                Arc::new(fetcher)
+            }
+            ProviderKind::Simulated => Arc::new(SimulatedFetcher::new(config.simulated.clone())),
+        };
+        fetchers.push(fetcher);
+    }
+    Ok(match fetchers.len() {
+        1 => fetchers.remove(0),
+        _ => Arc::new(FallbackFetcher::new(fetchers)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestFetcher;
+    use mockito::Server;
+    use tempfile::tempdir;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn file_fetcher_replays_csv() {
+        let dir = tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("AAPL.csv"),
+            "date,open,high,low,close,volume\n2024-01-03,11,13,10,12,200\n2024-01-02,10,11,9,10.5,100\n",
+        )
+        .unwrap();
+        let fetcher = FileFetcher::new(dir.path().to_path_buf());
+
+        let latest = fetcher.fetch_quotes("AAPL").await.unwrap();
+        assert_eq!(latest.len(), 1);
+        assert_eq!((latest[0].close, latest[0].high, latest[0].volume), (12.0, 13.0, 200));
+
+        let history = fetcher.fetch_history("AAPL", date("2024-01-01"), date("2024-01-02")).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].close, 10.5);
+        assert_eq!(history[0].adjclose, 10.5);
+
+        assert!(fetcher.fetch_quotes("MSFT").await.is_err());
+        assert!(fetcher.fetch_quotes("../AAPL").await.is_err());
+
+        // parsed bars are kept after the first read
+        std::fs::remove_file(dir.path().join("AAPL.csv")).unwrap();
+        assert_eq!(fetcher.fetch_quotes("AAPL").await.unwrap(), latest);
+    }
+
+    #[tokio::test]
+    async fn http_fetcher_maps_fields() {
+        let mut server = Server::new_async().await;
+        let m = server
+            .mock("GET", "/quote/AAPL")
+            .with_status(200)
+            .with_body(r#"{"data":{"points":[{"t":"2024-01-02","c":"10.5","v":7},{"t":1704240000,"c":11}]}}"#)
+            .create_async()
+            .await;
+        let fields = FieldMapping {
+            quotes: "/data/points".into(),
+            timestamp: "/t".into(),
+            close: "/c".into(),
+            volume: "/v".into(),
+            ..FieldMapping::default()
+        };
+        let fetcher = HttpJsonFetcher::new(format!("{}/quote/{{symbol}}", server.url()), fields);
+
+        let quotes = fetcher.fetch_quotes("AAPL").await.unwrap();
+        m.assert_async().await;
+        assert_eq!(quotes.len(), 2);
+        assert_eq!((quotes[0].timestamp, quotes[0].close, quotes[0].open, quotes[0].volume), (1704153600, 10.5, 10.5, 7));
+        assert_eq!((quotes[1].timestamp, quotes[1].close), (1704240000, 11.0));
+        // no history endpoint configured
+        assert!(fetcher.fetch_history("AAPL", date("2024-01-01"), date("2024-01-02")).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn http_fetcher_fills_history_template() {
+        let mut server = Server::new_async().await;
+        let m = server
+            .mock("GET", "/history/AAPL/2024-01-01/2024-01-31")
+            .with_status(200)
+            .with_body(r#"[{"timestamp":1704153600,"close":10}]"#)
+            .create_async()
+            .await;
+        let fetcher = HttpJsonFetcher::new(format!("{}/quote/{{symbol}}", server.url()), FieldMapping::default())
+            .with_history_url(format!("{}/history/{{symbol}}/{{start}}/{{end}}", server.url()));
+
+        let quotes = fetcher.fetch_history("AAPL", date("2024-01-01"), date("2024-01-31")).await.unwrap();
+        m.assert_async().await;
+        assert_eq!(quotes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn http_fetcher_encodes_symbols() {
+        let mut server = Server::new_async().await;
+        let index = server
+            .mock("GET", "/quote/%5EGSPC")
+            .with_status(200)
+            .with_body(r#"{"timestamp":1704153600,"close":10}"#)
+            .create_async()
+            .await;
+        let fetcher = HttpJsonFetcher::new(format!("{}/quote/{{symbol}}", server.url()), FieldMapping::default());
+        assert_eq!(fetcher.fetch_quotes("^GSPC").await.unwrap().len(), 1);
+        index.assert_async().await;
+
+        let pair = server
+            .mock("GET", "/quote")
+            .match_query(mockito::Matcher::UrlEncoded("pair".into(), "EURUSD=X".into()))
+            .with_status(200)
+            .with_body(r#"{"timestamp":1704153600,"close":1.1}"#)
+            .create_async()
+            .await;
+        let fetcher = HttpJsonFetcher::new(format!("{}/quote?pair={{symbol}}", server.url()), FieldMapping::default());
+        assert_eq!(fetcher.fetch_quotes("EURUSD=X").await.unwrap()[0].close, 1.1);
+        pair.assert_async().await;
+    }
+
+    #[test]
+    fn simulated_paths_are_reproducible() {
+        let params = SimulationParams { seed: 42, ..SimulationParams::default() };
+        let until = date("2020-12-31");
+        let a = SimulatedFetcher::new(params.clone()).bars("AAPL", until);
+        let b = SimulatedFetcher::new(params.clone()).bars("AAPL", until);
+        assert_eq!(a.len(), 366);
+        assert_eq!(a, b);
+        // extending a path generated up to an earlier day gives the same bars
+        let extended = SimulatedFetcher::new(params.clone());
+        assert_eq!(extended.bars("AAPL", date("2020-06-30")), a[..182]);
+        assert_eq!(extended.bars("AAPL", until), a);
+        assert_eq!(a[0].open, 100.0);
+        assert!(a.iter().all(|bar| bar.low <= bar.open.min(bar.close) && bar.high >= bar.open.max(bar.close)));
+        assert!(a.windows(2).all(|w| w[1].open == w[0].close));
+
+        let other_symbol = SimulatedFetcher::new(params.clone()).bars("MSFT", until);
+        assert_ne!(a.last().unwrap().close, other_symbol.last().unwrap().close);
+        let other_seed = SimulatedFetcher::new(SimulationParams { seed: 7, ..params }).bars("AAPL", until);
+        assert_ne!(a.last().unwrap().close, other_seed.last().unwrap().close);
+    }
+
+    #[test]
+    fn simulated_path_without_volatility_follows_drift() {
+        let params = SimulationParams { drift: 0.1, volatility: 0.0, ..SimulationParams::default() };
+        let bars = SimulatedFetcher::new(params).bars("AAPL", date("2020-12-31"));
+        let expected = 100.0 * (0.1f64 * 366.0 / 365.0).exp();
+        assert!((bars.last().unwrap().close - expected).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn simulated_history_matches_path() {
+        let fetcher = SimulatedFetcher::new(SimulationParams::default());
+        let history = fetcher.fetch_history("AAPL", date("2020-03-01"), date("2020-03-07")).await.unwrap();
+        let bars = fetcher.bars("AAPL", date("2020-03-07"));
+        assert_eq!(history.len(), 7);
+        assert_eq!(history.last().unwrap().close, bars.last().unwrap().close);
+        assert_eq!(fetcher.fetch_quotes("AAPL").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fallback_tries_next_fetcher() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("AAPL.csv"), "date,close\n2024-01-02,10\n").unwrap();
+        let down = TestFetcher::new().with_failing_symbol("AAPL").with_failing_symbol("MSFT");
+        let chain = FallbackFetcher::new(vec![Arc::new(down), Arc::new(FileFetcher::new(dir.path().to_path_buf()))]);
+
+        assert_eq!(chain.fetch_quotes("AAPL").await.unwrap()[0].close, 10.0);
+        assert!(chain.lookup_instrument("AAPL").await.unwrap().is_some());
+        let err = chain.fetch_quotes("MSFT").await.unwrap_err().to_string();
+        assert!(err.contains("no data for MSFT"));
+        assert!(err.contains("no price file for MSFT"));
+    }
+}
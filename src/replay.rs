@@ -0,0 +1,112 @@
+//! Replay of a past period over stored daily bars.
+//!
+//! While a replay session exists, [`crate::market::MarketData`] stops
+//! fetching live quotes and serves the stored bar of the session's current
+//! day instead, and holdings are valued at the virtual time. Trading is
+//! paused for the length of the session, so a replay never adds orders to
+//! the users' live portfolios.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Time of day the virtual clock reports: after the US close, so the bar of
+/// the current day is complete.
+const REPLAY_HOUR_UTC: u32 = 21;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("no replay is running")]
+    NotActive,
+    #[error("orders are not accepted while a replay is running")]
+    Trading,
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayStatus {
+    Running,
+    Paused,
+    /// The clock reached the last day of the session.
+    Finished,
+}
+
+/// State of the replay clock.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplaySession {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    /// Day the market is currently replaying.
+    pub current: NaiveDate,
+    pub status: ReplayStatus,
+    /// Real seconds between replayed days while running.
+    pub step_secs: u64,
+}
+
+/// Seconds between replayed days unless the request sets `step_secs`.
+pub const DEFAULT_REPLAY_STEP_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StartReplayRequest {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    #[serde(default)]
+    pub step_secs: Option<u64>,
+}
+
+impl ReplaySession {
+    pub fn new(req: StartReplayRequest) -> Result<Self, ReplayError> {
+        if req.start > req.end {
+            return Err(ReplayError::Invalid(format!("start {} is after end {}", req.start, req.end)));
+        }
+        let step_secs = req.step_secs.unwrap_or(DEFAULT_REPLAY_STEP_SECS);
+        if step_secs == 0 {
+            return Err(ReplayError::Invalid("step_secs must be positive".into()));
+        }
+        Ok(Self { start: req.start, end: req.end, current: req.start, status: ReplayStatus::Running, step_secs })
+    }
+
+    /// Virtual time of the session.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.current.and_hms_opt(REPLAY_HOUR_UTC, 0, 0).expect("valid time").and_utc()
+    }
+
+    /// Move the clock one day forward, finishing on the last day.
+    pub fn advance(&mut self) {
+        if self.current < self.end {
+            self.current = self.current.succ_opt().expect("date in range");
+        }
+        if self.current >= self.end {
+            self.status = ReplayStatus::Finished;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    #[test]
+    fn advances_until_end() {
+        let mut session = ReplaySession::new(StartReplayRequest { start: date(1), end: date(3), step_secs: None }).unwrap();
+        assert_eq!(session.now().to_rfc3339(), "2024-01-01T21:00:00+00:00");
+        session.advance();
+        assert_eq!((session.current, session.status), (date(2), ReplayStatus::Running));
+        session.advance();
+        session.advance();
+        assert_eq!((session.current, session.status), (date(3), ReplayStatus::Finished));
+    }
+
+    #[test]
+    fn rejects_invalid_sessions() {
+        assert!(ReplaySession::new(StartReplayRequest { start: date(3), end: date(1), step_secs: None }).is_err());
+        assert!(ReplaySession::new(StartReplayRequest { start: date(1), end: date(3), step_secs: Some(0) }).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use crate::holdings::HoldingStore;
 use crate::market::MarketData;
 use crate::portfolio::HoldingsService;
 use crate::activity::ActivityStore;
+use crate::league::LeagueStore;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -11,4 +12,8 @@ pub struct AppState {
     pub market: Arc<MarketData>,
     pub holdings: HoldingsService,
     pub activities: ActivityStore,
+    pub leagues: LeagueStore,
+    /// Token required in the `x-admin-token` header by admin endpoints;
+    /// admin endpoints are disabled when unset.
+    pub admin_token: Option<String>,
 }
@@ -0,0 +1,562 @@
+//! Parquet files under the data directory.
+//!
+//! Orders live in `<user>/orders.parquet` plus an append-only
+//! `<user>/orders.log`, prices in `<market dir>/<SYMBOL>/prices.parquet` and
+//! activities in a single `activities.parquet`. User and symbol directory
+//! names are encoded with [`encode_segment`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError};
+
+use anyhow::Context;
+use axum::async_trait;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use super::{ActivityStorage, OrderStorage, PriceStorage};
+use crate::activity::{activities_to_record_batch, batch_to_activities, Activity};
+use crate::holdings::{batch_to_orders, orders_to_record_batch, Order};
+use crate::ident::{decode_segment, encode_segment, IdError};
+use crate::market::{bars_to_record_batch, batch_to_bars, DailyBar};
+
+/// Orders appended to a user's log before it is compacted into Parquet.
+pub const DEFAULT_COMPACT_AFTER: usize = 100;
+
+const ORDERS_FILE: &str = "orders.parquet";
+/// Append-only log of orders not yet compacted into [`ORDERS_FILE`], one JSON
+/// object per line.
+const ORDER_LOG_FILE: &str = "orders.log";
+
+/// One async mutex per key, such as a user or a symbol, so the files of
+/// different keys can be read and written at the same time. Each mutex guards
+/// a `T` of per-key state. Mutexes are created on first use and kept for the
+/// life of the store.
+pub(crate) struct KeyedLocks<T = ()> {
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<T>>>>,
+}
+
+impl<T: Default> Default for KeyedLocks<T> {
+    fn default() -> Self {
+        Self { locks: std::sync::Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<T: Default> KeyedLocks<T> {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait for the mutex of `key`.
+    pub(crate) async fn lock(&self, key: &str) -> OwnedMutexGuard<T> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            locks.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// Run `f`, which does blocking file I/O, on Tokio's blocking thread pool.
+pub(crate) async fn blocking<T, F>(f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> anyhow::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.context("file task panicked")?
+}
+
+/// Directory of `id`'s files under `dir`.
+pub(crate) fn entity_dir(dir: &Path, id: &str) -> PathBuf {
+    dir.join(encode_segment(id))
+}
+
+/// Rename the directories under `dir` that still carry a raw id, such as
+/// `j.doe` or `BRK.B` from before names were encoded, to the encoded name.
+/// Only directories holding one of `file_names`, named by an id accepted by
+/// `validate` and not listed in `keep` are moved, and never onto an existing
+/// directory. Returns the number of directories renamed.
+pub(crate) fn encode_legacy_dirs(
+    dir: &Path,
+    validate: fn(&str) -> Result<(), IdError>,
+    file_names: &[&str],
+    keep: &[PathBuf],
+) -> std::io::Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut renamed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let Some(id) = entry.file_name().to_str().map(str::to_string) else { continue };
+        let encoded = encode_segment(&id);
+        let holds_data = file_names.iter().any(|name| path.join(name).is_file());
+        if encoded == id || !holds_data || validate(&id).is_err() || keep.iter().any(|k| same_path(k, &path)) {
+            continue;
+        }
+        let target = dir.join(&encoded);
+        if target.exists() {
+            tracing::warn!("not moving {} because {} already exists", path.display(), target.display());
+            continue;
+        }
+        std::fs::rename(&path, &target)?;
+        tracing::info!("moved {} to {}", path.display(), target.display());
+        renamed += 1;
+    }
+    Ok(renamed)
+}
+
+/// Whether `a` and `b` name the same existing directory, however spelled.
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Names of the users under `data_dir` that have any of `file_names`.
+pub(crate) fn users_with_files(data_dir: &Path, file_names: &[&str]) -> std::io::Result<Vec<String>> {
+    if !data_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut users = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if !file_names.iter().any(|name| entry.path().join(name).is_file()) {
+            continue;
+        }
+        let name = entry.file_name();
+        match name.to_str().and_then(decode_segment) {
+            Some(user) => users.push(user),
+            None => tracing::warn!("skipping user directory {name:?} with an unencoded name"),
+        }
+    }
+    users.sort();
+    Ok(users)
+}
+
+/// Read every batch of the Parquet file at `path`.
+fn read_batches(path: &Path) -> anyhow::Result<Vec<arrow_array::RecordBatch>> {
+    let file = std::fs::File::open(path)?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+    Ok(reader.collect::<Result<_, _>>()?)
+}
+
+/// Write `batch` next to `path` and rename it over `path`, so a crash leaves
+/// either the old file or the new one.
+pub(crate) fn write_atomically(path: &Path, batch: &arrow_array::RecordBatch) -> anyhow::Result<()> {
+    let dir = path.parent().context("file has no parent directory")?;
+    std::fs::create_dir_all(dir)?;
+    let mut tmp_name = path.file_name().context("file has no name")?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+
+    let file = std::fs::File::create(&tmp_path)?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+    writer.write(batch)?;
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Append `line` to the file at `path`, creating it and its directory if
+/// needed, and sync it before returning.
+pub(crate) fn append_line(path: &Path, line: &[u8]) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    std::fs::create_dir_all(path.parent().context("file has no parent directory")?)?;
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Remove the file at `path` if there is one.
+pub(crate) fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Orders per user.
+///
+/// New orders are appended to `<user>/orders.log` and synced before they are
+/// acknowledged. Once a log holds `compact_after` orders, all of the user's
+/// orders are written to a temporary file that is atomically renamed over
+/// `<user>/orders.parquet`, and the log is removed. Reading a user replays
+/// whatever is left in the log, e.g. after a crash, and compacts it.
+///
+/// Each user's files are guarded by their own lock, so different users never
+/// wait on each other, and all file I/O runs on the blocking thread pool.
+pub struct ParquetOrderStorage {
+    data_dir: PathBuf,
+    /// Per user, the number of orders in their log since it was last compacted.
+    user_locks: KeyedLocks<usize>,
+    compact_after: usize,
+}
+
+impl ParquetOrderStorage {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self { data_dir, user_locks: KeyedLocks::new(), compact_after: DEFAULT_COMPACT_AFTER }
+    }
+
+    /// Compact a user's order log once it holds `orders` orders.
+    pub fn with_compact_after(mut self, orders: usize) -> Self {
+        self.compact_after = orders;
+        self
+    }
+}
+
+/// Orders of `user` from Parquet followed by any orders left in the log, and
+/// whether there was a log.
+///
+/// Logged orders already in the Parquet file, left behind when a crash
+/// interrupted compaction, are skipped. A torn last line from a crash during
+/// an append is dropped.
+fn read_user_orders(user_dir: &Path, user: &str) -> anyhow::Result<(Vec<Order>, bool)> {
+    let file_path = user_dir.join(ORDERS_FILE);
+    let log_path = user_dir.join(ORDER_LOG_FILE);
+
+    let mut orders = Vec::new();
+    if file_path.exists() {
+        for batch in read_batches(&file_path)? {
+            let decoded = batch_to_orders(&batch, orders.len())?;
+            orders.extend(decoded);
+        }
+    }
+    if !log_path.exists() {
+        return Ok((orders, false));
+    }
+
+    let log = std::fs::read_to_string(&log_path)?;
+    let mut seen: HashSet<String> = orders.iter().map(|o| o.id.clone()).collect();
+    let lines: Vec<&str> = log.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Order>(line) {
+            Ok(order) => {
+                if seen.insert(order.id.clone()) {
+                    orders.push(order);
+                }
+            }
+            Err(e) if i + 1 == lines.len() && !log.ends_with('\n') => {
+                tracing::warn!("dropping incomplete order log entry for {user}: {e}");
+            }
+            Err(e) => return Err(e).with_context(|| format!("corrupt order log entry on line {}", i + 1)),
+        }
+    }
+    Ok((orders, true))
+}
+
+/// Write `orders` as all of the orders in `user_dir` and drop the log.
+fn compact_user_orders(user_dir: &Path, orders: &[Order]) -> anyhow::Result<()> {
+    write_atomically(&user_dir.join(ORDERS_FILE), &orders_to_record_batch(orders)?)?;
+    Ok(remove_if_exists(&user_dir.join(ORDER_LOG_FILE))?)
+}
+
+#[async_trait]
+impl OrderStorage for ParquetOrderStorage {
+    async fn users(&self) -> anyhow::Result<Vec<String>> {
+        let data_dir = self.data_dir.clone();
+        blocking(move || {
+            users_with_files(&data_dir, &[ORDERS_FILE, ORDER_LOG_FILE]).context("failed to scan for order files")
+        })
+        .await
+    }
+
+    async fn orders(&self, user: &str) -> anyhow::Result<Vec<Order>> {
+        let mut logged = self.user_locks.lock(user).await;
+        let user_dir = entity_dir(&self.data_dir, user);
+        let user = user.to_string();
+        let orders = blocking(move || {
+            let (orders, recovered) = read_user_orders(&user_dir, &user)?;
+            if recovered {
+                compact_user_orders(&user_dir, &orders)
+                    .with_context(|| format!("failed to compact orders for {user}"))?;
+            }
+            Ok(orders)
+        })
+        .await?;
+        *logged = 0;
+        Ok(orders)
+    }
+
+    async fn append_order(&self, order: &Order) -> anyhow::Result<()> {
+        let mut logged = self.user_locks.lock(&order.user).await;
+        let user_dir = entity_dir(&self.data_dir, &order.user);
+        let mut line = serde_json::to_vec(order)?;
+        line.push(b'\n');
+        let log_path = user_dir.join(ORDER_LOG_FILE);
+        blocking(move || append_line(&log_path, &line)).await?;
+
+        *logged += 1;
+        if *logged >= self.compact_after {
+            let user = order.user.clone();
+            blocking(move || {
+                let (orders, _) = read_user_orders(&user_dir, &user)?;
+                compact_user_orders(&user_dir, &orders)
+            })
+            .await
+            .context("failed to compact orders")?;
+            *logged = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Daily bars in `<data_dir>/<SYMBOL>/prices.parquet`, each symbol behind its
+/// own lock.
+pub struct ParquetPriceStorage {
+    data_dir: PathBuf,
+    symbol_locks: KeyedLocks,
+}
+
+impl ParquetPriceStorage {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self { data_dir, symbol_locks: KeyedLocks::new() }
+    }
+}
+
+fn read_bars_file(path: &Path) -> anyhow::Result<Vec<DailyBar>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut bars = Vec::new();
+    for batch in read_batches(path)? {
+        bars.extend(batch_to_bars(&batch)?);
+    }
+    Ok(bars)
+}
+
+#[async_trait]
+impl PriceStorage for ParquetPriceStorage {
+    async fn bars(&self, symbol: &str) -> anyhow::Result<Vec<DailyBar>> {
+        let _lock = self.symbol_locks.lock(symbol).await;
+        let path = entity_dir(&self.data_dir, symbol).join("prices.parquet");
+        blocking(move || read_bars_file(&path)).await
+    }
+
+    async fn upsert_bars(&self, symbol: &str, bars: &[DailyBar]) -> anyhow::Result<()> {
+        let _lock = self.symbol_locks.lock(symbol).await;
+        let path = entity_dir(&self.data_dir, symbol).join("prices.parquet");
+        let bars = bars.to_vec();
+        blocking(move || {
+            let mut by_date: BTreeMap<_, _> = read_bars_file(&path)?.into_iter().map(|b| (b.date, b)).collect();
+            by_date.extend(bars.into_iter().map(|b| (b.date, b)));
+            let merged: Vec<DailyBar> = by_date.into_values().collect();
+            write_atomically(&path, &bars_to_record_batch(&merged)?)
+        })
+        .await
+    }
+}
+
+/// Activities in a single Parquet file, rewritten on every save.
+///
+/// The file is read once, on first use; afterwards lookups are served from
+/// memory and saves update the copy in memory once the file is written.
+pub struct ParquetActivityStorage {
+    path: PathBuf,
+    /// Activities by id, `None` until the file has been read.
+    cache: Mutex<Option<BTreeMap<String, Activity>>>,
+}
+
+impl ParquetActivityStorage {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, cache: Mutex::new(None) }
+    }
+
+    /// The cached activities, reading the file if this is the first access.
+    async fn loaded<'a>(
+        &self,
+        cache: &'a mut Option<BTreeMap<String, Activity>>,
+    ) -> anyhow::Result<&'a mut BTreeMap<String, Activity>> {
+        if cache.is_none() {
+            let path = self.path.clone();
+            let activities = blocking(move || read_activities_file(&path)).await?;
+            *cache = Some(activities.into_iter().map(|a| (a.id.clone(), a)).collect());
+        }
+        Ok(cache.as_mut().expect("activities were just loaded"))
+    }
+}
+
+fn read_activities_file(path: &Path) -> anyhow::Result<Vec<Activity>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut activities = Vec::new();
+    for batch in read_batches(path)? {
+        activities.extend(batch_to_activities(&batch)?);
+    }
+    Ok(activities)
+}
+
+#[async_trait]
+impl ActivityStorage for ParquetActivityStorage {
+    async fn activity(&self, id: &str) -> anyhow::Result<Option<Activity>> {
+        let mut cache = self.cache.lock().await;
+        Ok(self.loaded(&mut cache).await?.get(id).cloned())
+    }
+
+    async fn save_activity(&self, activity: &Activity) -> anyhow::Result<()> {
+        let mut cache = self.cache.lock().await;
+        let by_id = self.loaded(&mut cache).await?;
+        let mut updated = by_id.clone();
+        updated.insert(activity.id.clone(), activity.clone());
+        let (path, activities): (_, Vec<Activity>) = (self.path.clone(), updated.values().cloned().collect());
+        blocking(move || write_atomically(&path, &activities_to_record_batch(&activities)?)).await?;
+        *by_id = updated;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holdings::Side;
+    use tempfile::tempdir;
+
+    #[test]
+    fn atomic_writes_replace_the_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("alice").join(ORDERS_FILE);
+        let first = vec![Order::new("alice", "AAPL", Side::Buy, 1, 10.0)];
+        let second = vec![Order::new("alice", "MSFT", Side::Sell, 2, 11.0)];
+        write_atomically(&path, &orders_to_record_batch(&first).unwrap()).unwrap();
+        write_atomically(&path, &orders_to_record_batch(&second).unwrap()).unwrap();
+
+        let batches = read_batches(&path).unwrap();
+        assert_eq!(batch_to_orders(&batches[0], 0).unwrap(), second);
+        let names: Vec<_> = std::fs::read_dir(path.parent().unwrap()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, [ORDERS_FILE]);
+    }
+
+    #[tokio::test]
+    async fn appends_to_log_and_compacts() {
+        let dir = tempdir().unwrap();
+        let user_dir = dir.path().join("alice");
+        let storage = ParquetOrderStorage::new(dir.path().to_path_buf()).with_compact_after(2);
+        let first = Order::new("alice", "AAPL", Side::Buy, 1, 10.0);
+        storage.append_order(&first).await.unwrap();
+        assert!(user_dir.join(ORDER_LOG_FILE).exists());
+        assert!(!user_dir.join(ORDERS_FILE).exists());
+
+        let second = Order::new("alice", "AAPL", Side::Buy, 2, 11.0);
+        storage.append_order(&second).await.unwrap();
+        assert!(!user_dir.join(ORDER_LOG_FILE).exists());
+        assert!(user_dir.join(ORDERS_FILE).exists());
+
+        let third = Order::new("alice", "MSFT", Side::Buy, 1, 12.0);
+        storage.append_order(&third).await.unwrap();
+        let reopened = ParquetOrderStorage::new(dir.path().to_path_buf());
+        assert_eq!(reopened.orders("alice").await.unwrap(), vec![first, second, third]);
+    }
+
+    #[tokio::test]
+    async fn recovers_orders_from_log() {
+        use std::io::Write;
+
+        let dir = tempdir().unwrap();
+        let user_dir = dir.path().join("alice");
+        let storage = ParquetOrderStorage::new(dir.path().to_path_buf()).with_compact_after(1);
+        let compacted = Order::new("alice", "AAPL", Side::Buy, 1, 10.0);
+        storage.append_order(&compacted).await.unwrap();
+
+        // a crash after the rename but before the log was removed, then a
+        // crash in the middle of appending another order
+        let logged = Order::new("alice", "AAPL", Side::Buy, 2, 11.0);
+        let mut log = std::fs::File::create(user_dir.join(ORDER_LOG_FILE)).unwrap();
+        for order in [&compacted, &logged] {
+            writeln!(log, "{}", serde_json::to_string(order).unwrap()).unwrap();
+        }
+        write!(log, "{{\"id\":\"torn").unwrap();
+        drop(log);
+
+        let restarted = ParquetOrderStorage::new(dir.path().to_path_buf());
+        assert_eq!(restarted.users().await.unwrap(), vec!["alice"]);
+        assert_eq!(restarted.orders("alice").await.unwrap(), vec![compacted.clone(), logged.clone()]);
+        assert!(!user_dir.join(ORDER_LOG_FILE).exists());
+        assert_eq!(restarted.orders("alice").await.unwrap(), vec![compacted, logged]);
+
+        std::fs::write(user_dir.join(ORDER_LOG_FILE), "not json\n{}\n").unwrap();
+        assert!(restarted.orders("alice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn users_do_not_wait_on_each_other() {
+        use std::time::Duration;
+        use tokio::time::timeout;
+
+        let dir = tempdir().unwrap();
+        let storage = Arc::new(ParquetOrderStorage::new(dir.path().to_path_buf()));
+        let alice = Order::new("alice", "AAPL", Side::Buy, 1, 10.0);
+        let bob = Order::new("bob", "AAPL", Side::Buy, 1, 10.0);
+
+        let held = storage.user_locks.lock("alice").await;
+        timeout(Duration::from_secs(10), storage.append_order(&bob)).await.unwrap().unwrap();
+        let waiting = tokio::spawn({
+            let (storage, alice) = (storage.clone(), alice.clone());
+            async move { storage.append_order(&alice).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(held);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(storage.orders("alice").await.unwrap(), vec![alice]);
+        assert_eq!(storage.orders("bob").await.unwrap(), vec![bob]);
+    }
+
+    #[tokio::test]
+    async fn encodes_user_and_symbol_directories() {
+        let dir = tempdir().unwrap();
+        let orders = ParquetOrderStorage::new(dir.path().to_path_buf());
+        let order = Order::new("j.doe", "BRK.B", Side::Buy, 1, 10.0);
+        orders.append_order(&order).await.unwrap();
+        assert!(dir.path().join("j%2Edoe").join(ORDER_LOG_FILE).exists());
+        assert_eq!(orders.users().await.unwrap(), vec!["j.doe"]);
+
+        // an unvalidated name still stays inside the data directory
+        let sneaky = Order::new("../escaped", "AAPL", Side::Buy, 1, 10.0);
+        orders.append_order(&sneaky).await.unwrap();
+        assert!(!dir.path().parent().unwrap().join("escaped").exists());
+        assert_eq!(orders.orders("../escaped").await.unwrap(), vec![sneaky]);
+
+        let prices = ParquetPriceStorage::new(dir.path().join("market"));
+        let bar = DailyBar {
+            date: chrono::NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+            open: 1.0,
+            high: 1.0,
+            low: 1.0,
+            close: 1.0,
+            adjclose: 1.0,
+            volume: 1,
+        };
+        prices.upsert_bars("^GSPC", std::slice::from_ref(&bar)).await.unwrap();
+        assert!(dir.path().join("market/%5EGSPC/prices.parquet").exists());
+        assert_eq!(prices.bars("^GSPC").await.unwrap(), vec![bar]);
+    }
+
+    #[tokio::test]
+    async fn activities_are_read_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("activities.parquet");
+        let activity = |id: &str| Activity { id: id.into(), metadata: String::new(), heart_rate: vec![60], power: vec![], gps: vec![] };
+        ParquetActivityStorage::new(path.clone()).save_activity(&activity("1")).await.unwrap();
+
+        let storage = ParquetActivityStorage::new(path.clone());
+        assert_eq!(storage.activity("1").await.unwrap(), Some(activity("1")));
+        // later lookups come from memory, saves still reach the file
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(storage.activity("1").await.unwrap(), Some(activity("1")));
+        storage.save_activity(&activity("2")).await.unwrap();
+        let reopened = ParquetActivityStorage::new(path);
+        assert_eq!(reopened.activity("1").await.unwrap(), Some(activity("1")));
+        assert_eq!(reopened.activity("2").await.unwrap(), Some(activity("2")));
+    }
+}
@@ -0,0 +1,67 @@
+//! Storage that lives only as long as the process.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::async_trait;
+use chrono::NaiveDate;
+use tokio::sync::RwLock;
+
+use super::{ActivityStorage, OrderStorage, PriceStorage};
+use crate::activity::Activity;
+use crate::holdings::Order;
+use crate::market::DailyBar;
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    orders: RwLock<BTreeMap<String, Vec<Order>>>,
+    bars: RwLock<HashMap<String, BTreeMap<NaiveDate, DailyBar>>>,
+    activities: RwLock<HashMap<String, Activity>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl OrderStorage for MemoryStorage {
+    async fn users(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.orders.read().await.keys().cloned().collect())
+    }
+
+    async fn orders(&self, user: &str) -> anyhow::Result<Vec<Order>> {
+        Ok(self.orders.read().await.get(user).cloned().unwrap_or_default())
+    }
+
+    async fn append_order(&self, order: &Order) -> anyhow::Result<()> {
+        self.orders.write().await.entry(order.user.clone()).or_default().push(order.clone());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PriceStorage for MemoryStorage {
+    async fn bars(&self, symbol: &str) -> anyhow::Result<Vec<DailyBar>> {
+        let bars = self.bars.read().await;
+        Ok(bars.get(symbol).map(|b| b.values().cloned().collect()).unwrap_or_default())
+    }
+
+    async fn upsert_bars(&self, symbol: &str, bars: &[DailyBar]) -> anyhow::Result<()> {
+        let mut stored = self.bars.write().await;
+        stored.entry(symbol.to_string()).or_default().extend(bars.iter().map(|b| (b.date, b.clone())));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ActivityStorage for MemoryStorage {
+    async fn activity(&self, id: &str) -> anyhow::Result<Option<Activity>> {
+        Ok(self.activities.read().await.get(id).cloned())
+    }
+
+    async fn save_activity(&self, activity: &Activity) -> anyhow::Result<()> {
+        self.activities.write().await.insert(activity.id.clone(), activity.clone());
+        Ok(())
+    }
+}
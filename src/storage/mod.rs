@@ -0,0 +1,285 @@
+//! Persistence of orders, daily prices and activities.
+//!
+//! Each entity has its own trait so the stores only see the operations they
+//! need. Three backends implement them: Parquet files under the data
+//! directory (the original layout), an embedded SQLite database and plain
+//! memory, which keeps nothing across restarts and suits tests.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::async_trait;
+use chrono::NaiveDate;
+use serde::Deserialize;
+
+use crate::activity::Activity;
+use crate::config::DataConfig;
+use crate::holdings::Order;
+use crate::market::DailyBar;
+
+pub mod files;
+pub mod memory;
+pub mod sqlite;
+
+pub use files::{ParquetActivityStorage, ParquetOrderStorage, ParquetPriceStorage};
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+/// Executed orders, appended per user.
+#[async_trait]
+pub trait OrderStorage: Send + Sync {
+    /// Users with at least one stored order, sorted by name.
+    async fn users(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Orders of `user` in the order they were appended.
+    async fn orders(&self, user: &str) -> anyhow::Result<Vec<Order>>;
+
+    /// Store `order` durably before returning.
+    async fn append_order(&self, order: &Order) -> anyhow::Result<()>;
+}
+
+/// Daily bars per symbol.
+#[async_trait]
+pub trait PriceStorage: Send + Sync {
+    /// Stored bars of `symbol`, oldest first.
+    async fn bars(&self, symbol: &str) -> anyhow::Result<Vec<DailyBar>>;
+
+    /// Store `bars`, replacing stored bars of the same dates.
+    async fn upsert_bars(&self, symbol: &str, bars: &[DailyBar]) -> anyhow::Result<()>;
+
+    /// Stored bars of `symbol` between `from` and `to` inclusive, oldest first.
+    async fn bars_between(
+        &self,
+        symbol: &str,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> anyhow::Result<Vec<DailyBar>> {
+        let mut bars = self.bars(symbol).await?;
+        bars.retain(|b| from.is_none_or(|f| b.date >= f) && to.is_none_or(|t| b.date <= t));
+        Ok(bars)
+    }
+}
+
+/// Recorded activities, keyed by id.
+#[async_trait]
+pub trait ActivityStorage: Send + Sync {
+    async fn activity(&self, id: &str) -> anyhow::Result<Option<Activity>>;
+
+    /// Store `activity`, replacing any activity with the same id.
+    async fn save_activity(&self, activity: &Activity) -> anyhow::Result<()>;
+}
+
+/// Storage backend names accepted in the configuration.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Parquet,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "parquet" => Ok(StorageBackend::Parquet),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            other => Err(format!("unknown storage backend {other:?}")),
+        }
+    }
+}
+
+/// Storage for every entity, usually backed by the same backend.
+#[derive(Clone)]
+pub struct Storage {
+    pub orders: Arc<dyn OrderStorage>,
+    pub prices: Arc<dyn PriceStorage>,
+    pub activities: Arc<dyn ActivityStorage>,
+}
+
+impl Storage {
+    /// Use `backend` for every entity.
+    pub fn shared<B>(backend: Arc<B>) -> Self
+    where
+        B: OrderStorage + PriceStorage + ActivityStorage + 'static,
+    {
+        Self { orders: backend.clone(), prices: backend.clone(), activities: backend }
+    }
+}
+
+/// Move user and symbol directories written before ids were encoded to
+/// their encoded names. The per-user holdings and pending order files live
+/// under the data directory whichever backend is selected, so this runs for
+/// all of them. The configured market and leagues directories are never
+/// moved. Returns the number of directories moved.
+pub fn migrate_legacy_dirs(config: &DataConfig) -> anyhow::Result<usize> {
+    let market_dir = config.market_dir();
+    let keep = [market_dir.clone(), config.leagues_dir()];
+    let user_files = ["orders.parquet", "orders.log", "pending.parquet", "holdings.parquet", "holdings.log"];
+    let users = files::encode_legacy_dirs(&config.dir, crate::ident::validate_user, &user_files, &keep)
+        .with_context(|| format!("failed to migrate user directories in {}", config.dir.display()))?;
+    let symbols = files::encode_legacy_dirs(&market_dir, crate::ident::validate_symbol, &["prices.parquet"], &keep)
+        .with_context(|| format!("failed to migrate symbol directories in {}", market_dir.display()))?;
+    Ok(users + symbols)
+}
+
+/// Open the backend selected by `config`.
+///
+/// Only executed orders, prices and activities go through the backend.
+/// Pending orders, holdings snapshots and leagues are always Parquet files
+/// under the data directory.
+pub fn build(config: &DataConfig) -> anyhow::Result<Storage> {
+    if config.backend != StorageBackend::Parquet {
+        tracing::info!(
+            "pending orders, holdings snapshots and leagues are still stored as Parquet under {}",
+            config.dir.display()
+        );
+    }
+    Ok(match config.backend {
+        StorageBackend::Parquet => Storage {
+            orders: Arc::new(ParquetOrderStorage::new(config.dir.clone()).with_compact_after(config.compact_orders_after)),
+            prices: Arc::new(ParquetPriceStorage::new(config.market_dir())),
+            activities: Arc::new(ParquetActivityStorage::new(config.dir.join("activities.parquet"))),
+        },
+        StorageBackend::Sqlite => Storage::shared(Arc::new(SqliteStorage::open(&config.sqlite_path())?)),
+        StorageBackend::Memory => Storage::shared(Arc::new(MemoryStorage::new())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::GpsPoint;
+    use crate::holdings::Side;
+    use tempfile::tempdir;
+
+    fn bar(day: u32, close: f64) -> DailyBar {
+        DailyBar {
+            date: NaiveDate::from_ymd_opt(2024, 1, day).unwrap(),
+            open: close,
+            high: close,
+            low: close,
+            close,
+            adjclose: close,
+            volume: 100,
+        }
+    }
+
+    /// Exercise every trait against `storage`, then against `reopened`,
+    /// which reads the same data from scratch.
+    async fn check_backend(storage: Storage, reopen: impl Fn() -> Storage) {
+        let first = Order::new("bob", "AAPL", Side::Buy, 3, 12.5);
+        let mut second = Order::new("alice", "MSFT", Side::Sell, 1, 20.0);
+        second.note = Some("trim".into());
+        let third = Order::new("alice", "AAPL", Side::Buy, 2, 11.0);
+        for order in [&first, &second, &third] {
+            storage.orders.append_order(order).await.unwrap();
+        }
+
+        storage.prices.upsert_bars("AAPL", &[bar(3, 3.0), bar(1, 1.0)]).await.unwrap();
+        storage.prices.upsert_bars("AAPL", &[bar(2, 2.0), bar(3, 30.0)]).await.unwrap();
+
+        let activity = Activity {
+            id: "7".into(),
+            metadata: "ride".into(),
+            heart_rate: vec![120, 130],
+            power: vec![200],
+            gps: vec![GpsPoint { lat: 1.5, lon: -2.5 }],
+        };
+        storage.activities.save_activity(&activity).await.unwrap();
+        let empty = Activity { id: "8".into(), metadata: String::new(), heart_rate: vec![], power: vec![], gps: vec![] };
+        storage.activities.save_activity(&empty).await.unwrap();
+
+        for storage in [storage, reopen()] {
+            assert_eq!(storage.orders.users().await.unwrap(), vec!["alice", "bob"]);
+            assert_eq!(storage.orders.orders("alice").await.unwrap(), vec![second.clone(), third.clone()]);
+            assert!(storage.orders.orders("carol").await.unwrap().is_empty());
+
+            let closes: Vec<f64> = storage.prices.bars("AAPL").await.unwrap().iter().map(|b| b.close).collect();
+            assert_eq!(closes, vec![1.0, 2.0, 30.0]);
+            let range = storage.prices.bars_between("AAPL", Some(bar(2, 0.0).date), None).await.unwrap();
+            assert_eq!(range, vec![bar(2, 2.0), bar(3, 30.0)]);
+            assert!(storage.prices.bars("MSFT").await.unwrap().is_empty());
+
+            assert_eq!(storage.activities.activity("7").await.unwrap(), Some(activity.clone()));
+            assert_eq!(storage.activities.activity("9").await.unwrap(), None);
+            assert_eq!(storage.activities.activity("8").await.unwrap(), Some(empty.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_unencoded_directories() {
+        let dir = tempdir().unwrap();
+        let config = DataConfig { dir: dir.path().to_path_buf(), ..DataConfig::default() };
+        let legacy = build(&config).unwrap();
+        legacy.orders.append_order(&Order::new("j.doe", "BRK.B", Side::Buy, 1, 10.0)).await.unwrap();
+        legacy.prices.upsert_bars("BRK.B", &[bar(1, 1.0)]).await.unwrap();
+        legacy.prices.upsert_bars("^GSPC", &[bar(2, 2.0)]).await.unwrap();
+        // lay the files out the way they were stored before names were encoded
+        std::fs::rename(dir.path().join("j%2Edoe"), dir.path().join("j.doe")).unwrap();
+        std::fs::rename(dir.path().join("market/BRK%2EB"), dir.path().join("market/BRK.B")).unwrap();
+        std::fs::rename(dir.path().join("market/%5EGSPC"), dir.path().join("market/^GSPC")).unwrap();
+        std::fs::create_dir(dir.path().join("alice")).unwrap();
+        // directories that hold no user data stay where they are
+        std::fs::create_dir(dir.path().join("backup.old")).unwrap();
+        std::fs::write(dir.path().join("backup.old/notes.txt"), "keep").unwrap();
+
+        assert_eq!(migrate_legacy_dirs(&config).unwrap(), 3);
+        assert_eq!(migrate_legacy_dirs(&config).unwrap(), 0);
+        assert!(dir.path().join("alice").is_dir());
+        assert!(dir.path().join("backup.old/notes.txt").is_file());
+        let storage = build(&config).unwrap();
+        assert_eq!(storage.orders.users().await.unwrap(), vec!["j.doe"]);
+        assert_eq!(storage.orders.orders("j.doe").await.unwrap().len(), 1);
+        assert_eq!(storage.prices.bars("BRK.B").await.unwrap(), vec![bar(1, 1.0)]);
+        assert_eq!(storage.prices.bars("^GSPC").await.unwrap(), vec![bar(2, 2.0)]);
+    }
+
+    #[tokio::test]
+    async fn keeps_configured_directories_in_place() {
+        let dir = tempdir().unwrap();
+        let config = DataConfig {
+            dir: dir.path().to_path_buf(),
+            market_dir: Some(dir.path().join("market.v2")),
+            leagues_dir: Some(dir.path().join("leagues.v2")),
+            ..DataConfig::default()
+        };
+        let storage = build(&config).unwrap();
+        storage.prices.upsert_bars("AAPL", &[bar(1, 1.0)]).await.unwrap();
+        std::fs::create_dir_all(dir.path().join("leagues.v2")).unwrap();
+        // looks like user data, but is the configured market directory
+        std::fs::write(dir.path().join("market.v2/orders.log"), "").unwrap();
+        std::fs::write(dir.path().join("leagues.v2/orders.log"), "").unwrap();
+
+        assert_eq!(migrate_legacy_dirs(&config).unwrap(), 0);
+        assert!(dir.path().join("market.v2/AAPL/prices.parquet").is_file());
+        assert!(dir.path().join("leagues.v2").is_dir());
+    }
+
+    #[tokio::test]
+    async fn parquet_backend() {
+        let dir = tempdir().unwrap();
+        let config = DataConfig { dir: dir.path().to_path_buf(), ..DataConfig::default() };
+        check_backend(build(&config).unwrap(), || build(&config).unwrap()).await;
+        assert!(dir.path().join("market/AAPL/prices.parquet").exists());
+    }
+
+    #[tokio::test]
+    async fn sqlite_backend() {
+        let dir = tempdir().unwrap();
+        let config = DataConfig { dir: dir.path().to_path_buf(), backend: StorageBackend::Sqlite, ..DataConfig::default() };
+        check_backend(build(&config).unwrap(), || build(&config).unwrap()).await;
+        assert!(config.sqlite_path().exists());
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        let storage = Storage::shared(Arc::new(MemoryStorage::new()));
+        let shared = storage.clone();
+        check_backend(storage, move || shared.clone()).await;
+    }
+}
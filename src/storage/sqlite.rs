@@ -0,0 +1,265 @@
+//! Embedded SQLite database holding every entity in one file.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use super::files::blocking;
+use super::{ActivityStorage, OrderStorage, PriceStorage};
+use crate::activity::Activity;
+use crate::holdings::Order;
+use crate::market::DailyBar;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS orders (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
+    user TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    side TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    price REAL NOT NULL,
+    executed_at INTEGER NOT NULL,
+    note TEXT
+);
+CREATE INDEX IF NOT EXISTS orders_by_user ON orders (user, seq);
+CREATE TABLE IF NOT EXISTS bars (
+    symbol TEXT NOT NULL,
+    date TEXT NOT NULL,
+    open REAL NOT NULL,
+    high REAL NOT NULL,
+    low REAL NOT NULL,
+    close REAL NOT NULL,
+    adjclose REAL NOT NULL,
+    volume INTEGER NOT NULL,
+    PRIMARY KEY (symbol, date)
+);
+CREATE TABLE IF NOT EXISTS activities (
+    id TEXT PRIMARY KEY,
+    metadata TEXT NOT NULL,
+    heart_rate TEXT NOT NULL,
+    power TEXT NOT NULL,
+    gps TEXT NOT NULL
+);
+";
+
+/// Orders, bars and activities in one database file. Dates are stored as
+/// `YYYY-MM-DD` text, timestamps as microseconds since the Unix epoch and the
+/// activity streams as JSON arrays.
+///
+/// Queries run on the blocking thread pool, one at a time.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Open or create the database at `path` and its tables.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA).context("failed to create tables")?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        blocking(move || {
+            let mut conn = conn.lock().map_err(|_| anyhow::anyhow!("sqlite connection poisoned"))?;
+            f(&mut conn)
+        })
+        .await
+    }
+}
+
+fn row_to_order(row: &Row) -> anyhow::Result<Order> {
+    let side: String = row.get("side")?;
+    let executed_at: i64 = row.get("executed_at")?;
+    Ok(Order {
+        id: row.get("id")?,
+        user: row.get("user")?,
+        symbol: row.get("symbol")?,
+        side: side.parse()?,
+        amount: row.get("amount")?,
+        price: row.get("price")?,
+        executed_at: DateTime::<Utc>::from_timestamp_micros(executed_at).context("invalid order timestamp")?,
+        note: row.get("note")?,
+    })
+}
+
+fn row_to_bar(row: &Row) -> anyhow::Result<DailyBar> {
+    let date: String = row.get("date")?;
+    let volume: i64 = row.get("volume")?;
+    Ok(DailyBar {
+        date: date.parse().context("invalid bar date")?,
+        open: row.get("open")?,
+        high: row.get("high")?,
+        low: row.get("low")?,
+        close: row.get("close")?,
+        adjclose: row.get("adjclose")?,
+        volume: volume.try_into()?,
+    })
+}
+
+fn row_to_activity(row: &Row) -> anyhow::Result<Activity> {
+    let json = |name: &str| -> anyhow::Result<String> { Ok(row.get(name)?) };
+    Ok(Activity {
+        id: row.get("id")?,
+        metadata: row.get("metadata")?,
+        heart_rate: serde_json::from_str(&json("heart_rate")?)?,
+        power: serde_json::from_str(&json("power")?)?,
+        gps: serde_json::from_str(&json("gps")?)?,
+    })
+}
+
+/// Run `sql` with `params` and decode every row with `decode`.
+fn query<T>(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+    decode: fn(&Row) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let mut rows = stmt.query(params)?;
+    let mut decoded = Vec::new();
+    while let Some(row) = rows.next()? {
+        decoded.push(decode(row)?);
+    }
+    Ok(decoded)
+}
+
+#[async_trait]
+impl OrderStorage for SqliteStorage {
+    async fn users(&self) -> anyhow::Result<Vec<String>> {
+        self.with_conn(|conn| {
+            query(conn, "SELECT DISTINCT user FROM orders ORDER BY user", [], |row| Ok(row.get(0)?))
+        })
+        .await
+    }
+
+    async fn orders(&self, user: &str) -> anyhow::Result<Vec<Order>> {
+        let user = user.to_string();
+        self.with_conn(move |conn| query(conn, "SELECT * FROM orders WHERE user = ?1 ORDER BY seq", [user], row_to_order))
+            .await
+    }
+
+    async fn append_order(&self, order: &Order) -> anyhow::Result<()> {
+        let order = order.clone();
+        self.with_conn(move |conn| {
+            conn.prepare_cached(
+                "INSERT INTO orders (id, user, symbol, side, amount, price, executed_at, note)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?
+            .execute(params![
+                order.id,
+                order.user,
+                order.symbol,
+                order.side.as_str(),
+                order.amount,
+                order.price,
+                order.executed_at.timestamp_micros(),
+                order.note,
+            ])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl PriceStorage for SqliteStorage {
+    async fn bars(&self, symbol: &str) -> anyhow::Result<Vec<DailyBar>> {
+        self.bars_between(symbol, None, None).await
+    }
+
+    async fn upsert_bars(&self, symbol: &str, bars: &[DailyBar]) -> anyhow::Result<()> {
+        let (symbol, bars) = (symbol.to_string(), bars.to_vec());
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "INSERT OR REPLACE INTO bars (symbol, date, open, high, low, close, adjclose, volume)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                )?;
+                for bar in &bars {
+                    let volume = i64::try_from(bar.volume)?;
+                    stmt.execute(params![
+                        symbol,
+                        bar.date.to_string(),
+                        bar.open,
+                        bar.high,
+                        bar.low,
+                        bar.close,
+                        bar.adjclose,
+                        volume,
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn bars_between(
+        &self,
+        symbol: &str,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> anyhow::Result<Vec<DailyBar>> {
+        // ISO dates sort as text
+        let from = from.map_or_else(|| "0000-00-00".to_string(), |d| d.to_string());
+        let to = to.map_or_else(|| "9999-99-99".to_string(), |d| d.to_string());
+        let symbol = symbol.to_string();
+        self.with_conn(move |conn| {
+            query(
+                conn,
+                "SELECT * FROM bars WHERE symbol = ?1 AND date BETWEEN ?2 AND ?3 ORDER BY date",
+                params![symbol, from, to],
+                row_to_bar,
+            )
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ActivityStorage for SqliteStorage {
+    async fn activity(&self, id: &str) -> anyhow::Result<Option<Activity>> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached("SELECT * FROM activities WHERE id = ?1")?;
+            let row = stmt.query_row([id], |row| Ok(row_to_activity(row))).optional()?;
+            row.transpose()
+        })
+        .await
+    }
+
+    async fn save_activity(&self, activity: &Activity) -> anyhow::Result<()> {
+        let activity = activity.clone();
+        self.with_conn(move |conn| {
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO activities (id, metadata, heart_rate, power, gps) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                activity.id,
+                activity.metadata,
+                serde_json::to_string(&activity.heart_rate)?,
+                serde_json::to_string(&activity.power)?,
+                serde_json::to_string(&activity.gps)?,
+            ])?;
+            Ok(())
+        })
+        .await
+    }
+}
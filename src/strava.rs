@@ -50,7 +50,7 @@ impl StravaClient {
             power,
             gps: Vec::new(),
         };
-        Ok(store.add_if_missing(activity).await)
+        store.add_if_missing(activity).await
     }
 }
 
@@ -96,7 +96,7 @@ mod tests {
         let store = crate::activity::ActivityStore::new();
         assert!(client.fetch_and_store_power(&store, "tok", 7).await.unwrap());
         assert!(!client.fetch_and_store_power(&store, "tok", 7).await.unwrap());
-        let act = store.get("7").await.unwrap();
+        let act = store.get("7").await.unwrap().unwrap();
         assert_eq!(act.power, vec![9]);
         m.assert();
     }
@@ -0,0 +1,166 @@
+//! Helpers shared by the tests of several modules.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use tokio::sync::Notify;
+use yahoo_finance_api::Quote;
+
+use crate::market::QuoteFetcher;
+
+/// Quote at `price` with every field set to it, stamped at the Unix epoch.
+pub fn quote(price: f64) -> Quote {
+    Quote { timestamp: 0, open: price, high: price, low: price, volume: 0, close: price, adjclose: price }
+}
+
+/// Quote at `price` stamped now, so it is never stale.
+pub fn live_quote(price: f64) -> Quote {
+    Quote { timestamp: chrono::Utc::now().timestamp(), ..quote(price) }
+}
+
+/// Quote at `close` stamped `day` days after the Unix epoch.
+pub fn daily_quote(day: i64, close: f64) -> Quote {
+    Quote { timestamp: day * 86_400, volume: 1, ..quote(close) }
+}
+
+/// Quote provider serving scripted quotes and history.
+///
+/// Quotes are queued per symbol, with a queue for every other symbol. Each
+/// fetch takes the next entry of the queue, and the last entry keeps being
+/// served once the others are used up. Symbols without quotes get none.
+#[derive(Default)]
+pub struct TestFetcher {
+    quotes: Mutex<HashMap<String, VecDeque<Quote>>>,
+    any: Mutex<VecDeque<Quote>>,
+    history: Vec<Quote>,
+    failing: HashSet<String>,
+    failures: AtomicU32,
+    history_failures: AtomicU32,
+    hang_history: bool,
+    delay: Duration,
+    gate: Option<Arc<Notify>>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    history_requests: Mutex<Vec<(NaiveDate, NaiveDate)>>,
+}
+
+impl TestFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `quote` for every symbol without quotes of its own.
+    pub fn with_quote(self, quote: Quote) -> Self {
+        self.any.lock().unwrap().push_back(quote);
+        self
+    }
+
+    /// Serve `quote` for `symbol`.
+    pub fn with_symbol_quote(self, symbol: &str, quote: Quote) -> Self {
+        self.quotes.lock().unwrap().entry(symbol.to_string()).or_default().push_back(quote);
+        self
+    }
+
+    /// Serve `history` for every symbol.
+    pub fn with_history(mut self, history: Vec<Quote>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Never answer history requests.
+    pub fn with_hanging_history(mut self) -> Self {
+        self.hang_history = true;
+        self
+    }
+
+    /// Fail every quote request for `symbol`.
+    pub fn with_failing_symbol(mut self, symbol: &str) -> Self {
+        self.failing.insert(symbol.to_string());
+        self
+    }
+
+    /// Wait `delay` before answering each quote request.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Hold each quote request until `gate` is notified.
+    pub fn with_gate(mut self, gate: Arc<Notify>) -> Self {
+        self.gate = Some(gate);
+        self
+    }
+
+    /// Fail the next `requests` quote requests.
+    pub fn fail_next(&self, requests: u32) {
+        self.failures.store(requests, Ordering::SeqCst);
+    }
+
+    /// Fail the next `requests` history requests.
+    pub fn fail_next_history(&self, requests: u32) {
+        self.history_failures.store(requests, Ordering::SeqCst);
+    }
+
+    /// Most quote requests that were answered at the same time.
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Date ranges of the history requests so far.
+    pub fn history_requests(&self) -> Vec<(NaiveDate, NaiveDate)> {
+        self.history_requests.lock().unwrap().clone()
+    }
+}
+
+/// Take the next entry of `queue`, keeping the last one.
+fn next(queue: &mut VecDeque<Quote>) -> Vec<Quote> {
+    let quote = if queue.len() > 1 { queue.pop_front() } else { queue.front().cloned() };
+    quote.into_iter().collect()
+}
+
+/// Take one of `counter`'s remaining failures, if any.
+fn take_failure(counter: &AtomicU32) -> bool {
+    counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok()
+}
+
+#[async_trait]
+impl QuoteFetcher for TestFetcher {
+    async fn fetch_quotes(&self, symbol: &str) -> anyhow::Result<Vec<Quote>> {
+        let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+        if let Some(gate) = &self.gate {
+            gate.notified().await;
+        }
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        if self.failing.contains(symbol) {
+            anyhow::bail!("no data for {symbol}");
+        }
+        if take_failure(&self.failures) {
+            anyhow::bail!("temporarily unavailable");
+        }
+        let mut quotes = self.quotes.lock().unwrap();
+        match quotes.get_mut(symbol) {
+            Some(queue) => Ok(next(queue)),
+            None => Ok(next(&mut self.any.lock().unwrap())),
+        }
+    }
+
+    async fn fetch_history(&self, _symbol: &str, start: NaiveDate, end: NaiveDate) -> anyhow::Result<Vec<Quote>> {
+        self.history_requests.lock().unwrap().push((start, end));
+        if self.hang_history {
+            std::future::pending::<()>().await;
+        }
+        if take_failure(&self.history_failures) {
+            anyhow::bail!("history unavailable");
+        }
+        Ok(self.history.clone())
+    }
+}
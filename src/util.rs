@@ -0,0 +1,19 @@
+//! Helpers shared by the modules that encode data as Arrow.
+
+use anyhow::Context;
+use chrono::NaiveDate;
+
+const UNIX_EPOCH_DAYS_FROM_CE: i32 = 719_163;
+
+/// Days since the Unix epoch, as stored in Arrow `Date32` columns.
+pub fn date_to_days(date: NaiveDate) -> i32 {
+    use chrono::Datelike;
+    date.num_days_from_ce() - UNIX_EPOCH_DAYS_FROM_CE
+}
+
+/// Inverse of [`date_to_days`].
+pub fn days_to_date(days: i32) -> anyhow::Result<NaiveDate> {
+    days.checked_add(UNIX_EPOCH_DAYS_FROM_CE)
+        .and_then(NaiveDate::from_num_days_from_ce_opt)
+        .context("invalid date")
+}